@@ -0,0 +1,170 @@
+//! Band-limited step synthesis, in the style of blip_buf. Instead of generating samples at the
+//! APU's clock rate and resampling them, every change in amplitude is added to the output buffer as
+//! a band-limited step, directly at the output rate. Between changes, there is nothing to do.
+
+use super::TCYCLE_FREQ;
+
+/// Number of output samples each step is spread over.
+const KERNEL_WIDTH: usize = 16;
+/// Number of sub-sample positions a step can start on.
+const PHASES: usize = 64;
+
+/// A mono band-limited synthesizer. Time is counted in clocks (T-cycles) within the current frame.
+/// Deltas are added with `add_delta`, then `end_frame` makes the samples up to the end of the frame
+/// available to `read_samples`.
+pub struct Blip {
+    /// Output samples per clock.
+    ratio: f64,
+    /// Step kernels for every phase. Each one sums to 1.
+    kernels: Vec<[f32; KERNEL_WIDTH]>,
+    /// Pending deltas. Index 0 is the next sample to be read.
+    deltas: Vec<f32>,
+    /// Time of the start of the current frame, in output samples after the next sample to be read.
+    offset: f64,
+    /// The running sum of all deltas read so far, i.e. the current output amplitude.
+    integrator: f32,
+}
+
+impl Blip {
+    pub fn new(sample_rate: u32) -> Blip {
+        let kernels = (0..=PHASES).map(|phase| make_kernel(phase as f64 / PHASES as f64)).collect();
+        Blip {
+            ratio: f64::from(sample_rate) / f64::from(TCYCLE_FREQ),
+            kernels,
+            deltas: vec![0.0; KERNEL_WIDTH],
+            offset: 0.0,
+            integrator: 0.0,
+        }
+    }
+
+    /// Adds a change in amplitude of `delta` at `clock` within the current frame.
+    pub fn add_delta(&mut self, clock: u32, delta: f32) {
+        let time = self.offset + f64::from(clock) * self.ratio;
+        let index = time as usize;
+        let kernel = &self.kernels[((time - index as f64) * PHASES as f64).round() as usize];
+        if self.deltas.len() < index + KERNEL_WIDTH {
+            self.deltas.resize(index + KERNEL_WIDTH, 0.0);
+        }
+        for (x, k) in self.deltas[index..index + KERNEL_WIDTH].iter_mut().zip(kernel.iter()) {
+            *x += k * delta;
+        }
+    }
+
+    /// Ends the current frame after `clocks` clocks, and starts a new one.
+    pub fn end_frame(&mut self, clocks: u32) {
+        self.offset += f64::from(clocks) * self.ratio;
+    }
+
+    /// Number of samples that can be read.
+    pub fn samples_available(&self) -> usize {
+        self.offset as usize
+    }
+
+    /// Reads all available samples into `out`.
+    pub fn read_samples(&mut self, out: &mut Vec<f32>) {
+        let count = self.samples_available();
+        if self.deltas.len() < count + KERNEL_WIDTH {
+            self.deltas.resize(count + KERNEL_WIDTH, 0.0);
+        }
+        for delta in self.deltas.drain(..count) {
+            self.integrator += delta;
+            out.push(self.integrator);
+        }
+        self.offset -= count as f64;
+    }
+}
+
+/// Builds the kernel of a step starting `phase` samples (between 0 and 1) after the first tap.
+/// The kernel is a Blackman-windowed sinc, cut off slightly below the output's Nyquist frequency
+/// to keep aliasing down. The step is centered in the kernel, which delays the output by half the
+/// kernel's width.
+fn make_kernel(phase: f64) -> [f32; KERNEL_WIDTH] {
+    const CUTOFF: f64 = 0.9;
+    use std::f64::consts::PI;
+    let half = (KERNEL_WIDTH / 2) as f64;
+    let mut kernel = [0.0; KERNEL_WIDTH];
+    let mut sum = 0.0;
+    for (i, x) in kernel.iter_mut().enumerate() {
+        let t = i as f64 - half - phase + 0.5;
+        let sinc = if t == 0.0 { 1.0 } else { (PI * CUTOFF * t).sin() / (PI * CUTOFF * t) };
+        let w = (t + half) / KERNEL_WIDTH as f64;
+        let window = if w <= 0.0 || w >= 1.0 {
+            0.0
+        } else {
+            0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos()
+        };
+        *x = sinc * window;
+        sum += *x;
+    }
+    for x in kernel.iter_mut() {
+        *x /= sum;
+    }
+    let mut result = [0.0f32; KERNEL_WIDTH];
+    for (out, x) in result.iter_mut().zip(kernel.iter()) {
+        *out = *x as f32;
+    }
+    result
+}
+
+/// The DMG's output capacitor, which filters out the DC offset of the DACs.
+pub struct HighPass {
+    capacitor: f32,
+    /// How much of its charge the capacitor keeps every sample.
+    charge_factor: f32,
+}
+
+impl HighPass {
+    pub fn new(sample_rate: u32) -> HighPass {
+        // The capacitor keeps 0.999958 of its charge every T-cycle.
+        let charge_factor = 0.999_958f64.powf(f64::from(TCYCLE_FREQ) / f64::from(sample_rate));
+        HighPass { capacitor: 0.0, charge_factor: charge_factor as f32 }
+    }
+
+    pub fn filter(&mut self, input: f32) -> f32 {
+        let output = input - self.capacitor;
+        self.capacitor = input - output * self.charge_factor;
+        output
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_step_settles_to_delta() {
+        let mut blip = Blip::new(48_000);
+        blip.add_delta(1000, 0.5);
+        blip.end_frame(10_000);
+        let mut samples = Vec::new();
+        blip.read_samples(&mut samples);
+        // 1000 clocks is ~11.4 samples in, and the step is delayed by half the kernel.
+        assert_eq!(samples.len(), 114);
+        assert!(samples[..4].iter().all(|&x| x == 0.0));
+        assert!(samples[30..].iter().all(|&x| (x - 0.5).abs() < 1e-5));
+        let crossing = samples.iter().position(|&x| x >= 0.25).unwrap();
+        assert_eq!(crossing, 12 + KERNEL_WIDTH / 2 - 1);
+    }
+
+    #[test]
+    fn test_sample_count_carries_over_frames() {
+        let mut blip = Blip::new(48_000);
+        let mut samples = Vec::new();
+        for _ in 0..1000 {
+            blip.end_frame(4194);
+            blip.read_samples(&mut samples);
+        }
+        assert_eq!(samples.len(), (4194.0 * 1000.0 * 48_000.0 / 4_194_304.0) as usize);
+    }
+
+    #[test]
+    fn test_high_pass_removes_dc() {
+        let mut filter = HighPass::new(48_000);
+        assert_eq!(filter.filter(1.0), 1.0);
+        let last = (0..48_000).map(|_| filter.filter(1.0)).last().unwrap();
+        assert_lt!(last.abs(), 1e-3);
+        // Steps still go through.
+        assert_gt!(filter.filter(0.0), -1.01);
+        assert_lt!(filter.filter(0.0), -0.9);
+    }
+}
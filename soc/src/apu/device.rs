@@ -1,17 +1,34 @@
-use std::sync::atomic::AtomicBool;
-use std::sync::Arc;
+use ringbuf::{Consumer, Producer};
 
-use super::mixer::SharedAudioRegs;
-use super::threads;
+use super::mixer::StereoFrame;
+use super::output::AudioOutput;
+use super::{SampleBuffer, DEFAULT_SAMPLE_RATE};
 
-/// The sampling rate chosen for the device.
-pub const DEVICE_RATE: f32 = 48_000.0;
+/// The sampling rate chosen for the device. The APU produces samples at this rate, so they can be
+/// played as-is.
+pub const DEVICE_RATE: u32 = DEFAULT_SAMPLE_RATE;
 
 #[cfg(target_os = "windows")]
 pub const FRAMES_PER_BUFFER: usize = 2048;
 #[cfg(not(target_os = "windows"))]
 pub const FRAMES_PER_BUFFER: usize = 256;
 
+/// Size of the ring buffer between the emulation and the device callback. Holds four device
+/// buffers' worth of samples.
+const SHARED_RINGBUFFER_SIZE: usize = FRAMES_PER_BUFFER * 4;
+
+/// Called by the device when it needs samples. If not enough samples are available, plays
+/// silence and keeps them for the next callback.
+fn stream_callback(sample_consumer: &mut Consumer<StereoFrame>, buffer: &mut [StereoFrame]) {
+    if sample_consumer.len() < buffer.len() {
+        trace!(target: "audio", "Sample buffer underrun. Got {} out of {} needed samples. Skipping frame.",
+            sample_consumer.len(), buffer.len());
+        buffer.iter_mut().for_each(|x| *x = StereoFrame::default());
+        return;
+    }
+    sample_consumer.pop_slice(buffer);
+}
+
 pub use audiohal_backend::*;
 
 mod audiohal_backend {
@@ -20,33 +37,53 @@ mod audiohal_backend {
     use super::*;
 
     pub struct Device {
-        outstream: Option<audiohal::Stream<[f32; 2]>>,
-        sampler_thread_kill: Arc<AtomicBool>,
-    }
-
-    impl Drop for Device {
-        fn drop(&mut self) {
-            // Turn off the sampler thread.
-            self.sampler_thread_kill.store(true, std::sync::atomic::Ordering::Relaxed);
-            self.outstream = None;
-        }
+        #[allow(dead_code)]
+        outstream: audiohal::Stream<[f32; 2]>,
+        sample_producer: Producer<StereoFrame>,
+        /// Emulation speed relative to real time.
+        speed: f32,
+        /// How many times the next chunk of samples should be played. See `push_samples`.
+        chunk_credit: f32,
     }
 
     impl Device {
-        pub fn try_new(global_regs: SharedAudioRegs) -> Result<Device, Box<dyn std::error::Error>> {
-            let (mut resampler, sampler_thread_kill) = threads::make_audio_threads(global_regs);
+        pub fn try_new() -> Result<Device, Box<dyn std::error::Error>> {
+            let (sample_producer, mut sample_consumer) =
+                ringbuf::RingBuffer::<StereoFrame>::new(SHARED_RINGBUFFER_SIZE).split();
 
             let mut stream = audiohal::Host::with_default_backend()?
                 .default_output_device()?
                 .open_outstream(audiohal::StreamOptions {
                     sample_rate: audiohal::SampleRate::Exact(DEVICE_RATE as i32),
                     frames_per_buffer: Some(FRAMES_PER_BUFFER as i32),
-                    callback: Box::new(move |x| resampler.stream_callback(x)),
+                    callback: Box::new(move |x| stream_callback(&mut sample_consumer, x)),
                     ..Default::default()
                 })?;
             stream.start()?;
 
-            return Ok(Device { outstream: Some(stream), sampler_thread_kill });
+            Ok(Device { outstream: stream, sample_producer, speed: 1.0, chunk_credit: 0.0 })
+        }
+    }
+
+    impl AudioOutput for Device {
+        /// Queues a chunk of samples for playback. When emulation runs faster than real time,
+        /// some chunks are dropped, and when it runs slower, some are played twice. That keeps the
+        /// pitch intact without underrunning the device. Samples that don't fit in the ring buffer
+        /// are dropped.
+        fn push_samples(&mut self, samples: &SampleBuffer) {
+            if self.speed <= 0.0 {
+                return;
+            }
+            self.chunk_credit += 1.0 / self.speed;
+            while self.chunk_credit >= 1.0 {
+                self.sample_producer.push_slice(&samples.frames);
+                self.chunk_credit -= 1.0;
+            }
+        }
+
+        fn set_speed(&mut self, speed: f32) {
+            self.speed = speed;
+            self.chunk_credit = 0.0;
         }
     }
 }
@@ -59,18 +96,16 @@ mod test {
     fn stress_test_device_create_destroy() {
         // TODO: Cleanup. If no audio devices, don't bother with stress test.
         {
-            if Device::try_new(SharedAudioRegs::default()).is_err() {
+            if Device::try_new().is_err() {
                 return;
             }
         }
         for _ in 0..10 {
-            let audio_regs = SharedAudioRegs::default();
-            let _device = Device::try_new(audio_regs).unwrap();
+            let _device = Device::try_new().unwrap();
         }
         // Do it while sleeping in between.
         for _ in 0..10 {
-            let audio_regs = SharedAudioRegs::default();
-            let _device = Device::try_new(audio_regs).unwrap();
+            let _device = Device::try_new().unwrap();
             // Sleep for a bit.
             std::thread::sleep(std::time::Duration::from_millis(100));
         }
@@ -0,0 +1,169 @@
+use super::sound::ComponentCycle;
+
+/// The 512Hz frame sequencer, which clocks the length counters, the volume envelopes and the
+/// frequency sweep. It steps on every falling edge of DIV bit 12, so writing to DIV (which resets
+/// it) can step the sequencer early.
+///
+/// Step:      0 1 2 3 4 5 6 7
+/// Length:    x   x   x   x
+/// Sweep:         x       x
+/// Envelope:                x
+#[derive(Default)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct FrameSequencer {
+    /// The step that will run on the next falling edge.
+    step: u8,
+    last_input: bool,
+}
+
+impl FrameSequencer {
+    /// Feeds DIV bit 12 for the current T-cycle. Returns the components to clock on this T-cycle.
+    pub fn tick(&mut self, input: bool) -> ComponentCycle {
+        let is_falling_edge = self.last_input && !input;
+        self.last_input = input;
+        if !is_falling_edge {
+            return ComponentCycle::empty();
+        }
+        let step = self.step;
+        self.step = (self.step + 1) % 8;
+        match step {
+            0 | 4 => ComponentCycle::LENGTH,
+            2 | 6 => ComponentCycle::LENGTH | ComponentCycle::SWEEP,
+            7 => ComponentCycle::ENVELOPE,
+            _ => ComponentCycle::empty(),
+        }
+    }
+
+    /// Whether the next step clocks the length counters. Enabling a length counter when it
+    /// doesn't clocks the counter once more.
+    pub fn is_length_step_next(&self) -> bool {
+        self.step % 2 == 0
+    }
+
+    /// Makes the next step step 0. Happens when the APU is powered on.
+    pub fn reset(&mut self) {
+        self.step = 0;
+    }
+}
+
+/// Counts down the remaining length of a sound, and turns its channel off when it expires. Keeps
+/// running while the channel is off, so it lives outside of the sound.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct LengthCounter {
+    max: u16,
+    counter: u16,
+    is_enabled: bool,
+}
+
+impl LengthCounter {
+    /// `max` is 64 for the square and noise channels, and 256 for the wave channel.
+    pub fn new(max: u16) -> LengthCounter {
+        LengthCounter { max, counter: 0, is_enabled: false }
+    }
+
+    /// Loads the length data written to NRx1.
+    pub fn load(&mut self, length: u16) {
+        self.counter = self.max - length;
+    }
+
+    /// Powering off clears NRx4, which disables the counter. The counter itself keeps its value.
+    pub fn power_off(&mut self) {
+        self.is_enabled = false;
+    }
+
+    /// Clocked by the frame sequencer. Returns true if the channel must be turned off.
+    pub fn clock(&mut self) -> bool {
+        self.decrement()
+    }
+
+    /// Handles a write to NRx4. Returns true if the channel must be turned off.
+    pub fn write_control(
+        &mut self,
+        is_enabled: bool,
+        is_triggered: bool,
+        is_length_step_next: bool,
+    ) -> bool {
+        let was_enabled = self.is_enabled;
+        self.is_enabled = is_enabled;
+        // Extra length clocking. Triggering overrides the expiry.
+        let is_expired = !was_enabled && !is_length_step_next && self.decrement() && !is_triggered;
+        if is_triggered && self.counter == 0 {
+            self.counter = self.max;
+            if is_enabled && !is_length_step_next {
+                self.counter -= 1;
+            }
+        }
+        is_expired
+    }
+
+    fn decrement(&mut self) -> bool {
+        if self.is_enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Runs the sequencer through `steps` falling edges.
+    fn run(sequencer: &mut FrameSequencer, steps: usize) -> Vec<ComponentCycle> {
+        (0..steps)
+            .map(|_| {
+                assert!(sequencer.tick(true).is_empty());
+                sequencer.tick(false)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sequencer_steps() {
+        let mut sequencer = FrameSequencer::default();
+        let steps = run(&mut sequencer, 9);
+        let length = steps.iter().map(|x| x.contains(ComponentCycle::LENGTH) as u8);
+        let sweep = steps.iter().map(|x| x.contains(ComponentCycle::SWEEP) as u8);
+        let envelope = steps.iter().map(|x| x.contains(ComponentCycle::ENVELOPE) as u8);
+        assert_eq!(length.collect::<Vec<_>>(), [1, 0, 1, 0, 1, 0, 1, 0, 1]);
+        assert_eq!(sweep.collect::<Vec<_>>(), [0, 0, 1, 0, 0, 0, 1, 0, 0]);
+        assert_eq!(envelope.collect::<Vec<_>>(), [0, 0, 0, 0, 0, 0, 0, 1, 0]);
+        // Only falling edges step.
+        assert!(sequencer.tick(false).is_empty());
+        assert!(!sequencer.is_length_step_next());
+        sequencer.reset();
+        assert!(sequencer.is_length_step_next());
+    }
+
+    #[test]
+    fn test_length_expires() {
+        let mut length = LengthCounter::new(64);
+        length.load(62);
+        assert!(!length.write_control(true, false, true));
+        assert!(!length.clock());
+        assert!(length.clock());
+        // Stays expired.
+        assert!(!length.clock());
+    }
+
+    #[test]
+    fn test_extra_length_clocking() {
+        let mut length = LengthCounter::new(64);
+        length.load(63);
+        // Enabling while the next step doesn't clock length clocks it once.
+        assert!(length.write_control(true, false, false));
+        // Unless the channel is triggered at the same time, which reloads the length.
+        let mut length = LengthCounter::new(256);
+        length.load(255);
+        assert!(!length.write_control(true, true, false));
+        assert_eq!(length.counter, 255);
+        // Already enabled: no extra clock.
+        let mut length = LengthCounter::new(64);
+        length.load(0);
+        length.write_control(true, false, true);
+        length.write_control(true, false, false);
+        assert_eq!(length.counter, 64);
+    }
+}
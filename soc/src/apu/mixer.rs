@@ -1,30 +1,20 @@
-use arrayvec::ArrayVec;
-use std::cell::RefCell;
-use std::iter::Cycle;
-use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
-use std::sync::Arc;
-
+use super::frame_sequencer::{FrameSequencer, LengthCounter};
 use super::registers::*;
 use super::sound::{ComponentCycle, Noise, Sound, Square, Wave};
-use super::SharedWaveTable;
-use crate::util::{iterate_bits, timer, AtomicInt, Timer};
+use crate::util::iterate_bits;
 
 pub type StereoFrame = [f32; 2];
 
-pub enum ChannelEvent {
-    TriggerSquare1(SquareConfig),
-    TriggerSquare2(SquareConfig),
-    TriggerWave(WaveConfig),
-    TriggerNoise(NoiseConfig),
-}
+/// The output of each channel on its own, before mixing: square 1, square 2, wave and noise.
+pub type ChannelFrame = [f32; NUM_CHANNELS];
 
-/// A local snapshot of ChannelState taken at the beginning of the audio callback. We take a
-/// snapshot in order to prevent reading the various atomic configs hundreds of time per sample.
-/// This is mainly used to handle length counter reloading.
-/// The only actually shared variable is SoundStatus, which needs to be updated when a sound
-/// finishes.
+pub const NUM_CHANNELS: usize = 4;
+
+/// The audio registers. Playing sounds write their state (the swept frequency) back after every
+/// sample, so reads always see the current state.
 #[derive(Default, Clone)]
-pub struct CachedAudioRegs {
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct AudioRegs {
     pub sound_status: SoundStatus,
     pub sound_mix: ChannelMixConfig,
     pub volume_control: VolumeControl,
@@ -32,231 +22,314 @@ pub struct CachedAudioRegs {
     pub square_2_config: SquareConfig,
     pub wave_config: WaveConfig,
     pub noise_config: NoiseConfig,
+    pub wave_table: u128,
 }
 
-impl CachedAudioRegs {
-    pub fn sync_from_shared(&mut self, state: &SharedAudioRegs) {
-        self.sound_status = SoundStatus(state.sound_status.load(Ordering::Acquire));
-        self.sound_mix = ChannelMixConfig(state.sound_mix.load(Ordering::Acquire));
-        self.volume_control = VolumeControl(state.volume_control.load(Ordering::Acquire));
-        self.square_1_config = SquareConfig(state.square_1_config.load(Ordering::Acquire));
-        self.square_2_config = SquareConfig(state.square_2_config.load(Ordering::Acquire));
-        self.wave_config = WaveConfig(state.wave_config.load(Ordering::Acquire));
-        self.noise_config = NoiseConfig(state.noise_config.load(Ordering::Acquire));
-    }
-
-    pub fn sync_to_shared(&self, prev_state: &CachedAudioRegs, state: &mut SharedAudioRegs) {
-        state.square_1_config.compare_and_swap(
-            prev_state.square_1_config.0,
-            self.square_1_config.0,
-            Ordering::Relaxed,
-        );
-        state.square_2_config.compare_and_swap(
-            prev_state.square_2_config.0,
-            self.square_2_config.0,
-            Ordering::Relaxed,
-        );
-        state.wave_config.compare_and_swap(
-            prev_state.wave_config.0,
-            self.wave_config.0,
-            Ordering::Relaxed,
-        );
-        state.noise_config.compare_and_swap(
-            prev_state.noise_config.0,
-            self.noise_config.0,
-            Ordering::Relaxed,
-        );
-        state
-            .sound_status
-            .weak_update_with(Ordering::Release, |x: u8| (x & 0x80) | (self.sound_status.0 & 0xF));
+/// Clears the trigger bit of `config`, returning the config to start the sound with if it was set.
+fn take_trigger(config: &mut u64) -> Option<u64> {
+    let mut common = CommonSoundConfig(*config);
+    if common.triggered() {
+        common.set_triggered(false);
+        *config = common.0;
+        Some(common.0)
+    } else {
+        None
     }
 }
 
-#[derive(Default, Clone)]
-pub struct SharedAudioRegs {
-    pub sound_mix: Arc<AtomicU8>,
-    pub sound_status: Arc<AtomicU8>,
-    pub volume_control: Arc<AtomicU8>,
-    pub square_1_config: Arc<AtomicU64>,
-    pub square_2_config: Arc<AtomicU64>,
-    pub wave_config: Arc<AtomicU64>,
-    pub noise_config: Arc<AtomicU64>,
-    pub wave_table: SharedWaveTable,
+/// One of the four channels: the sound playing on it (if any), and its length counter, which
+/// keeps running while nothing plays.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+struct Channel<T: Sound> {
+    sound: Option<T>,
+    length: LengthCounter,
+    /// The channel's bit in NR52.
+    status_bit: i32,
 }
 
-impl SharedAudioRegs {
-    pub fn poll_events(&mut self) -> ArrayVec<[ChannelEvent; 4]> {
-        let mut events = ArrayVec::new();
-        if let Some(config) = SharedAudioRegs::poll_event(&mut self.square_1_config) {
-            events.push(ChannelEvent::TriggerSquare1(SquareConfig(config)));
-        }
-        if let Some(config) = SharedAudioRegs::poll_event(&mut self.square_2_config) {
-            events.push(ChannelEvent::TriggerSquare2(SquareConfig(config)));
-        }
-        if let Some(config) = SharedAudioRegs::poll_event(&mut self.wave_config) {
-            events.push(ChannelEvent::TriggerWave(WaveConfig(config)));
+impl<T: Sound> Channel<T> {
+    fn new(max_length: u16, status_bit: i32) -> Channel<T> {
+        Channel { sound: None, length: LengthCounter::new(max_length), status_bit }
+    }
+
+    fn stop(&mut self, status_reg: &mut SoundStatus) {
+        self.sound = None;
+        status_reg.0 &= !(1 << self.status_bit);
+    }
+
+    fn power_off(&mut self) {
+        self.sound = None;
+        self.length.power_off();
+    }
+
+    /// Handles a write to NRx4. Updates the length counter, and starts a new sound with `start` if
+    /// the trigger bit is set and the channel's DAC is on.
+    fn write_control(
+        &mut self,
+        reg: &mut u64,
+        status_reg: &mut SoundStatus,
+        is_length_step_next: bool,
+        is_dac_on: bool,
+        start: impl FnOnce(u64) -> T,
+    ) {
+        let trigger = take_trigger(reg);
+        let is_timed = CommonSoundConfig(*reg).is_timed();
+        if self.length.write_control(is_timed, trigger.is_some(), is_length_step_next) {
+            self.stop(status_reg);
         }
-        if let Some(config) = SharedAudioRegs::poll_event(&mut self.noise_config) {
-            events.push(ChannelEvent::TriggerNoise(NoiseConfig(config)));
+        if let Some(config) = trigger.filter(|_| is_dac_on) {
+            self.sound = Some(start(config));
+            status_reg.0 |= 1 << self.status_bit;
         }
-        events
     }
 
-    fn poll_event(config: &mut Arc<AtomicU64>) -> Option<u64> {
-        // https://bartoszmilewski.com/2008/12/01/c-atomics-and-memory-ordering/
-        let mut current_value = CommonSoundConfig(config.load(Ordering::Acquire));
-        loop {
-            // Exit now if we don't have any audio to trigger.
-            let mut new_value = current_value;
-            if !new_value.triggered() {
-                return None;
-            }
-            new_value.set_triggered(false);
-            match config.compare_exchange_weak(
-                current_value.0,
-                new_value.0,
-                Ordering::Acquire,
-                Ordering::Relaxed,
-            ) {
-                Ok(_) => return Some(new_value.0),
-                Err(x) => {
-                    current_value = CommonSoundConfig(x);
-                    strict_fail!(
-                        "Actually have contention with main thread! On {:?}",
-                        current_value
-                    );
-                }
+    /// Updates the playing sound with the current register value. Turning the DAC off turns the
+    /// channel off.
+    fn update(&mut self, reg: u64, is_dac_on: bool, status_reg: &mut SoundStatus) {
+        if !is_dac_on {
+            self.stop(status_reg);
+        }
+        if let Some(sound) = &mut self.sound {
+            sound.update_from_reg(reg);
+            if sound.is_done() {
+                self.stop(status_reg);
             }
         }
     }
-}
 
-/// Quality-of-life helper to deal with lists of sounds that can turn off automatically.
-struct MaybeSound<T: Sound>(Option<T>);
-impl<T: Sound> MaybeSound<T> {
-    pub fn maybe_sample(
+    fn sample(
         &mut self,
         cycles: ComponentCycle,
+        reg: &mut u64,
         status_reg: &mut SoundStatus,
-        sound_bit: i32,
     ) -> f32 {
-        let (sample, is_done) =
-            self.0.as_mut().map(|s| (s.sample(cycles), s.is_done())).unwrap_or_default();
+        if cycles.contains(ComponentCycle::LENGTH) && self.length.clock() {
+            self.stop(status_reg);
+        }
+        let (sample, is_done) = self
+            .sound
+            .as_mut()
+            .map(|s| {
+                let sample = s.sample(cycles);
+                s.update_to_reg(reg);
+                (sample, s.is_done())
+            })
+            .unwrap_or_default();
         if is_done {
-            self.0 = None;
-            status_reg.0 &= !(1 << sound_bit);
+            self.stop(status_reg);
         }
         sample
     }
 }
 
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct ChannelMixer {
-    global_regs: SharedAudioRegs,
-    cached_regs: RefCell<CachedAudioRegs>,
-    square_1: MaybeSound<Square>,
-    square_2: MaybeSound<Square>,
-    wave: MaybeSound<Wave>,
-    noise: MaybeSound<Noise>,
-
-    length_timer: Cycle<Timer>,
+    pub regs: AudioRegs,
+    sequencer: FrameSequencer,
+    square_1: Channel<Square>,
+    square_2: Channel<Square>,
+    wave: Channel<Wave>,
+    noise: Channel<Noise>,
+    /// Channels left out of the mix, one bit per channel (bit 0 is square 1). Not visible to the
+    /// emulated program.
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    muted: u8,
+    /// If any bit is set, only those channels are mixed.
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    soloed: u8,
+    /// The output of every channel on the last T-cycle, including muted ones.
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    last_channels: ChannelFrame,
 }
 
-impl ChannelMixer {
-    pub fn new(global_regs: SharedAudioRegs) -> ChannelMixer {
+impl Default for ChannelMixer {
+    fn default() -> ChannelMixer {
         ChannelMixer {
-            global_regs,
-            cached_regs: Default::default(),
-            length_timer: timer(super::LENGTH_COUNTER_PERIOD).cycle(),
-            square_1: MaybeSound(None),
-            square_2: MaybeSound(None),
-            wave: MaybeSound(None),
-            noise: MaybeSound(None),
+            regs: Default::default(),
+            sequencer: Default::default(),
+            square_1: Channel::new(64, 0),
+            square_2: Channel::new(64, 1),
+            wave: Channel::new(256, 2),
+            noise: Channel::new(64, 3),
+            muted: 0,
+            soloed: 0,
+            last_channels: [0.0; NUM_CHANNELS],
         }
     }
+}
 
-    pub fn on_sample_begin(&mut self) {
-        self.handle_events();
-        // Update all current sounds with any changes from the audio registers.
-        let cached_regs = self.cached_regs.borrow();
-        if let Some(square) = &mut self.square_1.0 {
-            square.update_from_reg(cached_regs.square_1_config.0);
-        }
-        if let Some(square) = &mut self.square_2.0 {
-            square.update_from_reg(cached_regs.square_2_config.0);
-        }
-        if let Some(wave) = &mut self.wave.0 {
-            wave.update_from_reg(cached_regs.wave_config.0);
-        }
-        if let Some(noise) = &mut self.noise.0 {
-            noise.update_from_reg(cached_regs.noise_config.0);
+impl ChannelMixer {
+    /// Must be called after every register write, with the address written to. Reloads length
+    /// counters, starts any sound whose trigger bit was set, and updates the playing sounds with
+    /// the new register values.
+    pub fn on_register_write(&mut self, raw: i32) {
+        let regs = &mut self.regs;
+        let status = &mut regs.sound_status;
+        let is_length_step_next = self.sequencer.is_length_step_next();
+        let dacs = [
+            regs.square_1_config.dac_power() != 0,
+            regs.square_2_config.dac_power() != 0,
+            regs.wave_config.enabled(),
+            regs.noise_config.dac_power() != 0,
+        ];
+        match raw {
+            0xFF11 => self.square_1.length.load(regs.square_1_config.length().into()),
+            0xFF16 => self.square_2.length.load(regs.square_2_config.length().into()),
+            0xFF1B => self.wave.length.load(regs.wave_config.length()),
+            0xFF20 => self.noise.length.load(regs.noise_config.length().into()),
+            0xFF14 => self.square_1.write_control(
+                &mut regs.square_1_config.0,
+                status,
+                is_length_step_next,
+                dacs[0],
+                |x| Square::new(SquareConfig(x)),
+            ),
+            0xFF19 => self.square_2.write_control(
+                &mut regs.square_2_config.0,
+                status,
+                is_length_step_next,
+                dacs[1],
+                |x| Square::new(SquareConfig(x)),
+            ),
+            0xFF1E => {
+                let mut sample_buffer = 0;
+                if let Some(wave) = &self.wave.sound {
+                    if CommonSoundConfig(regs.wave_config.0).triggered() && dacs[2] {
+                        wave.corrupt_on_retrigger(&mut regs.wave_table);
+                    }
+                    sample_buffer = wave.sample_buffer;
+                }
+                self.wave.write_control(
+                    &mut regs.wave_config.0,
+                    status,
+                    is_length_step_next,
+                    dacs[2],
+                    |x| Wave::new(WaveConfig(x), sample_buffer),
+                )
+            }
+            0xFF23 => self.noise.write_control(
+                &mut regs.noise_config.0,
+                status,
+                is_length_step_next,
+                dacs[3],
+                |x| Noise::new(NoiseConfig(x)),
+            ),
+            _ => (),
         }
+        self.square_1.update(regs.square_1_config.0, dacs[0], status);
+        self.square_2.update(regs.square_2_config.0, dacs[1], status);
+        self.wave.update(regs.wave_config.0, dacs[2], status);
+        self.noise.update(regs.noise_config.0, dacs[3], status);
     }
 
-    pub fn on_sample_end(&mut self) {
-        // Update the global registers based on the current sound state.
-        let prev_state = self.cached_regs.clone().into_inner();
-        let mut cached_regs = self.cached_regs.borrow_mut();
-        if let Some(square) = &self.square_1.0 {
-            square.update_to_reg(&mut cached_regs.square_1_config.0);
-        }
-        if let Some(square) = &self.square_2.0 {
-            square.update_to_reg(&mut cached_regs.square_2_config.0);
+    /// CPU read from wave RAM. While the wave channel plays, only the byte it is playing can be
+    /// accessed, and only for a short time.
+    pub fn read_wave_ram(&self, index: i32) -> i32 {
+        match &self.wave.sound {
+            Some(wave) => wave
+                .cpu_accessible_byte()
+                .map_or(0xFF, |x| super::get_byte(self.regs.wave_table, x)),
+            None => super::get_byte(self.regs.wave_table, index),
         }
-        if let Some(wave) = &self.wave.0 {
-            wave.update_to_reg(&mut cached_regs.wave_config.0);
+    }
+
+    /// CPU write to wave RAM. Same restrictions as reads.
+    pub fn write_wave_ram(&mut self, index: i32, value: i32) {
+        let index = match &self.wave.sound {
+            Some(wave) => match wave.cpu_accessible_byte() {
+                Some(x) => x,
+                None => return,
+            },
+            None => index,
+        };
+        super::set_byte(&mut self.regs.wave_table, index, value);
+    }
+
+    /// Takes over the emulated state of `state`, e.g. one loaded from a save-state. Muting and
+    /// soloing are kept.
+    pub fn restore(&mut self, state: ChannelMixer) {
+        let (muted, soloed) = (self.muted, self.soloed);
+        *self = ChannelMixer { muted, soloed, ..state };
+    }
+
+    pub fn is_powered(&self) -> bool {
+        self.regs.sound_status.global_enable()
+    }
+
+    /// Handles a write to NR52 bit 7. Powering off zeroes NR10-NR51 and turns all channels off.
+    /// The length counters and wave RAM are left alone. Powering on restarts the frame sequencer.
+    pub fn set_power(&mut self, is_on: bool) {
+        if is_on && !self.is_powered() {
+            self.sequencer.reset();
         }
-        if let Some(noise) = &self.noise.0 {
-            noise.update_to_reg(&mut cached_regs.noise_config.0);
+        if !is_on {
+            let wave_table = self.regs.wave_table;
+            self.regs = AudioRegs { wave_table, ..Default::default() };
+            self.square_1.power_off();
+            self.square_2.power_off();
+            self.wave.power_off();
+            self.noise.power_off();
         }
-        cached_regs.sync_to_shared(&prev_state, &mut self.global_regs);
+        self.regs.sound_status.0 = (self.regs.sound_status.0 & !0x80) | ((is_on as u8) << 7);
     }
 
-    fn handle_events(&mut self) {
-        for event in self.global_regs.poll_events() {
-            self.handle_event(event);
-        }
-        // TODO: Can combine in one pass.
-        self.cached_regs.borrow_mut().sync_from_shared(&self.global_regs);
+    pub fn set_muted(&mut self, channel: usize, is_muted: bool) {
+        debug_assert_lt!(channel, NUM_CHANNELS);
+        self.muted = (self.muted & !(1 << channel)) | ((is_muted as u8) << channel);
     }
 
-    fn handle_event(&mut self, event: ChannelEvent) {
-        use ChannelEvent::*;
-        match event {
-            TriggerSquare1(config) => {
-                self.square_1 = MaybeSound(Some(Square::new(config)));
-            }
-            TriggerSquare2(config) => {
-                self.square_2 = MaybeSound(Some(Square::new(config)));
-            }
-            TriggerWave(config) => {
-                let wave_table: u128 = *self.global_regs.wave_table.try_read().unwrap();
-                self.wave = MaybeSound(Some(Wave::new(config, wave_table)));
-            }
-            TriggerNoise(config) => {
-                self.noise = MaybeSound(Some(Noise::new(config)));
-            }
+    pub fn is_muted(&self, channel: usize) -> bool {
+        self.muted & (1 << channel) != 0
+    }
+
+    pub fn set_soloed(&mut self, channel: usize, is_soloed: bool) {
+        debug_assert_lt!(channel, NUM_CHANNELS);
+        self.soloed = (self.soloed & !(1 << channel)) | ((is_soloed as u8) << channel);
+    }
+
+    pub fn is_soloed(&self, channel: usize) -> bool {
+        self.soloed & (1 << channel) != 0
+    }
+
+    /// The channels that make it into the mix, one bit per channel.
+    fn audible_channels(&self) -> u8 {
+        if self.soloed != 0 {
+            self.soloed
+        } else {
+            !self.muted & 0xF
         }
     }
 
-    pub fn next_sample(&mut self) -> [f32; 2] {
-        let mut cached_regs = self.cached_regs.borrow_mut();
+    /// The output of every channel on the last T-cycle, before muting and mixing.
+    pub fn last_channels(&self) -> ChannelFrame {
+        self.last_channels
+    }
+
+    /// Advances all sounds by one T-cycle, and returns the mixed frame. `sequencer_input` is DIV
+    /// bit 12, which drives the frame sequencer.
+    pub fn next_sample(&mut self, sequencer_input: bool) -> StereoFrame {
+        let component_cycles = self.sequencer.tick(sequencer_input);
+        let regs = &mut self.regs;
         // Completely ignore if audio is off.
-        if !cached_regs.sound_status.global_enable() {
+        if !regs.sound_status.global_enable() {
+            self.last_channels = [0.0; NUM_CHANNELS];
             return [0.0, 0.0];
         }
         // First, collect all the mono frames.
-        // TODO: Will be simplified once all sounds are implemented as traits.
-        let mut component_cycles = ComponentCycle::empty();
-        if self.length_timer.next().unwrap() == 0 {
-            component_cycles |= ComponentCycle::LENGTH;
-        }
+        let status = &mut regs.sound_status;
         let mono_frames = [
-            self.square_1.maybe_sample(component_cycles, &mut cached_regs.sound_status, 0),
-            self.square_2.maybe_sample(component_cycles, &mut cached_regs.sound_status, 1),
-            self.wave.maybe_sample(component_cycles, &mut cached_regs.sound_status, 2),
-            self.noise.maybe_sample(component_cycles, &mut cached_regs.sound_status, 3),
+            self.square_1.sample(component_cycles, &mut regs.square_1_config.0, status),
+            self.square_2.sample(component_cycles, &mut regs.square_2_config.0, status),
+            self.wave.sample(component_cycles, &mut regs.wave_config.0, status),
+            self.noise.sample(component_cycles, &mut regs.noise_config.0, status),
         ];
-        let sound_mix = cached_regs.sound_mix;
+        if let Some(wave) = &mut self.wave.sound {
+            wave.read_ram(regs.wave_table);
+        }
+        self.last_channels = mono_frames;
+        let audible = self.audible_channels();
+        let regs = &mut self.regs;
+        let sound_mix = regs.sound_mix.0 & (audible | audible << 4);
 
         let mut frame = [0.0, 0.0];
         let mut add_to_frame = |idx, bits| {
@@ -265,11 +338,11 @@ impl ChannelMixer {
                 frame[idx] += mono / 4.0;
             }
         };
-        let volume_control = cached_regs.volume_control;
+        let volume_control = regs.volume_control;
         // Mix in the right channel.
-        add_to_frame(1, sound_mix.0);
+        add_to_frame(1, sound_mix);
         // And the left channel.
-        add_to_frame(0, sound_mix.0 >> 4);
+        add_to_frame(0, sound_mix >> 4);
         // Scale left/right volumes.
         frame[0] *= (volume_control.left() as f32 + 1.0) / 15.0;
         frame[1] *= (volume_control.right() as f32 + 1.0) / 15.0;
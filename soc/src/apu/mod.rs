@@ -1,41 +1,272 @@
 use num_traits::PrimInt;
-use spin::RwLock;
-use std::sync::atomic::AtomicU64;
-use std::sync::Arc;
 
+mod blip;
+#[cfg(not(target_arch = "wasm32"))]
 mod device;
+mod frame_sequencer;
 mod mixer;
+pub mod output;
 mod registers;
 mod sound;
-mod threads;
 
 use crate::mmu;
-use mixer::SharedAudioRegs;
+use blip::{Blip, HighPass};
+use mixer::ChannelMixer;
+use output::AudioOutput;
+
+pub use mixer::{ChannelFrame, StereoFrame, NUM_CHANNELS};
 
 pub const TCYCLE_FREQ: i32 = 4_194_304;
 
-pub const LENGTH_COUNTER_PERIOD: i32 = TCYCLE_FREQ / 256;
-pub const ENVELOPE_PERIOD: i32 = TCYCLE_FREQ / 64;
-pub const SWEEP_PERIOD: i32 = TCYCLE_FREQ / 128;
+/// The rate the APU produces samples at, unless told otherwise. Also the rate of the audio device.
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+
 pub const NOISE_PERIOD: i32 = 8;
 
-pub type SharedWaveTable = Arc<RwLock<u128>>;
+/// How many T-cycles to synthesize before handing the samples off to the output.
+const CHUNK_CYCLES: u32 = 4096;
+
+/// Stereo samples at the APU's sample rate, stamped with the emulated T-cycle the first one
+/// corresponds to.
+#[derive(Default, Debug, PartialEq)]
+pub struct SampleBuffer {
+    pub start_cycle: u64,
+    pub frames: Vec<StereoFrame>,
+}
 
 pub struct Apu {
-    #[allow(dead_code)]
-    device: Option<device::Device>,
+    output: Box<dyn AudioOutput>,
+    mixer: ChannelMixer,
+    sample_rate: u32,
+    /// Left and right synthesizers, fed with every change in the mixer's output.
+    blips: [Blip; 2],
+    filters: [HighPass; 2],
+    /// The mixer's output on the last T-cycle.
+    last_frame: StereoFrame,
+    /// T-cycles into the current blip frame.
+    frame_clocks: u32,
+    /// The T-cycle the first sample corresponds to, and how many samples were produced since.
+    /// Used to stamp the samples.
+    start_cycle: Option<u64>,
+    num_samples: u64,
+    /// Samples that have yet to be handed off to the output.
+    samples: SampleBuffer,
+    /// Per-channel samples, only synthesized when enabled.
+    taps: Option<ChannelTaps>,
+}
+
+/// Synthesizes every channel on its own, e.g. to draw per-channel oscilloscopes.
+struct ChannelTaps {
+    blips: Vec<Blip>,
+    last_frame: ChannelFrame,
+    /// Samples that have yet to be taken.
+    samples: Vec<ChannelFrame>,
+}
+
+impl ChannelTaps {
+    fn new(sample_rate: u32) -> ChannelTaps {
+        ChannelTaps {
+            blips: (0..NUM_CHANNELS).map(|_| Blip::new(sample_rate)).collect(),
+            last_frame: [0.0; NUM_CHANNELS],
+            samples: Vec::new(),
+        }
+    }
+
+    fn add_frame(&mut self, clock: u32, frame: ChannelFrame) {
+        for ((blip, new), old) in self.blips.iter_mut().zip(&frame).zip(&self.last_frame) {
+            if new != old {
+                blip.add_delta(clock, new - old);
+            }
+        }
+        self.last_frame = frame;
+    }
+
+    fn end_frame(&mut self, clocks: u32) {
+        let mut channels = [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
+        for (blip, channel) in self.blips.iter_mut().zip(channels.iter_mut()) {
+            blip.end_frame(clocks);
+            blip.read_samples(channel);
+        }
+        self.samples.extend(
+            (0..channels[0].len())
+                .map(|i| [channels[0][i], channels[1][i], channels[2][i], channels[3][i]]),
+        );
+    }
+}
+
+/// Only the emulated state (the registers, channels and frame sequencer) is saved. A loaded APU
+/// has no output, see `restore_state`.
+#[cfg(feature = "serialize")]
+impl serde::Serialize for Apu {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.mixer.serialize(serializer)
+    }
+}
 
-    audio_regs: SharedAudioRegs,
+#[cfg(feature = "serialize")]
+impl<'de> serde::Deserialize<'de> for Apu {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Apu, D::Error> {
+        let mixer = ChannelMixer::deserialize(deserializer)?;
+        Ok(Apu { mixer, ..Apu::with_output(Box::new(output::NullOutput)) })
+    }
 }
 
+/// Plays audio through the default audio device.
 impl Default for Apu {
     fn default() -> Self {
-        let audio_regs = SharedAudioRegs::default();
-        let maybe_device = device::Device::try_new(audio_regs.clone());
-        if let Err(err) = &maybe_device {
-            eprintln!("Could not initialize audio. Audio will be disabled. Error: {}", err);
+        Apu::with_output(output::default_device())
+    }
+}
+
+impl Apu {
+    pub fn with_output(output: Box<dyn AudioOutput>) -> Apu {
+        let rate = DEFAULT_SAMPLE_RATE;
+        Apu {
+            output,
+            mixer: ChannelMixer::default(),
+            sample_rate: rate,
+            blips: [Blip::new(rate), Blip::new(rate)],
+            filters: [HighPass::new(rate), HighPass::new(rate)],
+            last_frame: [0.0, 0.0],
+            frame_clocks: 0,
+            start_cycle: None,
+            num_samples: 0,
+            samples: SampleBuffer::default(),
+            taps: None,
+        }
+    }
+
+    /// Replaces the output. Any pending samples are flushed to the old output first.
+    pub fn set_output(&mut self, output: Box<dyn AudioOutput>) {
+        self.flush();
+        self.output = output;
+    }
+
+    /// Sends samples to `output` in addition to the current output.
+    pub fn add_output(&mut self, output: Box<dyn AudioOutput>) {
+        let current = std::mem::replace(&mut self.output, Box::new(output::NullOutput));
+        self.output = Box::new(output::Tee(current, output));
+    }
+
+    /// Takes over the emulated state of `state`, e.g. one loaded from a save-state. The output,
+    /// sample rate, taps and muting are kept. Pending samples are handed off first, and the
+    /// following ones are stamped from the restored cycle on.
+    pub fn restore_state(&mut self, state: Apu) {
+        self.flush();
+        self.mixer.restore(state.mixer);
+        self.start_cycle = None;
+        self.num_samples = 0;
+    }
+
+    /// Tells the output how fast emulation runs relative to real time. 0 when paused.
+    pub fn set_speed(&mut self, speed: f32) {
+        self.output.set_speed(speed);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Changes the rate samples are produced at. All outputs must accept that rate (the audio
+    /// device only plays DEFAULT_SAMPLE_RATE). Pending samples are flushed first.
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.flush();
+        self.sample_rate = rate;
+        self.blips = [Blip::new(rate), Blip::new(rate)];
+        self.filters = [HighPass::new(rate), HighPass::new(rate)];
+        self.last_frame = [0.0, 0.0];
+        self.start_cycle = None;
+        self.num_samples = 0;
+        if self.taps.is_some() {
+            self.taps = Some(ChannelTaps::new(rate));
+        }
+    }
+
+    /// Leaves a channel (0 to 3: square 1, square 2, wave, noise) out of the mix. Only affects
+    /// what is heard, the emulated program can't tell.
+    pub fn set_channel_muted(&mut self, channel: usize, is_muted: bool) {
+        self.mixer.set_muted(channel, is_muted);
+    }
+
+    pub fn is_channel_muted(&self, channel: usize) -> bool {
+        self.mixer.is_muted(channel)
+    }
+
+    /// While any channel is soloed, only soloed channels are mixed, muted or not.
+    pub fn set_channel_soloed(&mut self, channel: usize, is_soloed: bool) {
+        self.mixer.set_soloed(channel, is_soloed);
+    }
+
+    pub fn is_channel_soloed(&self, channel: usize) -> bool {
+        self.mixer.is_soloed(channel)
+    }
+
+    /// Starts or stops synthesizing every channel on its own, at the APU's sample rate. Muting
+    /// doesn't affect these samples.
+    pub fn enable_channel_taps(&mut self, is_enabled: bool) {
+        self.taps = if is_enabled { Some(ChannelTaps::new(self.sample_rate)) } else { None };
+    }
+
+    /// Returns the per-channel samples produced since the last call. Empty unless enabled with
+    /// `enable_channel_taps`.
+    pub fn take_channel_samples(&mut self) -> Vec<ChannelFrame> {
+        self.taps.as_mut().map(|x| std::mem::take(&mut x.samples)).unwrap_or_default()
+    }
+
+    /// Hands off all pending samples to the output, and flushes it.
+    pub fn flush(&mut self) {
+        self.end_frame();
+        self.output.flush();
+    }
+
+    /// Advances all channels by one T-cycle. `cycle` is the emulated T-cycle count, used to stamp
+    /// the produced samples. `sequencer_input` is DIV bit 12 (see
+    /// `Timer::frame_sequencer_input`).
+    pub fn execute_tcycle(&mut self, cycle: u64, sequencer_input: bool) {
+        self.start_cycle.get_or_insert(cycle);
+        let frame = self.mixer.next_sample(sequencer_input);
+        for ((blip, new), old) in self.blips.iter_mut().zip(&frame).zip(&self.last_frame) {
+            if new != old {
+                blip.add_delta(self.frame_clocks, new - old);
+            }
         }
-        Apu { device: maybe_device.ok(), audio_regs }
+        self.last_frame = frame;
+        if let Some(taps) = &mut self.taps {
+            taps.add_frame(self.frame_clocks, self.mixer.last_channels());
+        }
+        self.frame_clocks += 1;
+        if self.frame_clocks >= CHUNK_CYCLES {
+            self.end_frame();
+        }
+    }
+
+    /// Synthesizes the samples up to the current T-cycle, and hands them off to the output.
+    fn end_frame(&mut self) {
+        let mut sides = [Vec::new(), Vec::new()];
+        for (blip, side) in self.blips.iter_mut().zip(sides.iter_mut()) {
+            blip.end_frame(self.frame_clocks);
+            blip.read_samples(side);
+        }
+        if let Some(taps) = &mut self.taps {
+            taps.end_frame(self.frame_clocks);
+        }
+        self.frame_clocks = 0;
+        if sides[0].is_empty() {
+            return;
+        }
+        let start_cycle = self.start_cycle.unwrap_or_default();
+        self.samples.start_cycle =
+            start_cycle + self.num_samples * TCYCLE_FREQ as u64 / u64::from(self.sample_rate);
+        let filters = &mut self.filters;
+        self.samples.frames.extend(
+            sides[0]
+                .iter()
+                .zip(sides[1].iter())
+                .map(|(&l, &r)| [filters[0].filter(l), filters[1].filter(r)]),
+        );
+        self.num_samples += sides[0].len() as u64;
+        self.output.push_samples(&self.samples);
+        self.samples.frames.clear();
     }
 }
 
@@ -46,22 +277,6 @@ fn set_byte<T: PrimInt>(reg: &mut T, i: i32, value: i32) {
     *reg = (*reg & !mask) | (T::from(value).unwrap() << (8 * i));
 }
 
-fn atomic_set_byte(reg: &AtomicU64, i: i32, value: i32) {
-    use std::sync::atomic::Ordering;
-    let mut current = reg.load(Ordering::Acquire);
-    loop {
-        let mut new_reg = current;
-        set_byte(&mut new_reg, i, value);
-        match reg.compare_exchange_weak(current, new_reg, Ordering::Release, Ordering::Relaxed) {
-            Ok(_) => break,
-            Err(x) => {
-                current = x;
-                eprintln!("There is actual contention with audio thread.")
-            }
-        }
-    }
-}
-
 fn get_byte<T: PrimInt>(reg: T, i: i32) -> i32 {
     debug_assert_lt!(i as usize, std::mem::size_of::<T>());
     use num_traits::cast::NumCast;
@@ -69,86 +284,424 @@ fn get_byte<T: PrimInt>(reg: T, i: i32) -> i32 {
     <i32 as NumCast>::from((reg >> (8 * i)) & T::from(0xFF).unwrap()).unwrap()
 }
 
+/// Bits that always read as 1, for NR10 (0xFF10) through NR52 (0xFF26). These are either unused,
+/// or write-only.
+#[rustfmt::skip]
+const READ_MASKS: [u8; 0x17] = [
+    // NR10-NR14
+    0x80, 0x3F, 0x00, 0xFF, 0xBF,
+    // NR20-NR24
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF,
+    // NR30-NR34
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF,
+    // NR40-NR44
+    0xFF, 0xFF, 0x00, 0x00, 0xBF,
+    // NR50-NR52
+    0x00, 0x00, 0x70,
+];
+
 impl mmu::MemoryMapped for Apu {
     fn read(&self, address: mmu::Address) -> Option<i32> {
-        use std::sync::atomic::Ordering::Acquire;
         let mmu::Address(_, raw) = address;
-        match raw {
+        let regs = &self.mixer.regs;
+        let value = match raw {
             // Volume control (NR50)
-            0xFF24 => Some(i32::from(self.audio_regs.volume_control.load(Acquire))),
+            0xFF24 => i32::from(regs.volume_control.0),
             // Channel R/L mix (NR51)
-            0xFF25 => Some(i32::from(self.audio_regs.sound_mix.load(Acquire))),
+            0xFF25 => i32::from(regs.sound_mix.0),
             // Sound status (NR52).
-            0xFF26 => {
-                //panic!("Ruh-oh!");
-                Some(i32::from(self.audio_regs.sound_status.load(Acquire) | 0x70))
-            }
+            0xFF26 => i32::from(regs.sound_status.0),
             // Square 1
-            0xFF10..=0xFF14 => {
-                Some(get_byte(self.audio_regs.square_1_config.load(Acquire), raw - 0xFF10))
-            }
-            // Square 2
-            0xFF16..=0xFF19 => {
-                Some(get_byte(self.audio_regs.square_2_config.load(Acquire), raw - 0xFF15))
-            }
+            0xFF10..=0xFF14 => get_byte(regs.square_1_config.0, raw - 0xFF10),
+            // Square 2. There is no NR20.
+            0xFF15..=0xFF19 => get_byte(regs.square_2_config.0, raw - 0xFF15),
             // Wave
-            0xFF1A..=0xFF1E => {
-                Some(get_byte(self.audio_regs.wave_config.load(Acquire), raw - 0xFF1A))
-            }
-            // Noise
-            0xFF20..=0xFF23 => {
-                Some(get_byte(self.audio_regs.noise_config.load(Acquire), raw - 0xFF1F))
-            }
+            0xFF1A..=0xFF1E => get_byte(regs.wave_config.0, raw - 0xFF1A),
+            // Noise. There is no NR40.
+            0xFF1F..=0xFF23 => get_byte(regs.noise_config.0, raw - 0xFF1F),
+            // Unused.
+            0xFF27..=0xFF2F => return Some(0xFF),
             // Wave table
-            0xFF30..=0xFF3F => Some(get_byte(*self.audio_regs.wave_table.read(), raw - 0xFF30)),
-            _ => None,
-        }
+            0xFF30..=0xFF3F => return Some(self.mixer.read_wave_ram(raw - 0xFF30)),
+            _ => return None,
+        };
+        Some(value | i32::from(READ_MASKS[(raw - 0xFF10) as usize]))
     }
 
-    #[allow(clippy::unit_arg)]
     fn write(&mut self, address: mmu::Address, value: i32) -> Option<()> {
-        use crate::util::AtomicInt;
-        use std::sync::atomic::Ordering;
         let mmu::Address(_, raw) = address;
+        // While powered off, only NR52, wave RAM and (on DMG) the length counters can be written.
+        let value = match raw {
+            0xFF10..=0xFF25 if !self.mixer.is_powered() => match raw {
+                0xFF11 | 0xFF16 => value & 0x3F,
+                0xFF1B | 0xFF20 => value,
+                _ => return Some(()),
+            },
+            _ => value,
+        };
+        let regs = &mut self.mixer.regs;
         match raw {
             // Volume control
-            0xFF24 => Some(self.audio_regs.volume_control.store(value as u8, Ordering::Release)),
+            0xFF24 => regs.volume_control.0 = value as u8,
             // Channel R/ L mix
-            0xFF25 => Some({
-                self.audio_regs.sound_mix.store(value as u8, Ordering::Release);
-            }),
+            0xFF25 => regs.sound_mix.0 = value as u8,
             // Sound status (NR52)
-            0xFF26 => Some({
-                self.audio_regs.sound_status.weak_update_with(Ordering::Release, |x: u8| {
-                    (x & !0x80) | (value as u8 & 0x80)
-                });
-            }),
+            0xFF26 => self.mixer.set_power(value & 0x80 != 0),
             // Square 1
-            0xFF10..=0xFF14 => {
-                atomic_set_byte(&self.audio_regs.square_1_config, raw - 0xFF10, value);
-                Some(())
-            }
+            0xFF10..=0xFF14 => set_byte(&mut regs.square_1_config.0, raw - 0xFF10, value),
             // Square 2
-            0xFF16..=0xFF19 => Some({
-                atomic_set_byte(&self.audio_regs.square_2_config, raw - 0xFF15, value);
-            }),
+            0xFF16..=0xFF19 => set_byte(&mut regs.square_2_config.0, raw - 0xFF15, value),
             // Wave
-            0xFF1A..=0xFF1E => Some({
-                atomic_set_byte(&self.audio_regs.wave_config, raw - 0xFF1A, value);
-            }),
+            0xFF1A..=0xFF1E => set_byte(&mut regs.wave_config.0, raw - 0xFF1A, value),
             // Noise
-            0xFF20..=0xFF23 => Some({
-                atomic_set_byte(&self.audio_regs.noise_config, raw - 0xFF1F, value);
-            }),
+            0xFF20..=0xFF23 => set_byte(&mut regs.noise_config.0, raw - 0xFF1F, value),
             // Wave table
-            0xFF30..=0xFF3F => {
-                // Lock for an EXTREMELY brief period of time so as to never block the audio thread.
-                let mut wave_table = self.audio_regs.wave_table.write();
-                set_byte(&mut *wave_table, raw - 0xFF30, value);
-                Some(())
-            }
+            0xFF30..=0xFF3F => self.mixer.write_wave_ram(raw - 0xFF30, value),
+            // Unused.
+            0xFF15 | 0xFF1F | 0xFF27..=0xFF2F => return Some(()),
+            _ => return None,
+        }
+        self.mixer.on_register_write(raw);
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mmu::MemoryMapped;
+    use output::MemoryOutput;
+
+    fn make_apu() -> (Apu, MemoryOutput) {
+        let memory = MemoryOutput::default();
+        (Apu::with_output(Box::new(memory.clone())), memory)
+    }
+
+    fn write(apu: &mut Apu, raw: i32, value: i32) {
+        apu.write(mmu::Address::from_raw(raw).unwrap(), value).unwrap();
+    }
+
+    fn read(apu: &Apu, raw: i32) -> i32 {
+        apu.read(mmu::Address::from_raw(raw).unwrap()).unwrap()
+    }
+
+    /// Powers on the APU and starts a square wave on channel 1, mixed to both sides.
+    fn play_square(apu: &mut Apu) {
+        write(apu, 0xFF26, 0x80);
+        write(apu, 0xFF24, 0x77);
+        write(apu, 0xFF25, 0x11);
+        write(apu, 0xFF11, 0x80);
+        write(apu, 0xFF12, 0xF0);
+        write(apu, 0xFF13, 0x00);
+        write(apu, 0xFF14, 0x87);
+    }
+
+    /// Runs the APU as if DIV started at 0 on cycle 0. Returns the mixer's output on every
+    /// T-cycle.
+    fn run(apu: &mut Apu, start: u64, tcycles: u64) -> Vec<StereoFrame> {
+        (start..start + tcycles)
+            .map(|cycle| {
+                apu.execute_tcycle(cycle, cycle & (1 << 12) != 0);
+                apu.last_frame
+            })
+            .collect()
+    }
+
+    /// Runs the APU, and returns the samples handed off to the output.
+    fn run_output(apu: &mut Apu, memory: &MemoryOutput, start: u64, tcycles: u64) -> SampleBuffer {
+        run(apu, start, tcycles);
+        apu.flush();
+        memory.take()
+    }
 
-            _ => None,
+    #[test]
+    fn test_samples_are_stamped_with_cycles() {
+        let (mut apu, memory) = make_apu();
+        let cycles_per_sample = |x: u64| x * TCYCLE_FREQ as u64 / u64::from(DEFAULT_SAMPLE_RATE);
+        let samples = run_output(&mut apu, &memory, 100, 64);
+        assert_eq!(samples, SampleBuffer::default());
+        let samples = run_output(&mut apu, &memory, 164, 40960);
+        assert_eq!(samples.start_cycle, 100);
+        assert_eq!(samples.frames.len(), 469);
+        // Chunks keep their stamps when handed off.
+        let samples = run_output(&mut apu, &memory, 41124, 40960);
+        assert_eq!(samples.start_cycle, 100 + cycles_per_sample(469));
+        assert_eq!(samples.frames.len(), 938 - 469);
+    }
+
+    #[test]
+    fn test_trigger_is_synchronous() {
+        let (mut apu, _) = make_apu();
+        play_square(&mut apu);
+        // The trigger bit is consumed by the write, and the channel reads as on.
+        assert_eq!(apu.mixer.regs.square_1_config.0 & (1 << 39), 0);
+        assert_eq!(read(&apu, 0xFF26) & 0x1, 0x1);
+        // Frequency 0x700 has a period of (2048 - 0x700) * 4 * 8 = 8192 T-cycles, with 50% duty.
+        let frames = run(&mut apu, 0, 8192);
+        let high = frames.iter().filter(|x| x[0] > 0.0).count();
+        assert_eq!(high, 4096);
+        assert!(frames.iter().all(|x| x[0] == x[1]));
+    }
+
+    #[test]
+    fn test_output_is_deterministic() {
+        let (mut first, first_memory) = make_apu();
+        let (mut second, second_memory) = make_apu();
+        play_square(&mut first);
+        play_square(&mut second);
+        assert_eq!(
+            run_output(&mut first, &first_memory, 0, 30000),
+            run_output(&mut second, &second_memory, 0, 30000)
+        );
+    }
+
+    #[test]
+    fn test_length_counter_turns_channel_off() {
+        let (mut apu, _) = make_apu();
+        play_square(&mut apu);
+        // Length 2, enabled and retriggered on the first half of a length period.
+        write(&mut apu, 0xFF11, 0x3E);
+        write(&mut apu, 0xFF14, 0xC7);
+        // The sequencer steps every 8192 T-cycles, and clocks length every other step.
+        run(&mut apu, 0, 8192 * 3);
+        assert_eq!(read(&apu, 0xFF26) & 0x1, 0x1);
+        run(&mut apu, 8192 * 3, 1);
+        assert_eq!(read(&apu, 0xFF26) & 0x1, 0x0);
+        // The channel goes silent.
+        assert!(run(&mut apu, 8192 * 3 + 1, 8192).iter().all(|x| x[0] == 0.0));
+    }
+
+    #[test]
+    fn test_read_masks() {
+        let (mut apu, _) = make_apu();
+        write(&mut apu, 0xFF26, 0x80);
+        for raw in 0xFF10..=0xFF25 {
+            write(&mut apu, raw, 0);
+        }
+        for raw in 0xFF10..=0xFF25 {
+            assert_eq!(read(&apu, raw), i32::from(READ_MASKS[(raw - 0xFF10) as usize]));
+        }
+        assert_eq!(read(&apu, 0xFF26), 0xF0);
+        for raw in 0xFF27..=0xFF2F {
+            assert_eq!(read(&apu, raw), 0xFF);
+        }
+        // NR11 keeps its duty readable.
+        write(&mut apu, 0xFF11, 0x81);
+        assert_eq!(read(&apu, 0xFF11), 0xBF);
+    }
+
+    #[test]
+    fn test_power_off_clears_registers() {
+        let (mut apu, _) = make_apu();
+        play_square(&mut apu);
+        write(&mut apu, 0xFF30, 0x12);
+        write(&mut apu, 0xFF26, 0x00);
+        assert_eq!(read(&apu, 0xFF26), 0x70);
+        assert_eq!(read(&apu, 0xFF24), 0x00);
+        assert_eq!(read(&apu, 0xFF12), 0x00);
+        // Wave RAM survives.
+        assert_eq!(read(&apu, 0xFF30), 0x12);
+        // Writes are ignored while off, except for the length registers.
+        write(&mut apu, 0xFF24, 0x77);
+        write(&mut apu, 0xFF11, 0xFF);
+        assert_eq!(read(&apu, 0xFF24), 0x00);
+        assert_eq!(read(&apu, 0xFF11), 0x3F);
+        write(&mut apu, 0xFF26, 0x80);
+        write(&mut apu, 0xFF24, 0x77);
+        assert_eq!(read(&apu, 0xFF24), 0x77);
+    }
+
+    #[test]
+    fn test_dac_controls_channel() {
+        let (mut apu, _) = make_apu();
+        play_square(&mut apu);
+        assert_eq!(read(&apu, 0xFF26) & 0x1, 0x1);
+        // Turning the DAC off turns the channel off.
+        write(&mut apu, 0xFF12, 0x00);
+        assert_eq!(read(&apu, 0xFF26) & 0x1, 0x0);
+        // And triggering doesn't turn it back on.
+        write(&mut apu, 0xFF14, 0x87);
+        assert_eq!(read(&apu, 0xFF26) & 0x1, 0x0);
+        // Envelope mode alone powers the DAC, even with a volume of 0.
+        write(&mut apu, 0xFF12, 0x08);
+        write(&mut apu, 0xFF14, 0x87);
+        assert_eq!(read(&apu, 0xFF26) & 0x1, 0x1);
+        // The wave DAC is NR30 bit 7.
+        write(&mut apu, 0xFF1E, 0x80);
+        assert_eq!(read(&apu, 0xFF26) & 0x4, 0x0);
+        write(&mut apu, 0xFF1A, 0x80);
+        write(&mut apu, 0xFF1E, 0x80);
+        assert_eq!(read(&apu, 0xFF26) & 0x4, 0x4);
+    }
+
+    /// Fills wave RAM with 0x00, 0x01, ..., 0x0F, and starts the wave channel with a period of 512
+    /// T-cycles per nibble.
+    fn play_wave(apu: &mut Apu) {
+        write(apu, 0xFF26, 0x80);
+        for i in 0..16 {
+            write(apu, 0xFF30 + i, i);
+        }
+        write(apu, 0xFF1A, 0x80);
+        write(apu, 0xFF1C, 0x20);
+        write(apu, 0xFF1D, 0x00);
+        write(apu, 0xFF1E, 0x87);
+    }
+
+    #[test]
+    fn test_wave_ram_access_while_playing() {
+        let (mut apu, _) = make_apu();
+        play_wave(&mut apu);
+        // Nibbles are read every 512 T-cycles, after a 6 T-cycle trigger delay. The first read is
+        // nibble 1, in byte 0.
+        let mut reads = Vec::new();
+        for cycle in 0..512 * 4 + 8 {
+            run(&mut apu, cycle, 1);
+            reads.push(read(&apu, 0xFF30));
         }
+        let accessible: Vec<_> =
+            reads.iter().enumerate().filter(|(_, &x)| x != 0xFF).map(|(i, &x)| (i, x)).collect();
+        assert_eq!(
+            accessible,
+            [(517, 0), (518, 0), (1029, 1), (1030, 1), (1541, 1), (1542, 1), (2053, 2), (2054, 2)]
+        );
+        // Writes outside of the window are ignored.
+        write(&mut apu, 0xFF3F, 0x55);
+        assert_eq!(read(&apu, 0xFF3F), 0xFF);
+        // And inside go to the byte being played.
+        run(&mut apu, 512 * 4 + 8, 510);
+        write(&mut apu, 0xFF3F, 0x55);
+        write(&mut apu, 0xFF1A, 0x00);
+        assert_eq!(read(&apu, 0xFF32), 0x55);
+        assert_eq!(read(&apu, 0xFF3F), 0x0F);
+    }
+
+    #[test]
+    fn test_wave_plays_nibbles() {
+        let (mut apu, _) = make_apu();
+        play_wave(&mut apu);
+        write(&mut apu, 0xFF24, 0x77);
+        write(&mut apu, 0xFF25, 0x44);
+        let frames = run(&mut apu, 0, 512 * 6 + 6);
+        // The sample buffer starts out empty, then plays nibbles 1 to 5: 0, 0, 1, 0 and 2.
+        let levels: Vec<_> =
+            [0, 600, 1200, 1800, 2400, 2800].iter().map(|&i| frames[i][0]).collect();
+        assert!(levels[..3].iter().all(|&x| x == 0.0));
+        assert_gt!(levels[3], 0.0);
+        assert_eq!(levels[4], 0.0);
+        assert_eq!(levels[5], levels[3] * 2.0);
+    }
+
+    #[test]
+    fn test_wave_retrigger_corrupts_ram() {
+        let (mut apu, _) = make_apu();
+        play_wave(&mut apu);
+        // Just before reading nibble 8, in byte 4.
+        run(&mut apu, 0, 6 + 512 * 8 - 1);
+        write(&mut apu, 0xFF1E, 0x87);
+        write(&mut apu, 0xFF1A, 0x00);
+        let ram: Vec<_> = (0xFF30..0xFF38).map(|raw| read(&apu, raw)).collect();
+        assert_eq!(ram, [4, 5, 6, 7, 4, 5, 6, 7]);
+        // Byte 0 only gets a copy of the one being read when it's among the first four.
+        let (mut apu, _) = make_apu();
+        play_wave(&mut apu);
+        run(&mut apu, 0, 6 + 512 * 4 - 1);
+        write(&mut apu, 0xFF1E, 0x87);
+        write(&mut apu, 0xFF1A, 0x00);
+        let ram: Vec<_> = (0xFF30..0xFF34).map(|raw| read(&apu, raw)).collect();
+        assert_eq!(ram, [2, 1, 2, 3]);
+        // Not about to read: no corruption.
+        let (mut apu, _) = make_apu();
+        play_wave(&mut apu);
+        run(&mut apu, 0, 6 + 512 * 4 - 2);
+        write(&mut apu, 0xFF1E, 0x87);
+        write(&mut apu, 0xFF1A, 0x00);
+        assert_eq!(read(&apu, 0xFF30), 0);
+    }
+
+    #[test]
+    fn test_mute_and_solo() {
+        let (mut apu, _) = make_apu();
+        play_square(&mut apu);
+        play_wave(&mut apu);
+        write(&mut apu, 0xFF24, 0x77);
+        write(&mut apu, 0xFF25, 0x55);
+        // Both the square and the wave are high.
+        run(&mut apu, 0, 7000);
+        let both = apu.last_frame[0];
+        let channels = apu.mixer.last_channels();
+        assert_gt!(channels[0], 0.0);
+        assert_gt!(channels[2], 0.0);
+        apu.set_channel_muted(0, true);
+        run(&mut apu, 7000, 1);
+        let wave_only = apu.last_frame[0];
+        assert_lt!(wave_only, both);
+        assert_gt!(wave_only, 0.0);
+        // Muting doesn't show on NR52, nor in the channel's own output.
+        assert_eq!(read(&apu, 0xFF26) & 0x1, 0x1);
+        assert_eq!(apu.mixer.last_channels()[0], channels[0]);
+        // Soloing overrides muting.
+        apu.set_channel_soloed(0, true);
+        run(&mut apu, 7001, 1);
+        assert_lt!((apu.last_frame[0] - (both - wave_only)).abs(), 1e-6);
+        apu.set_channel_soloed(0, false);
+        apu.set_channel_muted(0, false);
+        run(&mut apu, 7002, 1);
+        assert_eq!(apu.last_frame[0], both);
+    }
+
+    #[test]
+    #[cfg(feature = "serialize")]
+    fn test_restore_state() {
+        let (mut apu, memory) = make_apu();
+        play_square(&mut apu);
+        play_wave(&mut apu);
+        run(&mut apu, 0, 5000);
+        let state = bincode::serialize(&apu).unwrap();
+        let expected = run(&mut apu, 5000, 20000);
+        write(&mut apu, 0xFF24, 0x00);
+        write(&mut apu, 0xFF12, 0x00);
+        apu.set_channel_muted(2, true);
+        apu.restore_state(bincode::deserialize(&state).unwrap());
+        assert_eq!(read(&apu, 0xFF24), 0x77);
+        assert_eq!(read(&apu, 0xFF26) & 0x1, 0x1);
+        // Muting is kept, and samples keep going to the same output.
+        assert!(apu.is_channel_muted(2));
+        apu.set_channel_muted(2, false);
+        assert_eq!(run(&mut apu, 5000, 20000), expected);
+        assert!(!run_output(&mut apu, &memory, 25000, 1000).frames.is_empty());
+    }
+
+    #[test]
+    fn test_channel_taps() {
+        let (mut apu, memory) = make_apu();
+        assert!(apu.take_channel_samples().is_empty());
+        apu.enable_channel_taps(true);
+        play_square(&mut apu);
+        apu.set_channel_muted(0, true);
+        let samples = run_output(&mut apu, &memory, 0, 8192 * 4);
+        let taps = apu.take_channel_samples();
+        assert_eq!(taps.len(), samples.frames.len());
+        // The muted square still shows up in its tap, and the other channels are silent.
+        assert!(samples.frames.iter().all(|x| x[0] == 0.0));
+        assert!(taps.iter().any(|x| x[0] > 0.5));
+        assert!(taps.iter().all(|x| x[1] == 0.0 && x[2] == 0.0 && x[3] == 0.0));
+        assert!(apu.take_channel_samples().is_empty());
+    }
+
+    #[test]
+    fn test_output_is_band_limited() {
+        let (mut apu, memory) = make_apu();
+        play_square(&mut apu);
+        // Half a second of a 512Hz square.
+        let samples = run_output(&mut apu, &memory, 0, TCYCLE_FREQ as u64 / 2);
+        let frames: Vec<_> = samples.frames.iter().map(|x| x[0]).collect();
+        assert_eq!(frames.len(), DEFAULT_SAMPLE_RATE as usize / 2);
+        assert!(frames.iter().all(|x| x.abs() <= 0.3));
+        // Once the high-pass filter has removed the DC offset, the square crosses 0 twice per
+        // period, without any ringing around the edges.
+        let tail = &frames[frames.len() / 2..];
+        assert_lt!((tail.iter().sum::<f32>() / tail.len() as f32).abs(), 0.01);
+        let crossings = tail.windows(2).filter(|x| (x[0] < 0.0) != (x[1] < 0.0)).count();
+        assert_eq!(crossings, 256);
     }
 }
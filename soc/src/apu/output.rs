@@ -0,0 +1,193 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+#[cfg(not(target_arch = "wasm32"))]
+use super::device::Device;
+use super::SampleBuffer;
+
+/// Receives the samples produced by the APU, in chunks of consecutive frames at the APU's sample
+/// rate.
+pub trait AudioOutput {
+    fn push_samples(&mut self, samples: &SampleBuffer);
+
+    /// Tells the output how fast emulation runs relative to real time. 0 when paused. Only matters
+    /// for outputs that play in real time.
+    fn set_speed(&mut self, _speed: f32) {}
+
+    /// Makes sure everything pushed so far has reached its destination.
+    fn flush(&mut self) {}
+}
+
+/// Opens the default audio device. If that fails, audio is disabled.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn default_device() -> Box<dyn AudioOutput> {
+    match Device::try_new() {
+        Ok(device) => Box::new(device),
+        Err(err) => {
+            eprintln!("Could not initialize audio. Audio will be disabled. Error: {}", err);
+            Box::new(NullOutput)
+        }
+    }
+}
+
+/// There is no audio device in wasm. The page fetches the samples through
+/// `Simulator::take_audio_samples` instead.
+#[cfg(target_arch = "wasm32")]
+pub fn default_device() -> Box<dyn AudioOutput> {
+    Box::new(NullOutput)
+}
+
+/// Discards all samples.
+pub struct NullOutput;
+
+impl AudioOutput for NullOutput {
+    fn push_samples(&mut self, _: &SampleBuffer) {}
+}
+
+/// Sends all samples to two outputs.
+pub struct Tee(pub Box<dyn AudioOutput>, pub Box<dyn AudioOutput>);
+
+impl AudioOutput for Tee {
+    fn push_samples(&mut self, samples: &SampleBuffer) {
+        self.0.push_samples(samples);
+        self.1.push_samples(samples);
+    }
+
+    fn set_speed(&mut self, speed: f32) {
+        self.0.set_speed(speed);
+        self.1.set_speed(speed);
+    }
+
+    fn flush(&mut self) {
+        self.0.flush();
+        self.1.flush();
+    }
+}
+
+/// Collects all samples in memory. Clones share the same buffer, so one clone can be given to the
+/// system while another reads the samples back.
+#[derive(Clone, Default)]
+pub struct MemoryOutput(Arc<Mutex<SampleBuffer>>);
+
+impl MemoryOutput {
+    /// Returns all samples collected so far, and empties the buffer.
+    pub fn take(&self) -> SampleBuffer {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl AudioOutput for MemoryOutput {
+    fn push_samples(&mut self, samples: &SampleBuffer) {
+        let mut buffer = self.0.lock().unwrap();
+        if buffer.frames.is_empty() {
+            buffer.start_cycle = samples.start_cycle;
+        }
+        buffer.frames.extend_from_slice(&samples.frames);
+    }
+}
+
+/// Writes the samples to a 16-bit stereo WAV file.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    rate: u32,
+    num_frames: u32,
+}
+
+impl WavWriter {
+    /// `rate` must be the APU's sample rate.
+    pub fn create(path: impl AsRef<Path>, rate: u32) -> io::Result<WavWriter> {
+        let mut writer =
+            WavWriter { file: BufWriter::new(File::create(path)?), rate, num_frames: 0 };
+        writer.write_header()?;
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        const CHANNELS: u16 = 2;
+        const BYTES_PER_SAMPLE: u16 = 2;
+        let data_size = self.num_frames * u32::from(CHANNELS * BYTES_PER_SAMPLE);
+        let file = &mut self.file;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(b"RIFF")?;
+        file.write_all(&(36 + data_size).to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        // PCM.
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&CHANNELS.to_le_bytes())?;
+        file.write_all(&self.rate.to_le_bytes())?;
+        file.write_all(&(self.rate * u32::from(CHANNELS * BYTES_PER_SAMPLE)).to_le_bytes())?;
+        file.write_all(&(CHANNELS * BYTES_PER_SAMPLE).to_le_bytes())?;
+        file.write_all(&(BYTES_PER_SAMPLE * 8).to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&data_size.to_le_bytes())?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    fn write_frame(&mut self, frame: [f32; 2]) -> io::Result<()> {
+        for &sample in &frame {
+            let sample = (sample.max(-1.0).min(1.0) * f32::from(std::i16::MAX)) as i16;
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.num_frames += 1;
+        Ok(())
+    }
+
+    fn try_push_samples(&mut self, samples: &SampleBuffer) -> io::Result<()> {
+        for &frame in &samples.frames {
+            self.write_frame(frame)?;
+        }
+        Ok(())
+    }
+
+    fn try_flush(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        self.file.flush()
+    }
+}
+
+impl AudioOutput for WavWriter {
+    fn push_samples(&mut self, samples: &SampleBuffer) {
+        if let Err(err) = self.try_push_samples(samples) {
+            eprintln!("Could not write audio: {}", err);
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(err) = self.try_flush() {
+            eprintln!("Could not write audio: {}", err);
+        }
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        AudioOutput::flush(self);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wav_writer() {
+        let path = std::env::temp_dir().join("rusty_boy_test_wav_writer.wav");
+        {
+            let mut writer = WavWriter::create(&path, 48_000).unwrap();
+            let frames = (0..10).map(|i| if i < 5 { [0.5, 0.0] } else { [-1.0, 1.0] }).collect();
+            writer.push_samples(&SampleBuffer { start_cycle: 0, frames });
+        }
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(bytes.len(), 44 + 10 * 4);
+        assert_eq!(&bytes[40..44], &40u32.to_le_bytes());
+        let sample = |i: usize| i16::from_le_bytes([bytes[44 + i * 2], bytes[45 + i * 2]]);
+        assert_eq!((sample(0), sample(1)), (16383, 0));
+        assert_eq!((sample(18), sample(19)), (-32767, 32767));
+    }
+}
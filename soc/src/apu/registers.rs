@@ -2,6 +2,7 @@ use bitfield::bitfield;
 use num_derive::FromPrimitive;
 
 #[derive(Debug, FromPrimitive)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum EnvelopeMode {
     Attenuate,
     Amplify,
@@ -9,28 +10,32 @@ pub enum EnvelopeMode {
 from_u8!(EnvelopeMode);
 
 bitfield! {
+    #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
     pub struct SquareConfig(u64);
     impl Debug;
     u8;
     pub sweep_shift, _: 2, 0;
     pub sweep_negate, _: 3;
     pub sweep_time, _: 6, 4;
-    pub length, set_length: 13, 8;
+    pub length, _: 13, 8;
     pub duty, _: 15, 14;
     pub envelope_counter, _: 18, 16;
+    /// The DAC is on if any of the volume and envelope mode bits are set.
+    pub dac_power, _: 23, 19;
     pub into EnvelopeMode, envelope_mode, _: 19, 19;
-    pub volume, set_volume: 23, 20;
+    pub volume, _: 23, 20;
     pub u16, freq, set_freq: 34, 24;
     pub is_timed, _: 38;
     pub triggered, set_triggered: 39;
 }
 
 bitfield! {
+    #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
     pub struct WaveConfig(u64);
     impl Debug;
     u8;
     pub enabled, _: 7;
-    pub u16, length, set_length: 15, 8;
+    pub u16, length, _: 15, 8;
     pub volume, _: 22, 21;
     pub u16, freq, _: 34, 24;
     pub is_timed, _: 38;
@@ -38,13 +43,15 @@ bitfield! {
 }
 
 bitfield! {
+    #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
     pub struct NoiseConfig(u64);
     impl Debug;
     u8;
-    pub length, set_length: 13, 8;
+    pub length, _: 13, 8;
     pub envelope_counter, _: 18, 16;
+    pub dac_power, _: 23, 19;
     pub into EnvelopeMode, envelope_mode, _: 19, 19;
-    pub volume, set_volume: 23, 20;
+    pub volume, _: 23, 20;
     pub divisor_code, _: 26, 24;
     pub width_mode, _: 27;
     pub shift, _: 31, 28;
@@ -56,10 +63,12 @@ bitfield! {
     pub struct CommonSoundConfig(u64);
     impl Debug;
     u8;
+    pub is_timed, _: 38;
     pub triggered, set_triggered: 39;
 }
 
 bitfield! {
+    #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
     pub struct VolumeControl(u8);
     impl Debug;
     pub right, _: 2, 0;
@@ -67,6 +76,7 @@ bitfield! {
 }
 
 bitfield! {
+    #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
     pub struct ChannelMixConfig(u8);
     impl Debug;
     pub r_square_1, _: 0;
@@ -80,6 +90,7 @@ bitfield! {
 }
 
 bitfield! {
+    #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
     pub struct SoundStatus(u8);
     pub square_1, _: 0;
     pub square_2, _: 1;
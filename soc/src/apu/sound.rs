@@ -1,6 +1,4 @@
-use arrayvec::ArrayVec;
 use bitflags::bitflags;
-use std::iter::Cycle;
 
 use crate::apu::registers::{EnvelopeMode, NoiseConfig, SquareConfig, WaveConfig};
 use crate::util::{timer, Timer};
@@ -12,23 +10,30 @@ const WAVE_DUTIES: [[u8; 8]; 4] = [
     [0, 1, 1, 1, 1, 1, 1, 0],
 ];
 
-fn packed_wave_to_array(packed: u128) -> [u8; 32] {
-    let mut array = [0; 32];
-    for i in 0..32 {
-        let byte = i / 2;
-        let nibble = (i + 1) % 2;
-        let value = (super::get_byte(packed, byte) >> (nibble * 4)) & 0xF;
-        array[i as usize] = value as u8;
-    }
-    array
-}
-
 bitflags! {
+    /// The components the frame sequencer clocks on a given T-cycle.
     pub struct ComponentCycle: i32 {
+        const SWEEP     = 0b0001;
+        const ENVELOPE  = 0b0010;
         const LENGTH    = 0b0100;
     }
 }
 
+/// Sound timers always count down to 0, so the remaining length is all there is to save.
+#[cfg(feature = "serialize")]
+mod serde_timer {
+    use crate::util::{timer, Timer};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(timer: &Timer, serializer: S) -> Result<S::Ok, S::Error> {
+        (timer.len() as i32).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Timer, D::Error> {
+        Ok(timer(i32::deserialize(deserializer)?))
+    }
+}
+
 /// The trait that all sounds (square, wave, and noise) implement. Allows for a generic way of
 /// handling updates and sampling.
 pub trait Sound {
@@ -38,75 +43,133 @@ pub trait Sound {
     fn is_done(&self) -> bool;
 }
 
+/// Periods of 0 are treated as 8 by the envelope and sweep timers.
+fn timer_period(period: u8) -> u8 {
+    if period == 0 {
+        8
+    } else {
+        period
+    }
+}
+
+/// Volume envelope. The mode and period are latched when the sound is triggered.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct Envelope {
     pub mode: EnvelopeMode,
-    pub timer: Cycle<Timer>,
+    pub period: u8,
+    pub timer: u8,
 }
 
 impl Envelope {
-    pub fn new(mode: EnvelopeMode, time: i32) -> Envelope {
-        Envelope { mode, timer: timer(time * super::ENVELOPE_PERIOD).cycle() }
+    pub fn new(mode: EnvelopeMode, period: u8) -> Envelope {
+        Envelope { mode, period, timer: timer_period(period) }
     }
 
+    /// Clocked by the frame sequencer. Returns the new volume.
     pub fn clock(&mut self, volume: u8) -> u8 {
-        if let Some(0) = self.timer.next() {
-            match self.mode {
-                EnvelopeMode::Attenuate => volume.saturating_sub(1),
-                EnvelopeMode::Amplify => (volume + 1).min(15),
-            }
-        } else {
-            volume
+        if self.period == 0 {
+            return volume;
+        }
+        self.timer -= 1;
+        if self.timer > 0 {
+            return volume;
+        }
+        self.timer = timer_period(self.period);
+        match self.mode {
+            EnvelopeMode::Attenuate => volume.saturating_sub(1),
+            EnvelopeMode::Amplify => (volume + 1).min(15),
         }
     }
 }
 
+/// Frequency sweep of the first square channel. Works on a shadow copy of the frequency taken when
+/// the sound is triggered. The period, shift and direction are read from NR10 whenever they are
+/// used.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct Sweep {
-    pub shift: i32,
-    pub negate: bool,
-    pub timer: Cycle<Timer>,
+    shadow_freq: u16,
+    timer: u8,
+    is_enabled: bool,
+    /// Whether a calculation in negate mode happened since the trigger. Clearing the negate bit
+    /// afterwards turns the channel off.
+    pub negate_used: bool,
 }
 
 impl Sweep {
-    pub fn from_config(config: SquareConfig) -> Option<Sweep> {
-        if config.sweep_time() > 0 && config.sweep_shift() > 0 {
-            Some(Sweep {
-                shift: config.sweep_shift().into(),
-                negate: config.sweep_negate(),
-                timer: timer(super::SWEEP_PERIOD * i32::from(config.sweep_time())).cycle(),
-            })
-        } else {
+    /// Starts the sweep when the sound is triggered. Returns None if the initial overflow check
+    /// fails, in which case the channel must be turned off.
+    pub fn new(config: SquareConfig) -> Option<Sweep> {
+        let mut sweep = Sweep {
+            shadow_freq: config.freq(),
+            timer: timer_period(config.sweep_time()),
+            is_enabled: config.sweep_time() > 0 || config.sweep_shift() > 0,
+            negate_used: false,
+        };
+        if config.sweep_shift() > 0 && sweep.calculate(config) > 2047 {
             None
+        } else {
+            Some(sweep)
         }
     }
 
-    pub fn update(&mut self, frequency: u16) -> Option<u16> {
-        if let Some(0) = self.timer.next() {
-            let change = frequency >> self.shift;
-            Some(if self.negate { frequency.wrapping_sub(change) } else { frequency + change })
+    fn calculate(&mut self, config: SquareConfig) -> u16 {
+        let change = self.shadow_freq >> config.sweep_shift();
+        if config.sweep_negate() {
+            self.negate_used = true;
+            self.shadow_freq - change
         } else {
-            None
+            self.shadow_freq + change
         }
     }
+
+    /// Clocked by the frame sequencer. Returns the new frequency if it changed. A frequency above
+    /// 2047 means the sweep overflowed, and the channel must be turned off.
+    pub fn clock(&mut self, config: SquareConfig) -> Option<u16> {
+        self.timer -= 1;
+        if self.timer > 0 {
+            return None;
+        }
+        self.timer = timer_period(config.sweep_time());
+        if !self.is_enabled || config.sweep_time() == 0 {
+            return None;
+        }
+        let freq = self.calculate(config);
+        if freq > 2047 || config.sweep_shift() == 0 {
+            return Some(freq).filter(|&x| x > 2047);
+        }
+        self.shadow_freq = freq;
+        // The new frequency is checked for overflow once more, but not used.
+        if self.calculate(config) > 2047 {
+            return Some(2048);
+        }
+        Some(freq)
+    }
 }
 
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct Square {
     config: SquareConfig,
     waveform_index: u8,
+    /// The current volume. NRx2 keeps reading back the initial volume.
+    volume: u8,
     envelope: Envelope,
     sweep: Option<Sweep>,
+    #[cfg_attr(feature = "serialize", serde(with = "serde_timer"))]
     freq_timer: Timer,
     is_done: bool,
 }
 
 impl Square {
     pub fn new(config: SquareConfig) -> Square {
+        let sweep = Sweep::new(config);
         Square {
             config,
             waveform_index: 0,
-            envelope: Envelope::new(config.envelope_mode(), config.envelope_counter().into()),
-            sweep: Sweep::from_config(config),
+            volume: config.volume(),
+            envelope: Envelope::new(config.envelope_mode(), config.envelope_counter()),
+            is_done: sweep.is_none(),
+            sweep,
             freq_timer: Square::make_freq_timer(config.freq()),
-            is_done: false,
         }
     }
 
@@ -117,8 +180,12 @@ impl Square {
 
 impl Sound for Square {
     fn update_from_reg(&mut self, reg: u64) {
-        let reset_timer = self.config.freq() != SquareConfig(reg).freq();
-        self.config.0 = reg;
+        let reg = SquareConfig(reg);
+        let reset_timer = self.config.freq() != reg.freq();
+        if self.sweep.as_ref().map_or(false, |x| x.negate_used) && !reg.sweep_negate() {
+            self.is_done = true;
+        }
+        self.config.0 = reg.0;
         if reset_timer {
             self.freq_timer = Square::make_freq_timer(self.config.freq());
         }
@@ -136,58 +203,104 @@ impl Sound for Square {
         debug_assert!(!self.is_done);
         let sample = WAVE_DUTIES[usize::from(self.config.duty())][usize::from(self.waveform_index)]
             as f32
-            * self.config.volume() as f32;
+            * self.volume as f32;
         // Update waveform.
         if self.freq_timer.next().unwrap() == 0 {
             self.waveform_index = (self.waveform_index + 1) % 8;
             self.freq_timer = Square::make_freq_timer(self.config.freq());
         }
         // Update the envelope (volume).
-        self.config.set_volume(self.envelope.clock(self.config.volume()));
+        if cycles.contains(ComponentCycle::ENVELOPE) {
+            self.volume = self.envelope.clock(self.volume);
+        }
         // Update the sweep (frequency).
-        if let Some(sweep) = &mut self.sweep {
-            if let Some(freq) = sweep.update(self.config.freq()) {
-                self.config.set_freq(freq);
+        if cycles.contains(ComponentCycle::SWEEP) {
+            let config = self.config;
+            if let Some(freq) = self.sweep.as_mut().and_then(|x| x.clock(config)) {
                 if freq <= 2047 {
+                    self.config.set_freq(freq);
                     self.freq_timer = Square::make_freq_timer(freq);
                 } else {
                     self.is_done = true;
                 }
             }
         }
-        // Length.
-        if cycles.contains(ComponentCycle::LENGTH) {
-            let new_len = self.config.length() + 1;
-            if new_len >= 64 && self.config.is_timed() {
-                self.is_done = true;
-            }
-            self.config.set_length(new_len);
-        }
         sample as f32 / 15.0
     }
 }
 
+/// On trigger, the first step is delayed by 6 T-cycles.
+const WAVE_TRIGGER_DELAY: i32 = 6;
+
+/// The wave channel. Plays wave RAM one nibble at a time, high nibble first, reading each byte into
+/// a sample buffer as it gets to it. Wave RAM is read live, so it can be streamed while playing.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct Wave {
     config: WaveConfig,
-    waveform: ArrayVec<[u8; 32]>,
-    waveform_index: u8,
+    /// The nibble of wave RAM being played.
+    position: u8,
+    /// The last byte read from wave RAM. Survives retriggering.
+    pub sample_buffer: u8,
+    #[cfg_attr(feature = "serialize", serde(with = "serde_timer"))]
     freq_timer: Timer,
+    /// T-cycles since wave RAM was last read.
+    cycles_since_read: u32,
     is_done: bool,
 }
 
 impl Wave {
-    pub fn new(config: WaveConfig, packed_wave_table: u128) -> Wave {
+    pub fn new(config: WaveConfig, sample_buffer: u8) -> Wave {
         Wave {
             config,
-            waveform: ArrayVec::from(packed_wave_to_array(packed_wave_table)),
-            waveform_index: 0,
-            freq_timer: Wave::make_freq_timer(config.freq()),
+            position: 0,
+            sample_buffer,
+            freq_timer: timer(Wave::period(config.freq()) + WAVE_TRIGGER_DELAY),
+            cycles_since_read: u32::max_value(),
             is_done: false,
         }
     }
 
+    fn period(freq: u16) -> i32 {
+        (2048 - i32::from(freq)) * 2
+    }
+
     fn make_freq_timer(freq: u16) -> Timer {
-        timer((2048 - i32::from(freq)) * 2)
+        timer(Wave::period(freq))
+    }
+
+    /// Must be called after every sample. Reads wave RAM if the channel stepped to a new nibble.
+    pub fn read_ram(&mut self, wave_table: u128) {
+        if self.cycles_since_read == 0 {
+            self.sample_buffer = super::get_byte(wave_table, i32::from(self.position / 2)) as u8;
+        }
+    }
+
+    /// The byte of wave RAM the CPU gets to access while the channel plays. On DMG, that only
+    /// works within 2 T-cycles of the channel reading it. Otherwise, reads return 0xFF and writes
+    /// are ignored.
+    pub fn cpu_accessible_byte(&self) -> Option<i32> {
+        if self.cycles_since_read < 2 {
+            Some(i32::from(self.position / 2))
+        } else {
+            None
+        }
+    }
+
+    /// Retriggering on DMG while the channel is about to read wave RAM corrupts the first bytes:
+    /// if the byte about to be read is one of the first four, it is copied over the first byte.
+    /// Otherwise, the four-byte block it belongs to is copied over the first four bytes.
+    pub fn corrupt_on_retrigger(&self, wave_table: &mut u128) {
+        if self.freq_timer.len() != 1 {
+            return;
+        }
+        let next = i32::from((self.position + 1) % 32 / 2);
+        if next < 4 {
+            super::set_byte(wave_table, 0, super::get_byte(*wave_table, next));
+        } else {
+            for i in 0..4 {
+                super::set_byte(wave_table, i, super::get_byte(*wave_table, (next & !3) + i));
+            }
+        }
     }
 }
 
@@ -208,36 +321,35 @@ impl Sound for Wave {
         self.is_done
     }
 
-    fn sample(&mut self, cycles: ComponentCycle) -> f32 {
+    fn sample(&mut self, _cycles: ComponentCycle) -> f32 {
         debug_assert!(!self.is_done);
         let volume = if !self.config.enabled() || self.config.volume() == 0 {
             0.0
         } else {
             1.0 / self.config.volume() as f32
         };
-        let sample = self.waveform[usize::from(self.waveform_index)] as f32 * volume;
-        // Update waveform.
+        let nibble =
+            if self.position % 2 == 0 { self.sample_buffer >> 4 } else { self.sample_buffer & 0xF };
+        let sample = nibble as f32 * volume;
+        self.cycles_since_read = self.cycles_since_read.saturating_add(1);
+        // Step to the next nibble.
         if self.freq_timer.next().unwrap() == 0 {
-            self.waveform_index = (self.waveform_index + 1) % 32;
+            self.position = (self.position + 1) % 32;
+            self.cycles_since_read = 0;
             self.freq_timer = Wave::make_freq_timer(self.config.freq());
         }
-        // Length.
-        if cycles.contains(ComponentCycle::LENGTH) {
-            let new_len = self.config.length() + 1;
-            if new_len >= 256 && self.config.is_timed() {
-                self.is_done = true;
-            }
-            self.config.set_length(new_len);
-        }
         sample / 15.0
     }
 }
 
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct Noise {
     config: NoiseConfig,
+    volume: u8,
     envelope: Envelope,
     lfsr: u16,
-    timer: Cycle<Timer>,
+    #[cfg_attr(feature = "serialize", serde(with = "serde_timer"))]
+    freq_timer: Timer,
     is_done: bool,
 }
 
@@ -245,20 +357,22 @@ impl Noise {
     pub fn new(config: NoiseConfig) -> Noise {
         Noise {
             config,
-            envelope: Envelope::new(config.envelope_mode(), config.envelope_counter().into()),
+            volume: config.volume(),
+            envelope: Envelope::new(config.envelope_mode(), config.envelope_counter()),
             lfsr: 0x7FFF,
-            timer: Noise::make_freq_timer(config.divisor_code(), config.shift()),
+            freq_timer: Noise::make_freq_timer(config),
             is_done: false,
         }
     }
 
-    fn make_freq_timer(divisor_code: u8, shift: u8) -> Cycle<Timer> {
-        let mantissa = 2 * (divisor_code as i32 + 1);
-        timer((mantissa << i32::from(shift)) * super::NOISE_PERIOD).cycle()
+    fn make_freq_timer(config: NoiseConfig) -> Timer {
+        let mantissa = 2 * (config.divisor_code() as i32 + 1);
+        timer((mantissa << i32::from(config.shift())) * super::NOISE_PERIOD)
     }
 
     fn clock(&mut self) {
-        if let Some(0) = self.timer.next() {
+        if self.freq_timer.next().unwrap() == 0 {
+            self.freq_timer = Noise::make_freq_timer(self.config);
             let mut lfsr = self.lfsr;
             // XOR the low two bits.
             let new_bit = (lfsr & 1) ^ ((lfsr >> 1) & 1);
@@ -281,7 +395,7 @@ impl Sound for Noise {
             self.config.divisor_code() != reg.divisor_code() || self.config.shift() != reg.shift();
         self.config.0 = reg.0;
         if reset_timer {
-            self.timer = Noise::make_freq_timer(self.config.divisor_code(), self.config.shift());
+            self.freq_timer = Noise::make_freq_timer(self.config);
         }
     }
 
@@ -295,15 +409,10 @@ impl Sound for Noise {
 
     fn sample(&mut self, cycles: ComponentCycle) -> f32 {
         debug_assert!(!self.is_done);
-        let sample = (!self.lfsr & 1) as u8 * self.config.volume();
+        let sample = (!self.lfsr & 1) as u8 * self.volume;
         self.clock();
-        self.config.set_volume(self.envelope.clock(self.config.volume()));
-        if cycles.contains(ComponentCycle::LENGTH) {
-            let new_len = self.config.length() + 1;
-            if new_len >= 64 && self.config.is_timed() {
-                self.is_done = true;
-            }
-            self.config.set_length(new_len);
+        if cycles.contains(ComponentCycle::ENVELOPE) {
+            self.volume = self.envelope.clock(self.volume);
         }
         sample as f32 / 15.0
     }
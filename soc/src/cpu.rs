@@ -26,6 +26,16 @@ impl Default for DecodeMode {
     }
 }
 
+/// What the CPU did with the address latch during the current M-cycle.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct AddressUse {
+    pub read: bool,
+    pub write: bool,
+    /// The incrementer (IDU) incremented or decremented it.
+    pub idu: bool,
+}
+
 #[derive(Copy, Clone, Debug, Default)]
 #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct State {
@@ -35,9 +45,14 @@ pub struct State {
     pub data_latch: i32,
     pub read_latch: bool,
     pub write_latch: bool,
+    /// The OAM corruption bug depends on it.
+    pub address_use: AddressUse,
 
     interrupt_enable_counter: i32,
     exit_halt: bool,
+    /// Set when HALT is executed with IME=0 while an interrupt is already pending. The next opcode
+    /// fetch will then fail to increment PC.
+    halt_bug: bool,
 }
 
 #[derive(Copy, Clone, Debug, Default)]
@@ -54,8 +69,6 @@ impl TState {
     }
 }
 
-serialize_as!(deque_serialize, MicroCodeList, Vec<MicroCode>);
-
 #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 // This needs to get heavily refactored, with the control unit
 // code being migrated here, and state made private.
@@ -64,7 +77,6 @@ pub struct Cpu {
     pub registers: register::File,
     #[cfg_attr(feature = "serialize", serde(skip))]
     pub decoder: decoder::Decoder,
-    #[cfg_attr(feature = "serialize", serde(with = "deque_serialize"))]
     pub micro_code_stack: MicroCodeList,
 
     pub t_state: TState,
@@ -98,6 +110,7 @@ impl Cpu {
     pub fn execute_t_cycle(&mut self, memory: &mut Memory, hack: bool) -> Result<()> {
         // First step is to handle interrupts. Save off the is_halted flag since the next function
         // can unset it.
+        let was_halted = self.is_halted;
         self.handle_interrupts_or_unhalt(memory, hack)?;
         let (next_state, is_done) = control_unit::cycle(self);
         self.state = next_state;
@@ -112,6 +125,16 @@ impl Cpu {
             self.is_halted = false;
             self.state.exit_halt = false;
         }
+        // The HALT bug: if HALT is executed with IME=0 while an interrupt is already pending, the
+        // CPU doesn't halt at all. Instead, PC fails to increment on the next opcode fetch, which
+        // causes the byte after HALT to be read twice.
+        if is_done && self.is_halted && !was_halted && !self.interrupts_enabled {
+            let ie_flag = memory.read(io_registers::Addresses::InterruptEnable);
+            if (self.interrupt_fired_flag(memory)? & ie_flag) != 0 {
+                self.is_halted = false;
+                self.state.halt_bug = true;
+            }
+        }
         Ok(())
     }
 
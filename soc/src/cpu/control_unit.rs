@@ -47,6 +47,10 @@ pub fn cycle(cpu: &mut Cpu) -> (cpu::State, bool) {
                     debug_assert!(cpu.micro_code_stack.is_empty());
                     cpu.registers.set(Register::INSTR, opcode);
                     cpu.micro_code_stack = cpu.decoder.decode(opcode, cpu.state.in_cb_mode);
+                    if cpu.state.halt_bug {
+                        halt_bug_logic(cpu.micro_code_stack.last_mut().unwrap());
+                        cpu.state.halt_bug = false;
+                    }
                 }
                 (cpu.micro_code_stack.pop().unwrap(), DecodeMode::Execute)
             }
@@ -83,6 +87,16 @@ pub fn cycle(cpu: &mut Cpu) -> (cpu::State, bool) {
     (next_state, is_end)
 }
 
+/// Emulates the HALT bug. The first micro-code of every instruction increments PC past the opcode
+/// that was just fetched. Turning that increment into a move keeps PC pointing at the same byte, so
+/// it gets fetched (and executed) again.
+fn halt_bug_logic(code: &mut MicroCode) {
+    if code.inc_to_addr_bus && code.addr_write_enable && code.addr_select == Register::PC {
+        debug_assert!(code.inc_op == IncOp::Inc);
+        code.inc_op = IncOp::Mov;
+    }
+}
+
 /// Incrementer module.
 
 fn incrementer_logic(code: &MicroCode, address_latch: i32) -> i32 {
@@ -195,6 +209,13 @@ fn execute(code: &MicroCode, cpu: &mut Cpu) -> cpu::State {
         next_state.write_latch = true;
     }
 
+    if cpu.t_state.get() == 1 {
+        next_state.address_use = cpu::AddressUse::default();
+    }
+    next_state.address_use.read |= code.mem_read_enable;
+    next_state.address_use.write |= code.mem_write_enable;
+    next_state.address_use.idu |= code.inc_to_addr_bus && code.inc_op != IncOp::Mov;
+
     if code.reg_to_addr_buffer {
         debug_assert!(!code.inc_to_addr_bus);
         debug_assert!(!code.addr_write_enable);
@@ -1,5 +1,9 @@
 #![warn(clippy::all)]
 
+#[cfg(feature = "serialize")]
+#[macro_use]
+extern crate serde;
+
 pub mod micro_code;
 pub mod register;
 
@@ -184,12 +184,12 @@ impl DecoderBuilder {
                     },
                 },
                 // z = 3. INC/DEC rr
-                3 if op_q == 0 => self.pla["INC/DECrr"]
-                    .remap_alu_placeholder(AluOp::Add)
-                    .remap_lhs_reg(Register::from_sp_pair_table(op_p)),
-                3 if op_q == 1 => self.pla["INC/DECrr"]
-                    .remap_alu_placeholder(AluOp::Sub)
-                    .remap_lhs_reg(Register::from_sp_pair_table(op_p)),
+                3 if op_q == 0 => {
+                    self.pla["INCrr"].remap_lhs_reg(Register::from_sp_pair_table(op_p))
+                }
+                3 if op_q == 1 => {
+                    self.pla["DECrr"].remap_lhs_reg(Register::from_sp_pair_table(op_p))
+                }
                 // z = 4. INC n
                 4 => {
                     if op_y == 6 {
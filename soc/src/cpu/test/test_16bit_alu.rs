@@ -53,30 +53,62 @@ fn test_add_sp_i8() {
 
 #[test]
 fn test_inc_rr() {
+    // INC rr goes through the incrementer, which leaves all flags alone.
     for (&op, &reg) in [0x03, 0x13, 0x23, 0x33].iter().zip(SP_PAIRS.iter()) {
-        with_default()
-            .set_reg(reg, 0xFFFF)
-            .set_flag(Flags::ZERO | Flags::SUB, true)
-            .execute_instructions(&[op])
-            .assert_reg_eq(reg, 0)
-            .assert_flags(Flags::ZERO | Flags::SUB)
-            .assert_mcycles(2);
+        for &(value, result) in
+            [(0xFFFF, 0), (0x00FF, 0x0100), (0x0FFF, 0x1000), (0x1234, 0x1235)].iter()
+        {
+            for &flags in [Flags::empty(), Flags::all()].iter() {
+                with_default()
+                    .set_reg(reg, value)
+                    .set_flag(flags, true)
+                    .execute_instructions(&[op])
+                    .assert_reg_eq(reg, result)
+                    .assert_flags(flags)
+                    .assert_mcycles(2);
+            }
+        }
     }
 }
 
 #[test]
 fn test_dec_rr() {
     for (&op, &reg) in [0x0B, 0x1B, 0x2B, 0x3B].iter().zip(SP_PAIRS.iter()) {
-        with_default()
-            .set_reg(reg, 0)
-            .set_flag(Flags::ZERO, true)
-            .execute_instructions(&[op])
-            .assert_reg_eq(reg, 0xFFFF)
-            .assert_flags(Flags::ZERO)
-            .assert_mcycles(2);
+        for &(value, result) in
+            [(0, 0xFFFF), (0x0100, 0x00FF), (0x1000, 0x0FFF), (0x1235, 0x1234)].iter()
+        {
+            for &flags in [Flags::empty(), Flags::all()].iter() {
+                with_default()
+                    .set_reg(reg, value)
+                    .set_flag(flags, true)
+                    .execute_instructions(&[op])
+                    .assert_reg_eq(reg, result)
+                    .assert_flags(flags)
+                    .assert_mcycles(2);
+            }
+        }
     }
 }
 
+#[test]
+fn test_inc_dec_hl_then_load() {
+    // The incremented address is ready for the next instruction, without extra cycles.
+    with_default()
+        .set_mem_8bit(0xD001, 0x12)
+        .set_reg(HL, 0xD000)
+        .execute_instructions(&[0x23, LD_A_HL])
+        .assert_reg_eq(A, 0x12)
+        .assert_mem_8bit_eq(0xD000, 0)
+        .assert_mcycles(4);
+    with_default()
+        .set_mem_8bit(0xD000, 0x34)
+        .set_reg(HL, 0xD001)
+        .execute_instructions(&[0x2B, LD_A_HL])
+        .assert_reg_eq(A, 0x34)
+        .assert_mem_8bit_eq(0xD001, 0)
+        .assert_mcycles(4);
+}
+
 #[test]
 fn test_ld_hl_sp_i8() {
     for (sp, offset, result, flags) in [
@@ -0,0 +1,73 @@
+//! CPU accesses while OAM DMA runs, see `mmu::Conflict`.
+
+use super::*;
+use crate::cpu::register::Register::*;
+use crate::io_registers::Addresses;
+
+/// VRAM holds 0x40 plus the index of each byte, and DMA copies it to OAM.
+fn with_vram_dma() -> TestContext {
+    let vram: Vec<u8> = (0..160).map(|x| 0x40 + x as u8).collect();
+    with_default().set_mem_range(0x8000, &vram).set_mem_8bit(Addresses::Dma as i32, 0x80)
+}
+
+#[test]
+fn test_dma_source_bus_reads_transferred_byte() {
+    // The fetch moved the first byte, the read sees the second one whatever HL points to.
+    with_vram_dma().set_reg(HL, 0x9000).execute_instructions(&[LD_A_HL]).assert_reg_eq(A, 0x41);
+}
+
+#[test]
+fn test_dma_other_buses() {
+    // OAM is blocked.
+    with_vram_dma().set_reg(HL, 0xFE00).execute_instructions(&[LD_A_HL]).assert_reg_eq(A, 0xFF);
+    // The internal RAM is on the external bus, which DMA does not use here.
+    with_vram_dma()
+        .set_mem_8bit(0xD000, 0x12)
+        .set_reg(HL, 0xD000)
+        .execute_instructions(&[LD_A_HL])
+        .assert_reg_eq(A, 0x12);
+    // HRAM is always accessible.
+    with_vram_dma()
+        .set_mem_8bit(0xFF80, 0x34)
+        .execute_instructions(&[LD_A_FF, 0x80])
+        .assert_reg_eq(A, 0x34);
+}
+
+#[test]
+fn test_dma_drops_conflicting_writes() {
+    with_vram_dma()
+        .set_reg(A, 0x12)
+        .set_reg(HL, 0x9000)
+        .execute_instructions(&[0x77])
+        .assert_mem_8bit_eq(0x9000, 0);
+}
+
+/// Runs `instructions` from HRAM while DMA copies the start of the cartridge ROM to OAM. The
+/// test context runs code from internal RAM, whose fetches would conflict with the transfer.
+fn execute_during_rom_dma(mut context: TestContext, instructions: &[u8]) -> TestContext {
+    context = context.set_mem_range(0xFF80, instructions).set_mem_8bit(Addresses::Dma as i32, 0x00);
+    context.system.cpu_mut().registers.set(PC, 0xFF80);
+    let end = 0xFF80 + instructions.len() as i32;
+    while context.system.cpu_mut().registers.get(PC) != end || !context.system.is_fetching() {
+        context.system.execute_machine_cycle().unwrap();
+    }
+    context
+}
+
+#[test]
+fn test_rom_dma_leaves_vram_writes() {
+    execute_during_rom_dma(with_default().set_reg(A, 0x12).set_reg(HL, 0x9000), &[0x77])
+        .assert_mem_8bit_eq(0x9000, 0x12);
+}
+
+#[test]
+fn test_rom_dma_drops_external_bus_writes() {
+    execute_during_rom_dma(with_default().set_reg(A, 0x12).set_reg(HL, 0xD000), &[0x77])
+        .assert_mem_8bit_eq(0xD000, 0);
+}
+
+#[test]
+fn test_rom_dma_leaves_gpu_register_writes() {
+    execute_during_rom_dma(with_default().set_reg(A, 0x12), &[LD_FF_A, 0x45, LD_A_FF, 0x45])
+        .assert_reg_eq(A, 0x12);
+}
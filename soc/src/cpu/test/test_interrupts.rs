@@ -156,3 +156,76 @@ fn test_halt_with_ints_enabled() {
         .execute_instructions_for_mcycles(&ops, 256 * (16 / 4))
         .assert_reg_eq(A, 1);
 }
+
+/// HALT with IME=0 and an already pending interrupt triggers the HALT bug: the CPU doesn't halt,
+/// and the byte after HALT is read twice.
+#[test]
+fn test_halt_bug() {
+    #[rustfmt::skip]
+    let ops = [
+        HALT,
+        INC_A,
+    ];
+    with_default()
+        .set_mem_8bit(0xFFFF, Interrupts::TIMER.bits())
+        .set_mem_8bit(0xFF0F, Interrupts::TIMER.bits())
+        .set_reg(A, 0)
+        .execute_instructions(&ops)
+        .assert_reg_eq(A, 2)
+        .assert_mcycles(3);
+}
+
+/// The repeated byte is re-decoded as an opcode, so the operand of a multi-byte instruction that
+/// follows HALT ends up being the instruction's own opcode.
+#[test]
+fn test_halt_bug_with_operand() {
+    #[rustfmt::skip]
+    let ops = [
+        HALT,
+        LD_A_IMM, INC_A,
+    ];
+    with_default()
+        .set_mem_8bit(0xFFFF, Interrupts::TIMER.bits())
+        .set_mem_8bit(0xFF0F, Interrupts::TIMER.bits())
+        .set_reg(A, 0)
+        .execute_instructions(&ops)
+        .assert_reg_eq(A, LD_A_IMM as i32 + 1);
+}
+
+/// No interrupt is pending, so HALT halts as usual until the timer fires, even with IME=0.
+#[test]
+fn test_halt_with_ints_disabled() {
+    #[rustfmt::skip]
+    let ops = [
+        HALT,
+        INC_A,
+    ];
+    with_default()
+        .set_mem_8bit(0xFFFF, Interrupts::TIMER.bits())
+        .setup_timer(timer::TimerFrequency::Every16)
+        .set_reg(A, 0)
+        .execute_instructions_for_mcycles(&ops, 256 * (16 / 4) + 1)
+        .assert_reg_eq(A, 1);
+}
+
+/// A game waiting for input with HALT should wake up when a key in the selected group is pressed.
+#[test]
+fn test_halt_wakes_on_joypad() {
+    use crate::joypad::{Key, KeyEvent};
+    #[rustfmt::skip]
+    let ops = [
+        // Select the button keys.
+        LD_A_IMM, 0x10,
+        LD_FF_A, 0x00,
+        LD_A_IMM, 0,
+        EI,
+        HALT,
+        INC_A,
+    ];
+    let mut context = with_default()
+        .set_mem_range(0x40, &INTERRUPT_HANDLERS)
+        .set_mem_8bit(0xFFFF, Interrupts::JOYPAD.bits())
+        .set_reg(SP, 0xFFFF);
+    context.system.joypad_mut().queue_event(KeyEvent { cycle: 200, key: Key::A, pressed: true });
+    context.execute_instructions(&ops).assert_reg_eq(A, interrupt_handler_result(4) + 1);
+}
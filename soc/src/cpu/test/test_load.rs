@@ -125,3 +125,18 @@ fn test_ld_assorted() {
         .assert_reg_eq(SP, 0xBEEF)
         .assert_mcycles(2);
 }
+
+#[test]
+fn test_ld_unmapped() {
+    // Unused OAM reads as 0 while the LCD is off, writes are dropped.
+    with_default()
+        .set_reg(A, 0x12)
+        .set_reg(HL, 0xFEA0)
+        .execute_instructions(&[0x77, LD_A_HL])
+        .assert_reg_eq(A, 0x00);
+    // Unknown I/O registers read as 0xFF.
+    with_default()
+        .set_reg(A, 0x12)
+        .execute_instructions(&[LD_FF_A, 0x4C, LD_A_FF, 0x4C])
+        .assert_reg_eq(A, 0xFF);
+}
@@ -25,12 +25,22 @@ impl Dma {
         self.byte_index > 0 && self.byte_index <= 160
     }
 
+    /// Where the byte being transferred is read from, while DMA is active. On DMG, sources from
+    /// 0xE000 on read the internal RAM, like echo RAM does.
+    pub fn source_address(&self) -> Option<i32> {
+        if !self.is_active() {
+            return None;
+        }
+        let address = (self.control.0 << 8) + 160 - self.byte_index;
+        Some(if address >= 0xE000 { address - 0x2000 } else { address })
+    }
+
     pub fn execute_tcycle(&mut self, bus: &mmu::MemoryBus) -> Option<DmaRequest> {
         let mut dma_request = None;
         if bus.t_state == 4 && self.byte_index > 0 {
-            if self.byte_index <= 160 {
+            if let Some(source_address) = self.source_address() {
                 dma_request = Some(DmaRequest {
-                    source_address: (self.control.0 << 8) + 160 - self.byte_index,
+                    source_address,
                     destination_address: 0xFE00 + 160 - self.byte_index,
                 });
             }
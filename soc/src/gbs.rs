@@ -0,0 +1,311 @@
+use crate::error::{self, Result};
+use crate::mmu;
+use crate::system::System;
+
+/// GBS (Game Boy Sound) music rips. A GBS file holds a game's sound engine and music data, along
+/// with the addresses of its init and play routines. The rip is loaded into a synthetic cart
+/// together with a tiny driver, which calls init once with the song number, and then calls play on
+/// every vblank or timer interrupt.
+///
+/// Header (all integers are little-endian):
+///   magic:            "GBS"
+///   version:          u8 (1)
+///   num_songs:        u8
+///   first_song:       u8, 1-based
+///   load_address:     u16, where the data following the header is loaded
+///   init_address:     u16, called with the 0-based song number in A
+///   play_address:     u16
+///   stack_pointer:    u16
+///   timer_modulo:     u8, TMA
+///   timer_control:    u8, TAC. If bit 2 is set, play is called on timer interrupts instead of
+///                     vblank.
+///   title:            32 bytes
+///   author:           32 bytes
+///   copyright:        32 bytes
+const MAGIC: &[u8; 3] = b"GBS";
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 0x70;
+
+/// The driver lives below this address, so the rip must be loaded after it.
+const MIN_LOAD_ADDRESS: u16 = 0x400;
+const DRIVER_ADDRESS: usize = 0x100;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gbs {
+    pub num_songs: u8,
+    /// The song to play by default, 0-based.
+    pub first_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+    data: Vec<u8>,
+}
+
+impl Gbs {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Gbs> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(bad_gbs("Unexpected end of file"));
+        }
+        if &bytes[..3] != MAGIC {
+            return Err(bad_gbs("Not a GBS file"));
+        }
+        if bytes[3] != VERSION {
+            return Err(bad_gbs(&format!("Unsupported version {}", bytes[3])));
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let string_at = |i: usize| {
+            let field = &bytes[i..i + 32];
+            let len = field.iter().position(|&x| x == 0).unwrap_or(field.len());
+            String::from_utf8_lossy(&field[..len]).into_owned()
+        };
+        let gbs = Gbs {
+            num_songs: bytes[4],
+            first_song: bytes[5].saturating_sub(1),
+            load_address: u16_at(6),
+            init_address: u16_at(8),
+            play_address: u16_at(0xA),
+            stack_pointer: u16_at(0xC),
+            timer_modulo: bytes[0xE],
+            timer_control: bytes[0xF],
+            title: string_at(0x10),
+            author: string_at(0x30),
+            copyright: string_at(0x50),
+            data: bytes[HEADER_SIZE..].to_vec(),
+        };
+        if gbs.num_songs == 0 {
+            return Err(bad_gbs("No songs"));
+        }
+        if gbs.load_address < MIN_LOAD_ADDRESS || gbs.load_address >= 0x8000 {
+            return Err(bad_gbs(&format!("Unsupported load address {:#06X}", gbs.load_address)));
+        }
+        Ok(gbs)
+    }
+
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Gbs> {
+        let bytes = std::fs::read(path).map_err(|err| {
+            error::Type::InvalidOperation(format!("Could not read GBS file: {}", err))
+        })?;
+        Gbs::from_bytes(&bytes)
+    }
+
+    /// Whether play is called on timer interrupts, rather than on vblank.
+    pub fn uses_timer(&self) -> bool {
+        self.timer_control & 0x04 != 0
+    }
+
+    /// Creates a system that plays `song` (0-based). The system has no audio output yet.
+    pub fn system(&self, song: u8) -> Result<System> {
+        if song >= self.num_songs {
+            return Err(error::Type::InvalidOperation(format!(
+                "Song {} out of range, the file has {} songs.",
+                song + 1,
+                self.num_songs
+            )));
+        }
+        let mut system = System::default();
+        system.set_cart(Box::new(Cart::from_mem(self.rom(song))));
+        Ok(system)
+    }
+
+    /// Builds the cart ROM: the driver, followed by the rip at its load address. Padded to whole
+    /// 16KB banks.
+    fn rom(&self, song: u8) -> Vec<u8> {
+        let load_address = usize::from(self.load_address);
+        let bank_size = crate::cart::ROM_BANK_SIZE as usize;
+        let size = (load_address + self.data.len() + bank_size - 1) / bank_size * bank_size;
+        let mut rom = vec![0xFF; size.max(2 * bank_size)];
+        rom[load_address..load_address + self.data.len()].copy_from_slice(&self.data);
+        // RST vectors jump to their counterparts at the load address.
+        for rst in (0..0x40).step_by(8) {
+            let [lo, hi] = (self.load_address + rst).to_le_bytes();
+            rom[usize::from(rst)..usize::from(rst) + 3].copy_from_slice(&[0xC3, lo, hi]);
+        }
+        // Interrupt handlers only wake up the driver.
+        for vector in (0x40..=0x60).step_by(8) {
+            rom[vector] = 0xD9; // RETI
+        }
+        let driver = self.driver(song);
+        rom[DRIVER_ADDRESS..DRIVER_ADDRESS + driver.len()].copy_from_slice(&driver);
+        rom
+    }
+
+    /// The driver, which runs from the entry point.
+    fn driver(&self, song: u8) -> Vec<u8> {
+        let [sp_lo, sp_hi] = self.stack_pointer.to_le_bytes();
+        let [init_lo, init_hi] = self.init_address.to_le_bytes();
+        let [play_lo, play_hi] = self.play_address.to_le_bytes();
+        let interrupts = if self.uses_timer() { 0x04 } else { 0x01 };
+        #[rustfmt::skip]
+        let driver = vec![
+            0xF3,                           // DI
+            0x31, sp_lo, sp_hi,             // LD SP, stack_pointer
+            // Power on the APU, at full volume on both sides.
+            0x3E, 0x80, 0xE0, 0x26,         // LD A, 0x80; LDH (NR52), A
+            0x3E, 0x77, 0xE0, 0x24,         // LD A, 0x77; LDH (NR50), A
+            0x3E, 0xFF, 0xE0, 0x25,         // LD A, 0xFF; LDH (NR51), A
+            // Set up the timer. TAC bit 7 selects CGB double speed, which isn't supported.
+            0x3E, self.timer_modulo,        // LD A, TMA
+            0xE0, 0x06, 0xE0, 0x05,         // LDH (TMA), A; LDH (TIMA), A
+            0x3E, self.timer_control & 0x07, 0xE0, 0x07,    // LD A, TAC; LDH (TAC), A
+            0x3E, interrupts, 0xE0, 0xFF,   // LD A, interrupts; LDH (IE), A
+            0x3E, song,                     // LD A, song
+            0xCD, init_lo, init_hi,         // CALL init
+            0xFB,                           // EI
+            // Loop:
+            0x76,                           // HALT
+            0xCD, play_lo, play_hi,         // CALL play
+            0x18, 0xFA,                     // JR loop
+        ];
+        driver
+    }
+}
+
+fn bad_gbs(reason: &str) -> error::Type {
+    error::Type::InvalidOperation(format!("Bad GBS file: {}.", reason))
+}
+
+/// Holds the rip and the driver, with MBC1-style ROM banking and 8KB of RAM.
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+struct Cart {
+    #[cfg_attr(feature = "serialize", serde(with = "serde_bytes"))]
+    mem: Vec<u8>,
+    #[cfg_attr(feature = "serialize", serde(with = "serde_bytes"))]
+    ram: Vec<u8>,
+    rom_bank: usize,
+}
+
+impl Cart {
+    fn from_mem(mem: Vec<u8>) -> Cart {
+        Cart { mem, ram: vec![0; crate::cart::RAM_BANK_SIZE as usize], rom_bank: 1 }
+    }
+}
+
+impl mmu::MemoryMapped for Cart {
+    fn read(&self, address: mmu::Address) -> Option<i32> {
+        let mmu::Address(_, raw) = address;
+        let bank_size = crate::cart::ROM_BANK_SIZE as usize;
+        match raw {
+            0x0000..=0x3FFF => Some(i32::from(self.mem[raw as usize])),
+            0x4000..=0x7FFF => {
+                Some(i32::from(self.mem[self.rom_bank * bank_size + raw as usize - 0x4000]))
+            }
+            0xA000..=0xBFFF => Some(i32::from(self.ram[raw as usize - 0xA000])),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, address: mmu::Address, value: i32) -> Option<()> {
+        let mmu::Address(_, raw) = address;
+        match raw {
+            // ROM bank. Bank 0 selects bank 1.
+            0x2000..=0x3FFF => {
+                let num_banks = self.mem.len() / crate::cart::ROM_BANK_SIZE as usize;
+                self.rom_bank = (value as usize).max(1) % num_banks;
+                Some(())
+            }
+            0x0000..=0x7FFF => Some(()),
+            0xA000..=0xBFFF => {
+                self.ram[raw as usize - 0xA000] = value as u8;
+                Some(())
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg_attr(feature = "serialize", typetag::serde(name = "gbs"))]
+impl crate::cart::Cart for Cart {}
+
+impl AsRef<dyn mmu::MemoryMapped> for Cart {
+    fn as_ref(&self) -> &(dyn mmu::MemoryMapped + 'static) {
+        self
+    }
+}
+impl AsMut<dyn mmu::MemoryMapped> for Cart {
+    fn as_mut(&mut self) -> &mut (dyn mmu::MemoryMapped + 'static) {
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A rip whose init stores the song number at 0xC001, and whose play counts its calls at
+    /// 0xC000.
+    fn make_gbs(timer_control: u8) -> Vec<u8> {
+        let mut bytes = vec![0; HEADER_SIZE];
+        bytes[..4].copy_from_slice(b"GBS\x01");
+        bytes[4] = 3;
+        bytes[5] = 1;
+        bytes[6..8].copy_from_slice(&0x400u16.to_le_bytes());
+        bytes[8..10].copy_from_slice(&0x400u16.to_le_bytes());
+        bytes[0xA..0xC].copy_from_slice(&0x404u16.to_le_bytes());
+        bytes[0xC..0xE].copy_from_slice(&0xFFFEu16.to_le_bytes());
+        // 4096Hz, counting from 0xC0: 64 calls per second.
+        bytes[0xE] = 0xC0;
+        bytes[0xF] = timer_control;
+        bytes[0x10..0x15].copy_from_slice(b"Title");
+        // Init: LD (0xC001), A; RET
+        bytes.extend_from_slice(&[0xEA, 0x01, 0xC0, 0xC9]);
+        // Play: LD HL, 0xC000; INC (HL); RET
+        bytes.extend_from_slice(&[0x21, 0x00, 0xC0, 0x34, 0xC9]);
+        bytes
+    }
+
+    fn run(system: &mut System, tcycles: u64) {
+        while system.cycles() < tcycles {
+            system.execute_machine_cycle().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_parses_header() {
+        let gbs = Gbs::from_bytes(&make_gbs(0)).unwrap();
+        assert_eq!(gbs.num_songs, 3);
+        assert_eq!(gbs.first_song, 0);
+        assert_eq!((gbs.load_address, gbs.init_address, gbs.play_address), (0x400, 0x400, 0x404));
+        assert_eq!(gbs.title, "Title");
+        assert_eq!(gbs.author, "");
+        assert!(!gbs.uses_timer());
+        assert!(gbs.system(3).is_err());
+    }
+
+    #[test]
+    fn test_rejects_bad_files() {
+        let bytes = make_gbs(0);
+        assert!(Gbs::from_bytes(&bytes[..HEADER_SIZE - 1]).is_err());
+        assert!(Gbs::from_bytes(b"GBX").is_err());
+        let mut bytes = make_gbs(0);
+        bytes[6..8].copy_from_slice(&0x100u16.to_le_bytes());
+        assert!(Gbs::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_plays_on_vblank() {
+        let mut system = Gbs::from_bytes(&make_gbs(0)).unwrap().system(2).unwrap();
+        run(&mut system, 70224 * 10);
+        assert_eq!(system.memory_read(0xC001), 2);
+        let calls = system.memory_read(0xC000);
+        assert_ge!(calls, 9);
+        assert_le!(calls, 10);
+        // The driver powers on the APU.
+        assert_eq!(system.memory_read(0xFF26) & 0x80, 0x80);
+    }
+
+    #[test]
+    fn test_plays_on_timer() {
+        let mut system = Gbs::from_bytes(&make_gbs(0x04)).unwrap().system(0).unwrap();
+        run(&mut system, 4_194_304 / 4);
+        assert_eq!(system.memory_read(0xC001), 0);
+        let calls = system.memory_read(0xC000);
+        assert_ge!(calls, 15);
+        assert_le!(calls, 16);
+    }
+}
@@ -4,10 +4,15 @@ use num_traits::FromPrimitive;
 
 mod fetcher;
 mod fifo;
+pub mod filter;
+pub mod oam_bug;
 pub mod options;
+pub mod palette;
 pub mod registers;
+pub mod scanline_log;
 mod sprites;
 mod state_machine;
+pub mod viewer;
 
 #[cfg(test)]
 mod test;
@@ -37,9 +42,18 @@ pub enum Color {
     Black,
 }
 
+/// The palette register a pixel was shaded with. Frontends can give each its own colors.
+#[derive(Clone, Copy, Debug, PartialEq, FromPrimitive)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub enum Layer {
+    Bg,
+    Obj0,
+    Obj1,
+}
+
 /// BGRA pixel format.
 #[repr(C)]
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Pixel {
     pub b: u8,
     pub g: u8,
@@ -47,14 +61,10 @@ pub struct Pixel {
     pub a: u8,
 }
 
+/// Uses the default grey palette.
 impl From<&Color> for Pixel {
     fn from(color: &Color) -> Pixel {
-        match color {
-            Color::White => Pixel::new(255u8, 255u8, 255u8),
-            Color::LightGray => Pixel::new(192u8, 192u8, 192u8),
-            Color::DarkGray => Pixel::new(96u8, 96u8, 96u8),
-            Color::Black => Pixel::new(0u8, 0u8, 0u8),
-        }
+        palette::Palette::GREY.pixel(*color)
     }
 }
 
@@ -70,7 +80,7 @@ impl Pixel {
         Pixel { r: 0, g: 0, b: 0, a:0 }
     }*/
 
-    pub fn new(r: u8, g: u8, b: u8) -> Pixel {
+    pub const fn new(r: u8, g: u8, b: u8) -> Pixel {
         Pixel { b, g, r, a: 255 }
     }
 }
@@ -102,6 +112,15 @@ pub struct Gpu {
     fetcher: PixelFetcher,
     visible_sprites: ArrayVec<[u8; 10]>,
     fetched_sprites: [bool; 10],
+    /// The BG fetch interrupted by the current sprite fetch. It resumes where it left off.
+    bg_fetcher: PixelFetcher,
+    /// Dots until the sprite being fetched is done, see `sprites::fetch_penalty`.
+    sprite_penalty: i32,
+    /// The BG tiles sprites have already waited on during this line.
+    penalized_tiles: u32,
+    /// On DMG, a palette written during mode 3 reads as the OR of its old and new values for the
+    /// next dot.
+    palette_glitch: Option<(Layer, i32)>,
 
     // VRAM.
     #[cfg_attr(feature = "serialize", serde(with = "serde_bytes"))]
@@ -112,6 +131,9 @@ pub struct Gpu {
     pub options: Options,
 
     state: InternalState,
+
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    scanline_log: Option<scanline_log::ScanlineLog>,
 }
 
 #[derive(Debug)]
@@ -202,7 +224,9 @@ impl InternalState {
             self.oam_lock = true;
             self.vram_lock = true;
         }
-        if self.hblank_delay_tcycles < 8 || self.current_y >= 144 {
+        // The HBlank delay of the previous line is only reset at the end of this tick.
+        let is_hblank = self.counter != 0 && self.hblank_delay_tcycles < 8;
+        if is_hblank || self.current_y >= 144 {
             self.oam_lock = false;
             self.vram_lock = false;
         }
@@ -227,16 +251,37 @@ impl InternalState {
         }
         if let TState::T1 | TState::T3 = t_state {
             self.lcd_status.set_mode(self.mode as u8);
-            self.lcd_status.set_ly_is_lyc(self.lyc == self.required_lyc_for_interrupt());
+            self.lcd_status.set_ly_is_lyc(self.lyc_matches());
         }
-        let stat_asserted = (self.interrupts.bits() & self.lcd_status.0) != 0;
+        let stat_asserted = self.stat_line(self.lcd_status.0);
         self.fire_interrupt = stat_asserted && !self.old_stat_asserted;
         if let TState::T1 = t_state {
             self.old_stat_asserted = stat_asserted;
         }
         // Handle bus requests now.
         self.handle_bus_reads(bus);
+        let writes_stat = bus.writes_to_reg(self.lcd_status);
+        let writes_lyc = bus.writes_to_reg(self.lyc);
         self.handle_bus_writes(bus);
+        // Writes take effect on the STAT line right away, rather than on the next PPU cycle.
+        if writes_lyc {
+            self.interrupts.set(Interrupts::LYC, self.lyc_matches());
+        }
+        if writes_stat || writes_lyc {
+            // DMG bug: writing STAT enables every source for a cycle, so it fires in HBlank, VBlank
+            // or when LY=LYC, unless the line was already high.
+            let enabled = if writes_stat { Interrupts::all().bits() } else { self.lcd_status.0 };
+            let stat_asserted = self.stat_line(enabled);
+            self.fire_interrupt |= stat_asserted && !self.old_stat_asserted;
+            self.old_stat_asserted = stat_asserted;
+        }
+    }
+
+    /// The STAT interrupt line, the OR of every active source in `enabled`. The interrupt only
+    /// fires on its rising edge, so a source that becomes active while another one already holds
+    /// the line high is blocked.
+    fn stat_line(&self, enabled: i32) -> bool {
+        (self.interrupts.bits() & enabled) != 0
     }
 
     pub fn update_tock_after_render(&mut self, bus: &mut mmu::MemoryBus) {
@@ -258,13 +303,11 @@ impl InternalState {
         if (self.current_y == 144 && self.counter >= 4) || self.current_y >= 145 {
             self.interrupts |= Interrupts::VBLANK;
         }
-        if self.lyc == self.required_lyc_for_interrupt() && self.current_y > 0 {
-            // TODO: To fix the ly_lyc_write wilbert tests, I have to recheck for LYC interrupts
-            // after a CPU write has happened.
+        if self.lyc_matches() {
             self.interrupts |= Interrupts::LYC;
         }
         if let TState::T1 = t_state {
-            self.fire_interrupt_oam_hack = (self.interrupts.bits() & self.lcd_status.0) != 0;
+            self.fire_interrupt_oam_hack = self.stat_line(self.lcd_status.0);
             self.interrupts.remove(Interrupts::OAM);
             if self.entered_oam {
                 self.interrupts |= Interrupts::OAM;
@@ -282,6 +325,10 @@ impl InternalState {
         };
     }
 
+    fn lyc_matches(&self) -> bool {
+        self.lyc == self.required_lyc_for_interrupt()
+    }
+
     /// Returns the necessary LYC value for the LY=LYC interrupt to fire in this cycle. Will return
     /// 256 if it is impossible for LY=LYC to fire.
     fn required_lyc_for_interrupt(&self) -> i32 {
@@ -357,6 +404,10 @@ impl Default for Gpu {
             fetcher: PixelFetcher::new(),
             visible_sprites: ArrayVec::new(),
             fetched_sprites: [false; 10],
+            bg_fetcher: PixelFetcher::new(),
+            sprite_penalty: 0,
+            penalized_tiles: 0,
+            palette_glitch: None,
 
             vram: vec![0; 8192],
             oam: vec![0; 160],
@@ -364,6 +415,8 @@ impl Default for Gpu {
             options: Options::default(),
 
             state: InternalState::with_options(&Options::default()),
+
+            scanline_log: None,
         }
     }
 }
@@ -388,12 +441,107 @@ impl Gpu {
         self.oam[(address - 0xFE00) as usize] = value as u8;
     }
 
+    /// All of VRAM, 0x8000-0x9FFF, regardless of whether the CPU can currently access it.
+    pub fn vram_contents(&self) -> &[u8] {
+        &self.vram
+    }
+    /// All of OAM, 0xFE00-0xFE9F, regardless of whether the CPU can currently access it.
+    pub fn oam_contents(&self) -> &[u8] {
+        &self.oam
+    }
+
+    /// The registers the debug views in `viewer` depend on.
+    pub fn viewer_registers(&self) -> viewer::Registers {
+        let lcd_control = self.lcd_control();
+        viewer::Registers {
+            unsigned_tile_data: lcd_control.bg_set_id() != 0,
+            bg_map: lcd_control.bg_map_select() as usize,
+            enable_window: lcd_control.enable_window(),
+            window_map: lcd_control.window_map_select() as usize,
+            large_sprites: lcd_control.large_sprites(),
+            bg_palette: self.bg_palette as u8,
+            obj_palette_0: self.sprite_palette_0 as u8,
+            obj_palette_1: self.sprite_palette_1 as u8,
+            scroll_x: self.scroll_x as u8,
+            scroll_y: self.scroll_y as u8,
+            window_x: self.window_xpos as u8,
+            window_y: self.window_ypos as u8,
+        }
+    }
+
+    /// Starts recording a new `scanline_log::ScanlineLog`, dropping the one in progress.
+    pub fn start_scanline_log(&mut self) {
+        self.scanline_log = Some(scanline_log::ScanlineLog::new());
+    }
+
+    pub fn stop_scanline_log(&mut self) -> Option<scanline_log::ScanlineLog> {
+        self.scanline_log.take()
+    }
+
+    fn logged_registers(&self) -> scanline_log::RegisterValues {
+        scanline_log::RegisterValues {
+            lcdc: self.state.lcd_control.value() as u8,
+            stat: self.state.lcd_status.value() as u8,
+            scx: self.scroll_x as u8,
+            scy: self.scroll_y as u8,
+            wx: self.window_xpos as u8,
+            wy: self.window_ypos as u8,
+            bgp: self.bg_palette as u8,
+            obp0: self.sprite_palette_0 as u8,
+            obp1: self.sprite_palette_1 as u8,
+        }
+    }
+
+    /// Logs the mode, and the LCDC and STAT writes, which go through the bus rather than `write`.
+    fn update_scanline_log(&mut self, bus: &mmu::MemoryBus) {
+        use crate::io_registers::Addresses;
+        use scanline_log::Register;
+
+        let registers = self.logged_registers();
+        let (line, dot) = (self.state.current_y, self.state.counter);
+        if let Some(log) = &mut self.scanline_log {
+            let bus_registers = [
+                (Addresses::LcdControl as i32, Register::Lcdc),
+                (Addresses::LcdStatus as i32, Register::Stat),
+            ];
+            for &(address, register) in &bus_registers {
+                if let Some(value) = bus.writes_to(address) {
+                    log.record_write(line, dot, register, value as u8);
+                }
+            }
+            log.record_mode(line, dot, self.state.mode, registers);
+        }
+    }
+
     pub fn at_vblank(&self) -> bool {
         self.lcd_control().enable_display()
             && self.state.counter == 4
             && self.state.current_y == 144
     }
 
+    /// The OAM row the PPU reads in the current M-cycle, if it is scanning OAM. The scan is a row
+    /// ahead of the M-cycle, so it never reads row 0.
+    fn oam_scan_row(&self) -> Option<usize> {
+        let state = &self.state;
+        let is_scanning = self.lcd_control().enable_display()
+            && state.current_y < 144
+            && state.counter < 80
+            && !(state.is_first_frame && state.current_y == 0);
+        if is_scanning {
+            Some(state.counter as usize / 4 + 1)
+        } else {
+            None
+        }
+    }
+
+    /// Called for every M-cycle the CPU uses an address in 0xFE00-0xFEFF. Corrupts OAM if the PPU is
+    /// scanning it.
+    pub fn corrupt_oam(&mut self, access: oam_bug::Access) {
+        if let Some(row) = self.oam_scan_row() {
+            oam_bug::corrupt(&mut self.oam, row, access);
+        }
+    }
+
     fn can_access_oam(&self) -> bool {
         !self.state.oam_lock
     }
@@ -414,13 +562,20 @@ impl Gpu {
         t_state: TState,
         bus: &mut mmu::MemoryBus,
         screen: &mut [Color],
+        layers: &mut [Layer],
     ) -> system::Interrupts {
         if !self.state.lcd_control.enable_display() {
             self.state.update_tock_disabled(bus);
+            if self.scanline_log.is_some() {
+                self.update_scanline_log(bus);
+            }
             return system::Interrupts::empty();
         }
 
         self.state.update_tock(t_state, bus);
+        if self.scanline_log.is_some() {
+            self.update_scanline_log(bus);
+        }
 
         if self.state.counter == 82 {
             self.start_new_scanline();
@@ -429,7 +584,7 @@ impl Gpu {
         if let LcdMode::ReadingOAM | LcdMode::TransferringToLcd = self.state.mode {
             debug_assert!(self.state.mode == LcdMode::TransferringToLcd || self.state.counter < 84);
             if self.state.counter >= 82 && self.state.pixels_pushed < 160 {
-                self.lcd_transfer_cycle(screen);
+                self.lcd_transfer_cycle(screen, layers);
             }
         }
 
@@ -451,10 +606,11 @@ impl Gpu {
             self.window_ycount = (self.window_ycount + 1) & 0xFF;
         }
 
-        self.fetcher = PixelFetcher::start_new_scanline(&self);
+        self.fetcher = PixelFetcher::start_new_scanline();
         self.fifo = PixelFifo::start_new_scanline(self.scroll_x);
 
         self.fetched_sprites = [false; 10];
+        self.penalized_tiles = 0;
 
         if self.lcd_control().enable_sprites() {
             self.visible_sprites = sprites::find_visible_sprites(
@@ -465,9 +621,12 @@ impl Gpu {
         } else {
             self.visible_sprites.clear();
         }
+        if let Some(log) = &mut self.scanline_log {
+            log.record_sprites(self.state.current_y, &self.visible_sprites);
+        }
     }
 
-    fn lcd_transfer_cycle(&mut self, screen: &mut [Color]) {
+    fn lcd_transfer_cycle(&mut self, screen: &mut [Color], layers: &mut [Layer]) {
         self.fetcher = self.fetcher.execute_tcycle(&self);
 
         // Handle window.
@@ -485,17 +644,20 @@ impl Gpu {
         if self.fifo.has_pixels() && self.state.counter >= self.options.transfer_start_tcycle {
             if self.fifo.is_good_pixel() {
                 // Push a pixel into the screen.
-                let entry = self.fifo.peek();
-                if (entry.is_sprite()
-                    || self.lcd_control().enable_bg()
-                    || self.lcd_control().enable_window())
-                    && self.current_y() < LCD_HEIGHT as i32
-                {
+                let mut entry = self.fifo.peek();
+                // With the BG off, the BG and window are drawn as color 0 and sprites always win.
+                // Checked as the pixel is drawn, so it can change mid-line.
+                if !entry.is_sprite() && !self.lcd_control().enable_bg() {
+                    entry.set_index(0);
+                }
+                if self.current_y() < LCD_HEIGHT as i32 {
                     debug_assert_ge!(self.state.hblank_delay_tcycles, 7);
                     debug_assert_lt!(self.current_y(), LCD_HEIGHT as i32);
                     debug_assert_lt!(self.pixels_pushed(), LCD_WIDTH as i32);
-                    screen[(self.pixels_pushed() + self.current_y() * LCD_WIDTH as i32) as usize] =
-                        self.fifo_entry_to_color(self.fifo.peek());
+                    let i = (self.pixels_pushed() + self.current_y() * LCD_WIDTH as i32) as usize;
+                    let (color, layer) = self.fifo_entry_to_color(entry);
+                    screen[i] = color;
+                    layers[i] = layer;
                 }
 
                 self.state.pixels_pushed += 1;
@@ -503,6 +665,7 @@ impl Gpu {
             // Pop the pixel regardless if we drew it or not.
             self.fifo.pop();
         }
+        self.palette_glitch = None;
     }
 
     fn handle_window(&mut self) {
@@ -541,9 +704,16 @@ impl Gpu {
                     // the first place! Also, if we need to fine x-scroll, do it before any sprite
                     // work.
                     if self.fifo.enough_for_sprite() && self.fifo.is_good_pixel() {
+                        let sprite = self.get_sprite(sprite_index);
                         self.fifo.is_suspended = true;
-                        self.fetcher =
-                            self.fetcher.start_new_sprite(&self, self.get_sprite(sprite_index));
+                        // The FIFO is already stalled on this dot.
+                        self.sprite_penalty = sprites::fetch_penalty(
+                            sprite,
+                            self.scroll_x % 8,
+                            &mut self.penalized_tiles,
+                        ) - 1;
+                        self.bg_fetcher = self.fetcher;
+                        self.fetcher = self.fetcher.start_new_sprite(&self, sprite);
                         self.drawing_mode = DrawingMode::FetchingSprite;
                     }
                 } else {
@@ -557,8 +727,9 @@ impl Gpu {
                 debug_assert!(has_visible_sprite);
                 debug_assert!(!self.fetched_sprites[sprite_array_index]);
 
-                // Check if the fetcher is ready.
-                if self.fetcher.has_data() {
+                // Check if the fetcher is ready, and the sprite has stalled the FIFO long enough.
+                self.sprite_penalty -= 1;
+                if self.fetcher.has_data() && self.sprite_penalty <= 0 {
                     debug_assert!(self.fifo.enough_for_sprite());
                     // If so, composite the sprite pixels ontop of the pixels currently in the fifo.
                     let sprite = self.get_sprite(sprite_index);
@@ -570,8 +741,8 @@ impl Gpu {
                     )
                     .take(8)
                     .skip(sprites::pixels_behind(self.pixels_pushed(), sprite));
-                    // Only keep enough pixels to
-                    self.fetcher = self.fetcher.continue_scanline();
+                    // Pick the BG fetch back up. Waiting on it is part of the penalty.
+                    self.fetcher = self.bg_fetcher;
                     self.fifo = self.fifo.clone().combined_with_sprite(row);
 
                     // Go back to drawing as usual.
@@ -582,22 +753,34 @@ impl Gpu {
         }
     }
 
-    fn fifo_entry_to_color(&self, entry: FifoEntry) -> Color {
-        let palette = if entry.is_sprite() {
+    /// Returns the shade of the entry, and the palette register that shaded it.
+    fn fifo_entry_to_color(&self, entry: FifoEntry) -> (Color, Layer) {
+        let (palette, layer) = if entry.is_sprite() {
             if entry.palette() == 0 {
-                self.sprite_palette_0
+                (self.sprite_palette_0, Layer::Obj0)
             } else {
-                self.sprite_palette_1
+                (self.sprite_palette_1, Layer::Obj1)
             }
         } else {
-            self.bg_palette
+            (self.bg_palette, Layer::Bg)
+        };
+        let palette = match self.palette_glitch {
+            Some((glitched_layer, value)) if glitched_layer == layer => value,
+            _ => palette,
         };
 
-        match (palette >> (entry.pixel_index() * 2)) & 0x3 {
+        let color = match (palette >> (entry.pixel_index() * 2)) & 0x3 {
             0 => Color::White,
             1 => Color::LightGray,
             2 => Color::DarkGray,
             3 | _ => Color::Black,
+        };
+        (color, layer)
+    }
+
+    fn glitch_palette(&mut self, layer: Layer, value: i32) {
+        if self.lcd_control().enable_display() && self.state.mode == LcdMode::TransferringToLcd {
+            self.palette_glitch = Some((layer, value));
         }
     }
 
@@ -649,6 +832,14 @@ impl mmu::MemoryMapped for Gpu {
                     Some(0xFF)
                 }
             }
+            // Nothing is stored there. On DMG, it reads as 0 unless OAM is blocked.
+            mmu::Location::UnusedOAM => {
+                if self.can_access_oam() {
+                    Some(0x00)
+                } else {
+                    Some(0xFF)
+                }
+            }
             _ => None,
         }
     }
@@ -656,6 +847,17 @@ impl mmu::MemoryMapped for Gpu {
     fn write(&mut self, address: mmu::Address, value: i32) -> Option<()> {
         let mmu::Address(location, raw) = address;
         use crate::io_registers::Addresses;
+        if let (Some(log), mmu::Location::Registers) = (&mut self.scanline_log, location) {
+            use scanline_log::Register;
+            // LCDC and STAT are logged from the bus, see `update_scanline_log`.
+            match Register::from_address(raw) {
+                Some(Register::Lcdc) | Some(Register::Stat) | None => {}
+                Some(register) => {
+                    let (line, dot) = (self.state.current_y, self.state.counter);
+                    log.record_write(line, dot, register, value as u8);
+                }
+            }
+        }
         match location {
             mmu::Location::Registers => match Addresses::from_i32(raw) {
                 Some(Addresses::ScrollX) => {
@@ -675,14 +877,17 @@ impl mmu::MemoryMapped for Gpu {
                     Some(())
                 }
                 Some(Addresses::BgPalette) => {
+                    self.glitch_palette(Layer::Bg, self.bg_palette | value);
                     self.bg_palette = value;
                     Some(())
                 }
                 Some(Addresses::SpritePalette0) => {
+                    self.glitch_palette(Layer::Obj0, self.sprite_palette_0 | value);
                     self.sprite_palette_0 = value;
                     Some(())
                 }
                 Some(Addresses::SpritePalette1) => {
+                    self.glitch_palette(Layer::Obj1, self.sprite_palette_1 | value);
                     self.sprite_palette_1 = value;
                     Some(())
                 }
@@ -700,6 +905,7 @@ impl mmu::MemoryMapped for Gpu {
                 }
                 Some(())
             }
+            mmu::Location::UnusedOAM => Some(()),
             _ => None,
         }
     }
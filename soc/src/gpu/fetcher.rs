@@ -37,6 +37,8 @@ pub struct PixelFetcher {
     sprite_mode: bool,
     pub window_mode: bool,
 
+    /// BG tiles fetched on this line. SCX's upper bits are added on every fetch, so they can
+    /// change mid-line.
     bg_tiles: i32,
     pub window_tiles_read: i32,
     // Mostly (i.e. just) needed for sprites. Can easily just pass this in the execute functions.
@@ -52,10 +54,9 @@ impl PixelFetcher {
         PixelFetcher::default()
     }
 
-    pub fn start_new_scanline(gpu: &Gpu) -> PixelFetcher {
+    pub fn start_new_scanline() -> PixelFetcher {
         PixelFetcher {
             mode: Mode::ReadTileIndex,
-            bg_tiles: util::upper_5_bits(gpu.scroll_x),
             ..Default::default()
         }
     }
@@ -72,7 +73,8 @@ impl PixelFetcher {
         };
         PixelFetcher {
             mode: Mode::ReadTileIndex,
-            tock: false,
+            // Sprite fetches skip reading the tile index, so they are done in 5 dots.
+            tock: true,
             sprite_mode: true,
             tile_index,
             // Compute the y-offset now while we still have the sprite.
@@ -81,11 +83,6 @@ impl PixelFetcher {
         }
     }
 
-    pub fn continue_scanline(self) -> PixelFetcher {
-        debug_assert!(self.sprite_mode);
-        PixelFetcher { mode: Mode::ReadTileIndex, tock: false, sprite_mode: false, ..self }
-    }
-
     pub fn start_window_mode(&mut self) {
         self.mode = Mode::ReadTileIndex;
         self.tock = false;
@@ -168,7 +165,7 @@ impl PixelFetcher {
 
     fn bg_nametable_address(&self, gpu: &Gpu) -> i32 {
         let mut addr = NametableAddress(0);
-        addr.set_upper_xscroll(self.bg_tiles as u8);
+        addr.set_upper_xscroll((util::upper_5_bits(gpu.scroll_x) + self.bg_tiles) as u8);
         let ybase = gpu.scroll_y + gpu.current_y();
         addr.set_upper_ybase(util::upper_5_bits(ybase) as u8);
         addr.set_nametable_number(gpu.lcd_control().bg_map_select());
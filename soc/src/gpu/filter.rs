@@ -0,0 +1,258 @@
+//! Post-processing of the screen, done on the CPU so that the window, the headless runner and the
+//! web demo all get the same pixels. Frames are optionally blended with the previous one, then
+//! go through a pixel-art scaler and finally an integer scale.
+
+use super::viewer::Image;
+use super::Pixel;
+
+/// Pixel-art scalers, see https://www.scale2x.it/algorithm.
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scaler {
+    None,
+    Scale2x,
+    Scale3x,
+}
+
+impl Scaler {
+    /// Looks up a scaler by name: "none", "scale2x" or "scale3x".
+    pub fn from_name(name: &str) -> Option<Scaler> {
+        match name {
+            "none" => Some(Scaler::None),
+            "scale2x" => Some(Scaler::Scale2x),
+            "scale3x" => Some(Scaler::Scale3x),
+            _ => None,
+        }
+    }
+
+    pub fn factor(self) -> usize {
+        match self {
+            Scaler::None => 1,
+            Scaler::Scale2x => 2,
+            Scaler::Scale3x => 3,
+        }
+    }
+
+    /// Cycles through the scalers.
+    pub fn next(self) -> Scaler {
+        match self {
+            Scaler::None => Scaler::Scale2x,
+            Scaler::Scale2x => Scaler::Scale3x,
+            Scaler::Scale3x => Scaler::None,
+        }
+    }
+}
+
+/// For command line arguments.
+impl std::str::FromStr for Scaler {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Scaler, String> {
+        Scaler::from_name(name)
+            .ok_or_else(|| format!("Unknown scaler {}, expected none, scale2x or scale3x.", name))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Filters {
+    /// Averages every frame with the previous one, like the slow DMG LCD does. Many games flicker
+    /// sprites every other frame and count on it for transparency.
+    pub blend_frames: bool,
+    pub scaler: Scaler,
+    /// Nearest-neighbor scale applied after `scaler`. At least 1.
+    pub scale: usize,
+}
+
+impl Default for Filters {
+    fn default() -> Filters {
+        Filters { blend_frames: false, scaler: Scaler::None, scale: 1 }
+    }
+}
+
+impl Filters {
+    /// Whether the filters leave screens as they are.
+    pub fn is_identity(&self) -> bool {
+        !self.blend_frames && self.scaler == Scaler::None && self.scale == 1
+    }
+
+    /// The size of a `width`x`height` image once filtered.
+    pub fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+        let factor = self.scaler.factor() * self.scale;
+        (width * factor, height * factor)
+    }
+
+    /// Filters `frame`. `previous` is the frame before it, if any, for blending.
+    pub fn apply(&self, frame: &Image, previous: Option<&Image>) -> Image {
+        let blended = match previous {
+            Some(previous) if self.blend_frames => blend(frame, previous),
+            _ => frame.clone(),
+        };
+        let scaled = match self.scaler {
+            Scaler::None => blended,
+            Scaler::Scale2x => scale2x(&blended),
+            Scaler::Scale3x => scale3x(&blended),
+        };
+        if self.scale > 1 {
+            scale_nearest(&scaled, self.scale)
+        } else {
+            scaled
+        }
+    }
+}
+
+/// The average of two images of the same size.
+pub fn blend(a: &Image, b: &Image) -> Image {
+    debug_assert_eq!((a.width, a.height), (b.width, b.height));
+    let mix = |x: u8, y: u8| ((u16::from(x) + u16::from(y) + 1) >> 1) as u8;
+    let pixels = a
+        .pixels
+        .iter()
+        .zip(&b.pixels)
+        .map(|(x, y)| Pixel {
+            r: mix(x.r, y.r),
+            g: mix(x.g, y.g),
+            b: mix(x.b, y.b),
+            a: mix(x.a, y.a),
+        })
+        .collect();
+    Image { width: a.width, height: a.height, pixels }
+}
+
+/// Repeats every pixel `factor` times in both directions.
+pub fn scale_nearest(image: &Image, factor: usize) -> Image {
+    let width = image.width * factor;
+    let mut pixels = Vec::with_capacity(width * image.height * factor);
+    for row in image.pixels.chunks(image.width) {
+        let mut scaled_row = Vec::with_capacity(width);
+        for &pixel in row {
+            scaled_row.resize(scaled_row.len() + factor, pixel);
+        }
+        for _ in 0..factor {
+            pixels.extend_from_slice(&scaled_row);
+        }
+    }
+    Image { width, height: image.height * factor, pixels }
+}
+
+/// The pixel at (x + dx, y + dy), with the edges repeated outside of the image.
+fn neighbor(image: &Image, x: usize, y: usize, dx: isize, dy: isize) -> Pixel {
+    let clamp = |v: usize, d: isize, size: usize| (v as isize + d).max(0).min(size as isize - 1);
+    image.pixel(clamp(x, dx, image.width) as usize, clamp(y, dy, image.height) as usize)
+}
+
+/// Scales `image` by `factor`, with `expand` turning a pixel's 3x3 neighborhood, row by row,
+/// into its `factor`x`factor` block, row by row.
+fn scale_blocks(image: &Image, factor: usize, expand: impl Fn(&[Pixel; 9]) -> Vec<Pixel>) -> Image {
+    let width = image.width * factor;
+    let mut pixels = vec![Pixel::new(0, 0, 0); width * image.height * factor];
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let mut around = [Pixel::new(0, 0, 0); 9];
+            for (i, pixel) in around.iter_mut().enumerate() {
+                *pixel = neighbor(image, x, y, i as isize % 3 - 1, i as isize / 3 - 1);
+            }
+            for (i, &pixel) in expand(&around).iter().enumerate() {
+                pixels[(y * factor + i / factor) * width + x * factor + i % factor] = pixel;
+            }
+        }
+    }
+    Image { width, height: image.height * factor, pixels }
+}
+
+/// Scale2x, also known as AdvMAME2x: doubles the size, rounding off diagonal edges.
+pub fn scale2x(image: &Image) -> Image {
+    scale_blocks(image, 2, |&[_, b, _, d, e, f, _, h, _]| {
+        if b == h || d == f {
+            return vec![e; 4];
+        }
+        vec![
+            if d == b { d } else { e },
+            if b == f { f } else { e },
+            if d == h { d } else { e },
+            if h == f { f } else { e },
+        ]
+    })
+}
+
+/// Scale3x, also known as AdvMAME3x: the same idea as Scale2x, tripling the size.
+pub fn scale3x(image: &Image) -> Image {
+    scale_blocks(image, 3, |&[a, b, c, d, e, f, g, h, i]| {
+        if b == h || d == f {
+            return vec![e; 9];
+        }
+        vec![
+            if d == b { d } else { e },
+            if (d == b && e != c) || (b == f && e != a) { b } else { e },
+            if b == f { f } else { e },
+            if (d == b && e != g) || (d == h && e != a) { d } else { e },
+            e,
+            if (b == f && e != i) || (h == f && e != c) { f } else { e },
+            if d == h { d } else { e },
+            if (d == h && e != i) || (h == f && e != g) { h } else { e },
+            if h == f { f } else { e },
+        ]
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const WHITE: Pixel = Pixel::new(255, 255, 255);
+    const BLACK: Pixel = Pixel::new(0, 0, 0);
+
+    /// Parses rows of '#' (black) and '.' (white).
+    fn image(rows: &[&str]) -> Image {
+        let pixels =
+            rows.iter().flat_map(|x| x.chars()).map(|x| if x == '#' { BLACK } else { WHITE });
+        Image { width: rows[0].len(), height: rows.len(), pixels: pixels.collect() }
+    }
+
+    #[test]
+    fn test_blend() {
+        let blended = blend(&image(&["#."]), &image(&["##"]));
+        assert_eq!(blended.pixels, [BLACK, Pixel::new(128, 128, 128)]);
+    }
+
+    #[test]
+    fn test_scale_nearest() {
+        let scaled = scale_nearest(&image(&["#.", ".."]), 2);
+        assert_eq!(scaled, image(&["##..", "##..", "....", "...."]));
+    }
+
+    #[test]
+    fn test_scale2x() {
+        // The inside corner of a staircase is filled in.
+        let scaled = scale2x(&image(&["##", "#."]));
+        assert_eq!(scaled, image(&["####", "####", "###.", "##.."]));
+        // Isolated pixels and straight edges are left as they are.
+        for rows in &[&["...", ".#.", "..."][..], &["##", ".."]] {
+            assert_eq!(scale2x(&image(rows)), scale_nearest(&image(rows), 2));
+        }
+    }
+
+    #[test]
+    fn test_scale3x() {
+        let scaled = scale3x(&image(&["##", "#."]));
+        assert_eq!(scaled, image(&["######", "######", "######", "#####.", "####..", "###..."]));
+        for rows in &[&["...", ".#.", "..."][..], &["##", ".."]] {
+            assert_eq!(scale3x(&image(rows)), scale_nearest(&image(rows), 3));
+        }
+    }
+
+    #[test]
+    fn test_filters() {
+        let frame = image(&["#."]);
+        let previous = image(&[".."]);
+        let filters = Filters { blend_frames: true, scaler: Scaler::Scale2x, scale: 2 };
+        assert!(!filters.is_identity());
+        assert_eq!(filters.output_size(160, 144), (640, 576));
+        let filtered = filters.apply(&frame, Some(&previous));
+        assert_eq!((filtered.width, filtered.height), (8, 4));
+        assert_eq!(filtered.pixel(0, 0), Pixel::new(128, 128, 128));
+        assert_eq!(filtered.pixel(7, 3), WHITE);
+        // Without a previous frame, there is nothing to blend with.
+        assert_eq!(filters.apply(&frame, None).pixel(0, 0), BLACK);
+        assert!(Filters::default().is_identity());
+    }
+}
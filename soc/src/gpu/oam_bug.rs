@@ -0,0 +1,135 @@
+//! The DMG OAM corruption bug. During the OAM scan the PPU reads one 8 byte row of OAM per M-cycle.
+//! If the CPU puts an address in 0xFE00-0xFEFF on the bus at the same time, through a memory access
+//! or the 16-bit incrementer (IDU), the row being read gets mixed with the previous one.
+//!
+//! See https://gbdev.io/pandocs/OAM_Corruption_Bug.html. Rows are made of four 16-bit words.
+//! `END_ROW` and the row `Gpu::oam_scan_row` reports are derived from that description, and have
+//! yet to be checked against Blargg's `oam_bug` ROMs, see `tests/blargg.rs`.
+
+const ROW_SIZE: usize = 8;
+/// The first row, and the rows from this one on, are never corrupted.
+const END_ROW: usize = 19;
+/// Reads during an increase only touch rows from this one on.
+const FIRST_INCREASE_ROW: usize = 4;
+
+/// What the CPU did with an OAM address while the PPU was reading `row`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    /// A write, or the IDU on its own.
+    Write,
+    Read,
+    /// A read in the same M-cycle as the IDU. The read itself corrupts OAM too.
+    ReadIncrease,
+}
+
+fn word(oam: &[u8], row: usize, index: usize) -> u16 {
+    let offset = row * ROW_SIZE + index * 2;
+    u16::from_le_bytes([oam[offset], oam[offset + 1]])
+}
+
+fn set_word(oam: &mut [u8], row: usize, index: usize, value: u16) {
+    let offset = row * ROW_SIZE + index * 2;
+    oam[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn copy_row(oam: &mut [u8], from: usize, to: usize) {
+    oam.copy_within(from * ROW_SIZE..(from + 1) * ROW_SIZE, to * ROW_SIZE);
+}
+
+/// Replaces the first word of `row` with `glitch` of it and the first and third words of the
+/// previous row. The other three words are copied from the previous row.
+fn corrupt_row(oam: &mut [u8], row: usize, glitch: impl Fn(u16, u16, u16) -> u16) {
+    let value = glitch(word(oam, row, 0), word(oam, row - 1, 0), word(oam, row - 1, 2));
+    copy_row(oam, row - 1, row);
+    set_word(oam, row, 0, value);
+}
+
+/// Corrupts `oam` the way `access` does while the PPU reads `row`.
+pub fn corrupt(oam: &mut [u8], row: usize, access: Access) {
+    if row == 0 || row >= END_ROW {
+        return;
+    }
+    match access {
+        Access::Write => corrupt_row(oam, row, |a, b, c| ((a ^ c) & (b ^ c)) ^ c),
+        Access::Read => corrupt_row(oam, row, |a, b, c| b | (a & c)),
+        Access::ReadIncrease => {
+            if row >= FIRST_INCREASE_ROW {
+                let (a, b) = (word(oam, row - 2, 0), word(oam, row - 1, 0));
+                let (c, d) = (word(oam, row, 0), word(oam, row - 1, 2));
+                set_word(oam, row - 1, 0, (b & (a | c | d)) | (a & c & d));
+                copy_row(oam, row - 1, row);
+                copy_row(oam, row - 1, row - 2);
+            }
+            corrupt(oam, row, Access::Read);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every word holds its row in the high byte and its index in the low byte.
+    fn numbered_oam() -> Vec<u8> {
+        (0..80).flat_map(|x| vec![(x % 4) as u8, (x / 4) as u8]).collect()
+    }
+
+    fn row(oam: &[u8], row: usize) -> [u16; 4] {
+        [word(oam, row, 0), word(oam, row, 1), word(oam, row, 2), word(oam, row, 3)]
+    }
+
+    #[test]
+    fn test_write() {
+        let mut oam = numbered_oam();
+        corrupt(&mut oam, 5, Access::Write);
+        // ((0x0500 ^ 0x0402) & (0x0400 ^ 0x0402)) ^ 0x0402
+        assert_eq!(row(&oam, 5), [0x0400, 0x0401, 0x0402, 0x0403]);
+        assert_eq!(row(&oam, 4), [0x0400, 0x0401, 0x0402, 0x0403]);
+        assert_eq!(row(&oam, 6), [0x0600, 0x0601, 0x0602, 0x0603]);
+    }
+
+    #[test]
+    fn test_read() {
+        let mut oam = numbered_oam();
+        corrupt(&mut oam, 3, Access::Read);
+        // 0x0200 | (0x0300 & 0x0202)
+        assert_eq!(row(&oam, 3), [0x0200, 0x0201, 0x0202, 0x0203]);
+        let mut oam = numbered_oam();
+        set_word(&mut oam, 3, 0, 0xFFFF);
+        corrupt(&mut oam, 3, Access::Read);
+        assert_eq!(row(&oam, 3), [0x0202, 0x0201, 0x0202, 0x0203]);
+    }
+
+    #[test]
+    fn test_read_increase() {
+        let mut oam = numbered_oam();
+        set_word(&mut oam, 4, 0, 0x00F0);
+        set_word(&mut oam, 5, 0, 0x0F0F);
+        set_word(&mut oam, 5, 2, 0x5555);
+        set_word(&mut oam, 6, 0, 0x3C3C);
+        corrupt(&mut oam, 6, Access::ReadIncrease);
+        // (0x0F0F & (0x00F0 | 0x3C3C | 0x5555)) | (0x00F0 & 0x3C3C & 0x5555)
+        let glitched = [0x0D1D, 0x0501, 0x5555, 0x0503];
+        assert_eq!(row(&oam, 4), glitched);
+        assert_eq!(row(&oam, 5), glitched);
+        // Then the read corruption, over the copied row.
+        assert_eq!(row(&oam, 6), glitched);
+        assert_eq!(row(&oam, 3), [0x0300, 0x0301, 0x0302, 0x0303]);
+        assert_eq!(row(&oam, 7), [0x0700, 0x0701, 0x0702, 0x0703]);
+    }
+
+    #[test]
+    fn test_unaffected_rows() {
+        let mut oam = numbered_oam();
+        for &row in &[0, END_ROW, 20] {
+            corrupt(&mut oam, row, Access::Write);
+            corrupt(&mut oam, row, Access::Read);
+        }
+        assert_eq!(oam, numbered_oam());
+        // Too close to the start for the increase part, only the read applies.
+        corrupt(&mut oam, 2, Access::ReadIncrease);
+        let mut expected = numbered_oam();
+        corrupt(&mut expected, 2, Access::Read);
+        assert_eq!(oam, expected);
+    }
+}
@@ -0,0 +1,224 @@
+use super::{Color, Layer, Pixel};
+use crate::error::{self, Result};
+
+/// The colors the four DMG shades are displayed with, from White to Black.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette(pub [Pixel; 4]);
+
+impl Palette {
+    /// An even grey ramp.
+    pub const GREY: Palette = Palette([
+        Pixel::new(255, 255, 255),
+        Pixel::new(192, 192, 192),
+        Pixel::new(96, 96, 96),
+        Pixel::new(0, 0, 0),
+    ]);
+    /// The original DMG's green LCD.
+    pub const DMG_GREEN: Palette = Palette([
+        Pixel::new(0x9B, 0xBC, 0x0F),
+        Pixel::new(0x8B, 0xAC, 0x0F),
+        Pixel::new(0x30, 0x62, 0x30),
+        Pixel::new(0x0F, 0x38, 0x0F),
+    ]);
+    /// The Game Boy Pocket's grey LCD.
+    pub const POCKET: Palette = Palette([
+        Pixel::new(0xC4, 0xCF, 0xA1),
+        Pixel::new(0x8B, 0x95, 0x6D),
+        Pixel::new(0x4D, 0x53, 0x3C),
+        Pixel::new(0x1F, 0x1F, 0x1F),
+    ]);
+
+    /// Looks up a built-in palette: "grey", "green" or "pocket".
+    pub fn from_name(name: &str) -> Option<Palette> {
+        match name {
+            "grey" => Some(Palette::GREY),
+            "green" => Some(Palette::DMG_GREEN),
+            "pocket" => Some(Palette::POCKET),
+            _ => None,
+        }
+    }
+
+    pub fn pixel(&self, color: Color) -> Pixel {
+        self.0[color as usize]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::GREY
+    }
+}
+
+/// A palette for each of the DMG's palette registers, so that e.g. sprites can stand out from the
+/// background.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DisplayPalettes {
+    pub bg: Palette,
+    pub obj0: Palette,
+    pub obj1: Palette,
+}
+
+impl DisplayPalettes {
+    /// Uses `palette` for everything.
+    pub fn uniform(palette: Palette) -> DisplayPalettes {
+        DisplayPalettes { bg: palette, obj0: palette, obj1: palette }
+    }
+
+    pub fn for_layer(&self, layer: Layer) -> &Palette {
+        match layer {
+            Layer::Bg => &self.bg,
+            Layer::Obj0 => &self.obj0,
+            Layer::Obj1 => &self.obj1,
+        }
+    }
+
+    /// Parses a palette file. It holds either 4 colors, used for everything, or 12 colors: 4 for
+    /// BG, then 4 for OBJ0 and 4 for OBJ1. Colors are hex RRGGBB, optionally prefixed with '#',
+    /// separated by whitespace or commas, and go from lightest to darkest shade. Anything after a
+    /// ';' is a comment.
+    pub fn from_text(text: &str) -> Result<DisplayPalettes> {
+        let colors = text
+            .lines()
+            .map(|line| line.split(';').next().unwrap())
+            .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|x| !x.is_empty())
+            .map(parse_color)
+            .collect::<Result<Vec<_>>>()?;
+        let palette = |i: usize| Palette([colors[i], colors[i + 1], colors[i + 2], colors[i + 3]]);
+        match colors.len() {
+            4 => Ok(DisplayPalettes::uniform(palette(0))),
+            12 => Ok(DisplayPalettes { bg: palette(0), obj0: palette(4), obj1: palette(8) }),
+            len => Err(bad_palette(&format!("Expected 4 or 12 colors, got {}", len))),
+        }
+    }
+
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<DisplayPalettes> {
+        let text = std::fs::read_to_string(path).map_err(|err| {
+            error::Type::InvalidOperation(format!("Could not read palette: {}", err))
+        })?;
+        DisplayPalettes::from_text(&text)
+    }
+}
+
+fn parse_color(text: &str) -> Result<Pixel> {
+    let hex = text.trim_start_matches('#');
+    if hex.len() != 6 {
+        return Err(bad_palette(&format!("Invalid color {}", text)));
+    }
+    let rgb = u32::from_str_radix(hex, 16)
+        .map_err(|_| bad_palette(&format!("Invalid color {}", text)))?;
+    Ok(Pixel::new((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8))
+}
+
+fn bad_palette(reason: &str) -> error::Type {
+    error::Type::InvalidOperation(format!("Bad palette file: {}.", reason))
+}
+
+/// How screen pixels are laid out in memory.
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelFormat {
+    /// 4 bytes per pixel: red, green, blue, alpha.
+    Rgba8,
+    /// 4 bytes per pixel: blue, green, red, alpha.
+    Bgra8,
+    /// 2 bytes per pixel, little-endian, with 5 bits of red at the top.
+    Rgb565,
+    /// 1 byte per pixel, holding the shade (0 is White, 3 is Black). Palettes are not applied.
+    Shade,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgba8 | PixelFormat::Bgra8 => 4,
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Shade => 1,
+        }
+    }
+}
+
+/// Encodes the screen in `format`. `layers` tells which palette each pixel was shaded with.
+pub fn encode(
+    screen: &[Color],
+    layers: &[Layer],
+    palettes: &DisplayPalettes,
+    format: PixelFormat,
+) -> Vec<u8> {
+    debug_assert_eq!(screen.len(), layers.len());
+    let mut bytes = Vec::with_capacity(screen.len() * format.bytes_per_pixel());
+    for (&color, &layer) in screen.iter().zip(layers) {
+        match format {
+            PixelFormat::Shade => bytes.push(color as u8),
+            _ => push_pixel(&mut bytes, palettes.for_layer(layer).pixel(color), format),
+        }
+    }
+    bytes
+}
+
+/// Encodes pixels that were already shaded, e.g. by `filter`. They have no shade anymore, so
+/// `PixelFormat::Shade` is not supported.
+pub fn encode_pixels(pixels: &[Pixel], format: PixelFormat) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(pixels.len() * format.bytes_per_pixel());
+    for &pixel in pixels {
+        push_pixel(&mut bytes, pixel, format);
+    }
+    bytes
+}
+
+fn push_pixel(bytes: &mut Vec<u8>, pixel: Pixel, format: PixelFormat) {
+    match format {
+        PixelFormat::Rgba8 => bytes.extend_from_slice(&[pixel.r, pixel.g, pixel.b, pixel.a]),
+        PixelFormat::Bgra8 => bytes.extend_from_slice(&[pixel.b, pixel.g, pixel.r, pixel.a]),
+        PixelFormat::Rgb565 => {
+            let rgb = (u16::from(pixel.r) >> 3) << 11
+                | (u16::from(pixel.g) >> 2) << 5
+                | u16::from(pixel.b) >> 3;
+            bytes.extend_from_slice(&rgb.to_le_bytes());
+        }
+        PixelFormat::Shade => panic!("Shaded pixels have no shade to encode."),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parses_palette_files() {
+        let palettes =
+            DisplayPalettes::from_text("#9BBC0F 8BAC0F, 306230\n0f380f ; comment").unwrap();
+        assert_eq!(palettes, DisplayPalettes::uniform(Palette::DMG_GREEN));
+        let text = "; BG\nFFFFFF C0C0C0 606060 000000\n; OBJ0\n9BBC0F 8BAC0F 306230 0F380F\n\
+                    ; OBJ1\nC4CFA1 8B956D 4D533C 1F1F1F";
+        let palettes = DisplayPalettes::from_text(text).unwrap();
+        assert_eq!(palettes.bg, Palette::GREY);
+        assert_eq!(palettes.obj0, Palette::DMG_GREEN);
+        assert_eq!(palettes.obj1, Palette::POCKET);
+    }
+
+    #[test]
+    fn test_rejects_bad_palette_files() {
+        assert!(DisplayPalettes::from_text("FFFFFF C0C0C0 606060").is_err());
+        assert!(DisplayPalettes::from_text("FFFFFF C0C0C0 606060 00000G").is_err());
+        assert!(DisplayPalettes::from_text("FFFFFF C0C0C0 606060 0000000").is_err());
+    }
+
+    #[test]
+    fn test_encodes_pixel_formats() {
+        let screen = [Color::White, Color::Black, Color::DarkGray];
+        let layers = [Layer::Bg, Layer::Obj0, Layer::Obj1];
+        let palettes =
+            DisplayPalettes { bg: Palette::GREY, obj0: Palette::DMG_GREEN, obj1: Palette::POCKET };
+        let encode = |format| encode(&screen, &layers, &palettes, format);
+        assert_eq!(
+            encode(PixelFormat::Rgba8),
+            [255, 255, 255, 255, 0x0F, 0x38, 0x0F, 255, 0x4D, 0x53, 0x3C, 255]
+        );
+        assert_eq!(encode(PixelFormat::Bgra8)[4..8], [0x0F, 0x38, 0x0F, 255]);
+        assert_eq!(encode(PixelFormat::Rgb565), [0xFF, 0xFF, 0xC1, 0x09, 0x87, 0x4A]);
+        assert_eq!(encode(PixelFormat::Shade), [0, 3, 2]);
+        let pixels = [Palette::DMG_GREEN.pixel(Color::Black)];
+        assert_eq!(encode_pixels(&pixels, PixelFormat::Rgba8), [0x0F, 0x38, 0x0F, 255]);
+    }
+}
@@ -0,0 +1,240 @@
+//! Records what the PPU saw on every scanline: the registers at the start of mode 3, every write to
+//! them, the mode changes and the sprites selected during the OAM scan. Dots count from the start
+//! of the line, like `InternalState::counter`. The log can be exported as JSON to diff two runs, or
+//! to compare with another emulator.
+
+use std::fmt::Write as _;
+
+use super::registers::LcdMode;
+use crate::io_registers::Addresses;
+
+/// The registers that affect rendering.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Register {
+    Lcdc,
+    Stat,
+    Scx,
+    Scy,
+    Wx,
+    Wy,
+    Bgp,
+    Obp0,
+    Obp1,
+}
+
+impl Register {
+    pub fn from_address(address: i32) -> Option<Register> {
+        use num_traits::FromPrimitive;
+        match Addresses::from_i32(address)? {
+            Addresses::LcdControl => Some(Register::Lcdc),
+            Addresses::LcdStatus => Some(Register::Stat),
+            Addresses::ScrollX => Some(Register::Scx),
+            Addresses::ScrollY => Some(Register::Scy),
+            Addresses::WindowXPos => Some(Register::Wx),
+            Addresses::WindowYPos => Some(Register::Wy),
+            Addresses::BgPalette => Some(Register::Bgp),
+            Addresses::SpritePalette0 => Some(Register::Obp0),
+            Addresses::SpritePalette1 => Some(Register::Obp1),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Register::Lcdc => "LCDC",
+            Register::Stat => "STAT",
+            Register::Scx => "SCX",
+            Register::Scy => "SCY",
+            Register::Wx => "WX",
+            Register::Wy => "WY",
+            Register::Bgp => "BGP",
+            Register::Obp0 => "OBP0",
+            Register::Obp1 => "OBP1",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RegisterValues {
+    pub lcdc: u8,
+    pub stat: u8,
+    pub scx: u8,
+    pub scy: u8,
+    pub wx: u8,
+    pub wy: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RegisterWrite {
+    pub dot: i32,
+    pub register: Register,
+    pub value: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ModeChange {
+    pub dot: i32,
+    pub mode: LcdMode,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Scanline {
+    /// Counts from the frame the log was started in.
+    pub frame: u64,
+    pub line: i32,
+    /// None if mode 3 never started, e.g. in VBlank.
+    pub mode3_registers: Option<RegisterValues>,
+    pub writes: Vec<RegisterWrite>,
+    pub mode_changes: Vec<ModeChange>,
+    /// The OAM indices of the sprites selected for this line, in drawing order.
+    pub sprites: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScanlineLog {
+    pub scanlines: Vec<Scanline>,
+    frame: u64,
+    last_mode: Option<LcdMode>,
+}
+
+impl ScanlineLog {
+    pub fn new() -> ScanlineLog {
+        ScanlineLog::default()
+    }
+
+    /// The entry for `line`, starting a new one if the PPU moved on to another line.
+    fn line_mut(&mut self, line: i32) -> &mut Scanline {
+        match self.scanlines.last() {
+            Some(last) if last.line == line => (),
+            last => {
+                if last.map_or(false, |x| line < x.line) {
+                    self.frame += 1;
+                }
+                self.scanlines.push(Scanline { frame: self.frame, line, ..Scanline::default() });
+            }
+        }
+        self.scanlines.last_mut().unwrap()
+    }
+
+    pub(super) fn record_write(&mut self, line: i32, dot: i32, register: Register, value: u8) {
+        self.line_mut(line).writes.push(RegisterWrite { dot, register, value });
+    }
+
+    /// Called on every dot, so that every line gets an entry. Logs `mode` if it changed, and keeps
+    /// `registers` if mode 3 just started.
+    pub(super) fn record_mode(
+        &mut self,
+        line: i32,
+        dot: i32,
+        mode: LcdMode,
+        registers: RegisterValues,
+    ) {
+        let is_new_mode = self.last_mode != Some(mode);
+        self.last_mode = Some(mode);
+        let scanline = self.line_mut(line);
+        if is_new_mode {
+            scanline.mode_changes.push(ModeChange { dot, mode });
+            if mode == LcdMode::TransferringToLcd {
+                scanline.mode3_registers = Some(registers);
+            }
+        }
+    }
+
+    pub(super) fn record_sprites(&mut self, line: i32, sprites: &[u8]) {
+        self.line_mut(line).sprites = sprites.to_vec();
+    }
+
+    /// One object per scanline, in the order they were drawn.
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{\"scanlines\": [");
+        for (i, scanline) in self.scanlines.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(json, "\n  {{\"frame\": {}, \"line\": {}", scanline.frame, scanline.line)
+                .unwrap();
+            json.push_str(", \"registers\": ");
+            match &scanline.mode3_registers {
+                Some(x) => write!(
+                    json,
+                    "{{\"LCDC\": {}, \"STAT\": {}, \"SCX\": {}, \"SCY\": {}, \"WX\": {}, \"WY\": {}, \
+                     \"BGP\": {}, \"OBP0\": {}, \"OBP1\": {}}}",
+                    x.lcdc, x.stat, x.scx, x.scy, x.wx, x.wy, x.bgp, x.obp0, x.obp1
+                )
+                .unwrap(),
+                None => json.push_str("null"),
+            }
+            let writes = scanline.writes.iter().map(|x| {
+                format!(
+                    "{{\"dot\": {}, \"register\": \"{}\", \"value\": {}}}",
+                    x.dot,
+                    x.register.name(),
+                    x.value
+                )
+            });
+            write!(json, ", \"writes\": [{}]", writes.collect::<Vec<_>>().join(", ")).unwrap();
+            let modes = scanline
+                .mode_changes
+                .iter()
+                .map(|x| format!("{{\"dot\": {}, \"mode\": \"{:?}\"}}", x.dot, x.mode));
+            write!(json, ", \"modes\": [{}]", modes.collect::<Vec<_>>().join(", ")).unwrap();
+            let sprites = scanline.sprites.iter().map(ToString::to_string);
+            write!(json, ", \"sprites\": [{}]}}", sprites.collect::<Vec<_>>().join(", ")).unwrap();
+        }
+        json.push_str("\n]}\n");
+        json
+    }
+
+    pub fn save_json(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_json())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_splits_lines_and_frames() {
+        let mut log = ScanlineLog::new();
+        let registers = RegisterValues { scx: 3, ..RegisterValues::default() };
+        log.record_mode(152, 0, LcdMode::VBlank, registers);
+        log.record_mode(153, 4, LcdMode::VBlank, registers);
+        log.record_mode(0, 4, LcdMode::ReadingOAM, registers);
+        log.record_sprites(0, &[2, 7]);
+        log.record_mode(0, 84, LcdMode::TransferringToLcd, registers);
+        log.record_write(0, 100, Register::Scx, 5);
+        log.record_mode(0, 260, LcdMode::HBlank, registers);
+
+        let lines: Vec<_> = log.scanlines.iter().map(|x| (x.frame, x.line)).collect();
+        assert_eq!(lines, [(0, 152), (0, 153), (1, 0)]);
+        // Still in VBlank.
+        assert!(log.scanlines[1].mode_changes.is_empty());
+        let line = &log.scanlines[2];
+        assert_eq!(line.mode3_registers, Some(registers));
+        assert_eq!(line.sprites, [2, 7]);
+        assert_eq!(line.writes, [RegisterWrite { dot: 100, register: Register::Scx, value: 5 }]);
+        assert_eq!(line.mode_changes.len(), 3);
+        assert_eq!(log.scanlines[0].mode3_registers, None);
+    }
+
+    #[test]
+    fn test_exports_json() {
+        let mut log = ScanlineLog::new();
+        log.record_mode(10, 84, LcdMode::TransferringToLcd, RegisterValues::default());
+        log.record_write(10, 90, Register::Bgp, 0xE4);
+        log.record_mode(11, 0, LcdMode::HBlank, RegisterValues::default());
+        assert_eq!(
+            log.to_json(),
+            "{\"scanlines\": [\n  {\"frame\": 0, \"line\": 10, \"registers\": {\"LCDC\": 0, \
+             \"STAT\": 0, \"SCX\": 0, \"SCY\": 0, \"WX\": 0, \"WY\": 0, \"BGP\": 0, \"OBP0\": 0, \
+             \"OBP1\": 0}, \"writes\": [{\"dot\": 90, \"register\": \"BGP\", \"value\": 228}], \
+             \"modes\": [{\"dot\": 84, \"mode\": \"TransferringToLcd\"}], \"sprites\": []},\n  \
+             {\"frame\": 0, \"line\": 11, \"registers\": null, \"writes\": [], \"modes\": \
+             [{\"dot\": 0, \"mode\": \"HBlank\"}], \"sprites\": []}\n]}\n"
+        );
+    }
+}
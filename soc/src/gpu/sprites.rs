@@ -81,13 +81,31 @@ pub fn get_visible_sprite(
     let sprite_location = |x| &oam[(x * 4) as usize..];
     for (i, sprite_index) in visible_sprites.iter().enumerate() {
         let sprite = SpriteEntry::from_slice(sprite_location(sprite_index));
-        if !fetched_sprites[i] && sprite.left() <= x && sprite.right() > x {
+        // Sprites at X=0 are fully offscreen, but still fetched at the start of the line.
+        if !fetched_sprites[i] && sprite.left() <= x && (sprite.right() > x || x == 0) {
             return Some(i);
         }
     }
     None
 }
 
+/// The dots a sprite stalls mode 3 for. Fetching the sprite takes 6, but first the BG fetch of the
+/// tile under the sprite's leftmost pixel must finish, which takes up to 5 more. Only the first
+/// sprite over a given tile waits on it, and a sprite at X=0 always waits the full 5.
+/// `penalized_tiles` tracks the tiles already waited on during the line.
+pub fn fetch_penalty(sprite: SpriteEntry, fine_scroll_x: i32, penalized_tiles: &mut u32) -> i32 {
+    const FETCH_DOTS: i32 = 6;
+    // Counts tiles from the one left of the screen, so that it is never negative.
+    let x = sprite.pos_x() as i32 + fine_scroll_x;
+    let tile_bit = 1 << (x / 8);
+    if *penalized_tiles & tile_bit != 0 {
+        return FETCH_DOTS;
+    }
+    *penalized_tiles |= tile_bit;
+    let bg_wait = if sprite.pos_x() == 0 { 5 } else { 5 - (x % 8).min(5) };
+    FETCH_DOTS + bg_wait
+}
+
 pub fn pixels_behind(x: i32, sprite: SpriteEntry) -> usize {
     if sprite.left() < x {
         (x - sprite.left()) as usize
@@ -95,3 +113,29 @@ pub fn pixels_behind(x: i32, sprite: SpriteEntry) -> usize {
         0
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fetch_penalty() {
+        let sprite = |x| SpriteEntry::from_slice(&[16, x, 0, 0]);
+        let penalty = |x, scx| fetch_penalty(sprite(x), scx, &mut 0);
+        assert_eq!(penalty(0, 0), 11);
+        assert_eq!(penalty(1, 0), 10);
+        assert_eq!(penalty(4, 0), 7);
+        assert_eq!(penalty(8, 0), 11);
+        assert_eq!(penalty(7, 1), 11);
+        assert_eq!(penalty(3, 3), 6);
+
+        // Only the first sprite over a tile waits on its fetch.
+        let mut tiles = 0;
+        assert_eq!(fetch_penalty(sprite(4), 0, &mut tiles), 7);
+        assert_eq!(fetch_penalty(sprite(4), 0, &mut tiles), 6);
+        assert_eq!(fetch_penalty(sprite(12), 0, &mut tiles), 7);
+        let mut tiles = 0;
+        assert_eq!(fetch_penalty(sprite(0), 0, &mut tiles), 11);
+        assert_eq!(fetch_penalty(sprite(0), 0, &mut tiles), 6);
+    }
+}
@@ -1,5 +1,10 @@
 mod test_bg;
+mod test_locks;
+mod test_mode3;
+mod test_oam_bug;
 mod test_sprites;
+mod test_stat;
+mod test_unused_oam;
 mod test_window;
 
 use std::path::Path;
@@ -13,6 +18,24 @@ use crate::test::*;
 
 pub type ImageFn = Box<dyn Fn(usize, usize) -> Color>;
 
+/// Turns the LCD on, and runs until the CPU is about to fetch an instruction while the PPU scans
+/// OAM on `line`.
+pub fn run_until_oam_scan(mut context: TestContext, line: i32) -> TestContext {
+    let mut lcdc = LcdControl(0);
+    lcdc.set_enable_display(true);
+    context.system.memory_write(io_registers::Addresses::LcdControl as i32, lcdc.0);
+    let mut context = context.wait_for_vsync();
+    let is_scanning = |system: &System| {
+        let mode = system.memory_read(io_registers::Addresses::LcdStatus as i32) & 0x3;
+        let ly = system.memory_read(io_registers::Addresses::LcdY as i32);
+        ly == line && mode == LcdMode::ReadingOAM as i32 && system.is_fetching()
+    };
+    while !is_scanning(&context.system) {
+        context.system.execute_machine_cycle().unwrap();
+    }
+    context
+}
+
 #[allow(non_snake_case)]
 pub fn IDENTITY_TRANSFORM(i: usize, j: usize) -> (usize, usize) {
     (i, j)
@@ -267,15 +290,7 @@ pub fn build_golden(
 
 /// Tests the current system screen vs a golden image.
 pub fn compare_with_golden(test_name: &str, system: &System, golden: &[gpu::Color]) {
-    if system.screen() != golden {
-        dump_system_image(Path::new("./failed_tests/gpu"), test_name, system);
-        dump_image(
-            Path::new("./failed_tests/gpu"),
-            format!("{}_golden", test_name).as_str(),
-            golden,
-        );
-        panic!("{} failed.", test_name);
-    }
+    compare_images(Path::new("./failed_tests/gpu"), test_name, system.screen(), golden);
 }
 
 fn color_to_row(color: Color) -> (u8, u8) {
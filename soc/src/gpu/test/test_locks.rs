@@ -0,0 +1,14 @@
+//! CPU access to OAM while the PPU uses it.
+
+use super::*;
+
+use micro_code::register::Register;
+
+#[test]
+fn test_oam_blocked_during_mode_2() {
+    // Line 1 starts right after the HBlank of line 0.
+    run_until_oam_scan(with_dynamic_cart().set_mem_8bit(0xFE00, 0x12), 1)
+        .set_reg(Register::HL, 0xFE00)
+        .execute_instructions(&[LD_A_HL])
+        .assert_reg_eq(Register::A, 0xFF);
+}
@@ -0,0 +1,77 @@
+//! Register writes in the middle of mode 3, which take effect from the next pixels on.
+
+use super::*;
+
+use num_traits::FromPrimitive as _;
+
+const LINE: usize = 10;
+/// Roughly where the writes land. The fetcher runs up to two tiles ahead of the pixels drawn.
+const WRITE_X: i32 = 80;
+
+/// Vertical stripes, one shade per tile.
+fn stripes(i: usize, _: usize) -> Color {
+    Color::from_usize((i / 8) % 4).unwrap()
+}
+
+/// Draws a frame, runs until `WRITE_X` pixels of `LINE` are drawn, then writes `value` to
+/// `address`. Returns `LINE` once the frame is done, and the first pixel drawn after the write.
+fn write_mid_line(
+    builder: ImageBuilder,
+    address: io_registers::Addresses,
+    value: i32,
+) -> (Vec<Color>, usize) {
+    let mut context = builder.as_test().wait_for_vsync();
+    let is_mid_line = |system: &System| {
+        let state = &system.gpu().state;
+        state.current_y == LINE as i32
+            && state.pixels_pushed >= WRITE_X
+            && state.pixels_pushed < LCD_WIDTH as i32
+    };
+    while !is_mid_line(&context.system) {
+        context.system.execute_machine_cycle().unwrap();
+    }
+    let x = context.system.gpu().state.pixels_pushed as usize;
+    context.system.memory_write(address as i32, value);
+    let context = context.wait_for_vsync();
+    let screen = context.system.screen();
+    (screen[LINE * LCD_WIDTH..(LINE + 1) * LCD_WIDTH].to_vec(), x)
+}
+
+/// Asserts that the pixels of `line` from `start` to `end` match `image_fn`.
+fn assert_pixels(line: &[Color], start: usize, end: usize, image_fn: impl Fn(usize) -> Color) {
+    for (i, &color) in line.iter().enumerate().take(end).skip(start) {
+        assert!(color == image_fn(i), "Pixel {}", i);
+    }
+}
+
+#[test]
+fn test_scx_change() {
+    let builder = ImageBuilder::new().build_default_bg(Box::new(stripes));
+    let (line, x) = write_mid_line(builder, io_registers::Addresses::ScrollX, 8);
+    // Tiles already fetched keep the old scroll.
+    assert_pixels(&line, 0, x, |i| stripes(i, LINE));
+    assert_pixels(&line, x + 16, LCD_WIDTH, |i| stripes(i + 8, LINE));
+}
+
+#[test]
+fn test_bgp_change() {
+    let builder = ImageBuilder::new().build_default_bg(Box::new(stripes));
+    let (line, x) = write_mid_line(builder, io_registers::Addresses::BgPalette, 0b00_01_10_11);
+    assert_pixels(&line, 0, x, |i| stripes(i, LINE));
+    // Drawn with the old and new palettes OR'ed together, which is all black.
+    assert!(line[x] == Color::Black);
+    assert_pixels(&line, x + 1, LCD_WIDTH, |i| {
+        Color::from_usize(3 - stripes(i, LINE) as usize).unwrap()
+    });
+}
+
+#[test]
+fn test_bg_disable() {
+    let builder = ImageBuilder::new().build_default_bg(Box::new(stripes));
+    let mut lcdc = LcdControl(0);
+    lcdc.set_enable_display(true);
+    let (line, x) = write_mid_line(builder, io_registers::Addresses::LcdControl, lcdc.0);
+    assert_pixels(&line, 0, x, |i| stripes(i, LINE));
+    // The rest of the line is BG color 0, rather than left over from the previous frame.
+    assert_pixels(&line, x, LCD_WIDTH, |_| Color::White);
+}
@@ -0,0 +1,30 @@
+//! The OAM corruption bug, see `gpu::oam_bug`.
+
+use super::*;
+
+use micro_code::register::Register;
+
+const INC_HL: u8 = 0x23;
+
+fn numbered_oam() -> Vec<u8> {
+    (0..160).map(|x| x as u8).collect()
+}
+
+/// Fills OAM, turns the LCD on, then runs `INC HL` a few times from the start of an OAM scan.
+/// Returns OAM.
+fn inc_hl_during_scan(hl: i32) -> Vec<u8> {
+    let context = with_dynamic_cart().set_mem_range(0xFE00, &numbered_oam());
+    let context =
+        run_until_oam_scan(context, 0).set_reg(Register::HL, hl).execute_instructions(&[INC_HL; 8]);
+    context.system.gpu().oam_contents().to_vec()
+}
+
+#[test]
+fn test_inc_in_oam_corrupts() {
+    assert_ne!(inc_hl_during_scan(0xFE00), numbered_oam());
+}
+
+#[test]
+fn test_inc_outside_oam_does_not_corrupt() {
+    assert_eq!(inc_hl_during_scan(0xFD00), numbered_oam());
+}
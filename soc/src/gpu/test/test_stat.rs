@@ -0,0 +1,40 @@
+//! The STAT interrupt line.
+
+use super::*;
+
+use micro_code::register::Register;
+
+/// Runs until the HBlank of line 0, with the STAT interrupt enabled for LY=LYC and HBlank, and
+/// returns whether entering HBlank fired it.
+fn hblank_fires_on_line_0(lyc: i32) -> bool {
+    let mut context = with_dynamic_cart();
+    let mut lcdc = LcdControl(0);
+    lcdc.set_enable_display(true);
+    context.system.memory_write(io_registers::Addresses::LcdControl as i32, lcdc.0);
+    context.system.memory_write(io_registers::Addresses::LcdStatus as i32, 0x48);
+    // LYC is only seen by the PPU through CPU writes.
+    let mut context =
+        context.wait_for_vsync().set_reg(Register::A, lyc).execute_instructions(&[LD_FF_A, 0x45]);
+    let mode =
+        |system: &System| system.memory_read(io_registers::Addresses::LcdStatus as i32) & 0x3;
+    let ly = |system: &System| system.memory_read(io_registers::Addresses::LcdY as i32);
+    while !(ly(&context.system) == 0 && mode(&context.system) == LcdMode::ReadingOAM as i32) {
+        context.system.execute_machine_cycle().unwrap();
+    }
+    context.system.memory_write(io_registers::Addresses::InterruptFired as i32, 0);
+    while mode(&context.system) != LcdMode::HBlank as i32 {
+        context.system.execute_machine_cycle().unwrap();
+    }
+    for _ in 0..4 {
+        context.system.execute_machine_cycle().unwrap();
+    }
+    (context.system.memory_read(io_registers::Addresses::InterruptFired as i32) & 0x2) != 0
+}
+
+#[test]
+fn test_lyc_holds_stat_line_on_line_0() {
+    // LY=LYC=0 keeps the line high through line 0 (as the STAT coincidence bit shows), so HBlank
+    // can't raise it again.
+    assert!(!hblank_fires_on_line_0(0));
+    assert!(hblank_fires_on_line_0(1));
+}
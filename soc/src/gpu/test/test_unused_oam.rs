@@ -0,0 +1,14 @@
+//! Reads of 0xFEA0-0xFEFF, which is not backed by any memory.
+
+use super::*;
+
+use micro_code::register::Register;
+
+#[test]
+fn test_unused_oam_reads_ff_while_blocked() {
+    run_until_oam_scan(with_dynamic_cart(), 0)
+        .set_reg(Register::A, 0x12)
+        .set_reg(Register::HL, 0xFEA0)
+        .execute_instructions(&[LD_A_HL])
+        .assert_reg_eq(Register::A, 0xFF);
+}
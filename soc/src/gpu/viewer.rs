@@ -0,0 +1,399 @@
+//! Debug views of VRAM and OAM: the tile sheet, the two tile maps and the sprite attributes. These
+//! are pure functions of the memory contents (see `Gpu::vram_contents`, `Gpu::oam_contents` and
+//! `Gpu::viewer_registers`), so they can be used on any snapshot.
+
+use num_traits::FromPrimitive;
+
+use super::palette::{DisplayPalettes, Palette};
+use super::sprites::SpriteEntry;
+use super::{Color, Pixel};
+
+/// Tiles in VRAM: 0x8000-0x97FF.
+pub const NUM_TILES: usize = 384;
+pub const NUM_OBJECTS: usize = 40;
+/// Tile maps are 32x32 tiles.
+pub const MAP_SIZE: usize = 256;
+const TILES_PER_ROW: usize = 16;
+const MAPS_OFFSET: usize = 0x1800;
+
+/// Outlines the part of a tile map the background viewport shows.
+const VIEWPORT_COLOR: Pixel = Pixel::new(255, 0, 0);
+/// Outlines the part of a tile map the window shows.
+const WINDOW_COLOR: Pixel = Pixel::new(0, 0, 255);
+/// Stands in for the transparent pixels of sprites.
+const TRANSPARENT_COLOR: Pixel = Pixel::new(255, 0, 255);
+/// Separates the sprites in the object sheet.
+const GRID_COLOR: Pixel = Pixel::new(64, 64, 64);
+
+/// The PPU registers the views depend on.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Registers {
+    /// LCDC bit 4: tile data at 0x8000 (unsigned indices) rather than 0x8800 (signed).
+    pub unsigned_tile_data: bool,
+    /// LCDC bit 3.
+    pub bg_map: usize,
+    /// LCDC bit 5.
+    pub enable_window: bool,
+    /// LCDC bit 6.
+    pub window_map: usize,
+    /// LCDC bit 2: 8x16 sprites.
+    pub large_sprites: bool,
+    pub bg_palette: u8,
+    pub obj_palette_0: u8,
+    pub obj_palette_1: u8,
+    pub scroll_x: u8,
+    pub scroll_y: u8,
+    pub window_x: u8,
+    pub window_y: u8,
+}
+
+/// A BGRA image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Pixel>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, fill: Pixel) -> Image {
+        Image { width, height, pixels: vec![fill; width * height] }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Pixel {
+        self.pixels[y * self.width + x]
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, pixel: Pixel) {
+        self.pixels[y * self.width + x] = pixel;
+    }
+
+    /// Places `other` to the right of this image, `gap` pixels apart.
+    pub fn beside(&self, other: &Image, gap: usize, fill: Pixel) -> Image {
+        let mut image =
+            Image::new(self.width + gap + other.width, self.height.max(other.height), fill);
+        image.blit(self, 0, 0);
+        image.blit(other, self.width + gap, 0);
+        image
+    }
+
+    fn blit(&mut self, other: &Image, x: usize, y: usize) {
+        for row in 0..other.height {
+            let start = (y + row) * self.width + x;
+            self.pixels[start..start + other.width]
+                .copy_from_slice(&other.pixels[row * other.width..(row + 1) * other.width]);
+        }
+    }
+
+    /// Draws a rectangle outline that wraps around the edges, like the viewport does on the maps.
+    fn outline(&mut self, x: usize, y: usize, width: usize, height: usize, color: Pixel) {
+        for i in 0..width {
+            let px = (x + i) % self.width;
+            self.set_pixel(px, y % self.height, color);
+            self.set_pixel(px, (y + height - 1) % self.height, color);
+        }
+        for i in 0..height {
+            let py = (y + i) % self.height;
+            self.set_pixel(x % self.width, py, color);
+            self.set_pixel((x + width - 1) % self.width, py, color);
+        }
+    }
+
+    pub fn bgra8(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.pixels.len() * 4);
+        for pixel in &self.pixels {
+            bytes.extend_from_slice(&[pixel.b, pixel.g, pixel.r, pixel.a]);
+        }
+        bytes
+    }
+
+    pub fn rgba8(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.pixels.len() * 4);
+        for pixel in &self.pixels {
+            bytes.extend_from_slice(&[pixel.r, pixel.g, pixel.b, pixel.a]);
+        }
+        bytes
+    }
+
+    pub fn to_png(&self) -> Vec<u8> {
+        crate::png::encode_rgba(self.width, self.height, &self.rgba8())
+    }
+
+    pub fn save_png(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_png())
+    }
+}
+
+/// A decoded OAM entry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Object {
+    pub index: usize,
+    /// Screen coordinates of the top-left corner. Offscreen objects have negative or large ones.
+    pub x: i32,
+    pub y: i32,
+    pub tile: u8,
+    /// 0 for OBP0, 1 for OBP1.
+    pub palette: u8,
+    pub flip_x: bool,
+    pub flip_y: bool,
+    /// Only drawn over background color 0.
+    pub behind_bg: bool,
+}
+
+impl Object {
+    fn from_entry(index: usize, entry: SpriteEntry) -> Object {
+        Object {
+            index,
+            x: entry.left(),
+            y: entry.top(),
+            tile: entry.tile_index(),
+            palette: entry.palette(),
+            flip_x: entry.flip_x(),
+            flip_y: entry.flip_y(),
+            behind_bg: entry.priority() != 0,
+        }
+    }
+}
+
+impl std::fmt::Display for Object {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "#{:02} x={:4} y={:4} tile={:02X} OBP{}",
+            self.index, self.x, self.y, self.tile, self.palette
+        )?;
+        if self.flip_x {
+            write!(f, " flip_x")?;
+        }
+        if self.flip_y {
+            write!(f, " flip_y")?;
+        }
+        if self.behind_bg {
+            write!(f, " behind_bg")?;
+        }
+        Ok(())
+    }
+}
+
+/// Maps a 2-bit color index through a palette register.
+fn shade(palette_register: u8, index: u8) -> Color {
+    Color::from_u8((palette_register >> (index * 2)) & 0x3).unwrap()
+}
+
+/// The color indices of a row of a tile, left to right. `tile` counts from 0x8000.
+fn tile_row(vram: &[u8], tile: usize, row: usize) -> [u8; 8] {
+    let low = vram[tile * 16 + row * 2];
+    let high = vram[tile * 16 + row * 2 + 1];
+    let mut indices = [0; 8];
+    for (x, index) in indices.iter_mut().enumerate() {
+        let bit = 7 - x;
+        *index = ((low >> bit) & 1) | (((high >> bit) & 1) << 1);
+    }
+    indices
+}
+
+/// All 384 tiles, 16 per row, shaded through `bg_palette`. Pass 0xE4 to see the raw indices.
+pub fn tile_sheet(vram: &[u8], bg_palette: u8, palette: &Palette) -> Image {
+    let rows = NUM_TILES / TILES_PER_ROW;
+    let mut image = Image::new(TILES_PER_ROW * 8, rows * 8, palette.pixel(Color::White));
+    for tile in 0..NUM_TILES {
+        let (tile_x, tile_y) = (tile % TILES_PER_ROW * 8, tile / TILES_PER_ROW * 8);
+        for y in 0..8 {
+            for (x, &index) in tile_row(vram, tile, y).iter().enumerate() {
+                image.set_pixel(tile_x + x, tile_y + y, palette.pixel(shade(bg_palette, index)));
+            }
+        }
+    }
+    image
+}
+
+/// The 256x256 background map `map` (0 at 0x9800, 1 at 0x9C00), using the current tile data and
+/// BGP. The area the viewport shows is outlined if the background uses this map, and so is the
+/// area the window shows if it is enabled and uses it.
+pub fn tile_map(vram: &[u8], registers: &Registers, map: usize, palette: &Palette) -> Image {
+    debug_assert!(map < 2);
+    let mut image = Image::new(MAP_SIZE, MAP_SIZE, palette.pixel(Color::White));
+    let map_base = MAPS_OFFSET + map * 0x400;
+    for (i, &index) in vram[map_base..map_base + 32 * 32].iter().enumerate() {
+        let tile = if registers.unsigned_tile_data {
+            index as usize
+        } else {
+            (256 + (index as i8) as i32) as usize
+        };
+        let (tile_x, tile_y) = (i % 32 * 8, i / 32 * 8);
+        for y in 0..8 {
+            for (x, &color) in tile_row(vram, tile, y).iter().enumerate() {
+                let pixel = palette.pixel(shade(registers.bg_palette, color));
+                image.set_pixel(tile_x + x, tile_y + y, pixel);
+            }
+        }
+    }
+
+    if registers.bg_map == map {
+        let (x, y) = (registers.scroll_x as usize, registers.scroll_y as usize);
+        image.outline(x, y, super::LCD_WIDTH, super::LCD_HEIGHT, VIEWPORT_COLOR);
+    }
+    let (window_x, window_y) = (registers.window_x as usize, registers.window_y as usize);
+    if registers.enable_window
+        && registers.window_map == map
+        && window_x <= 166
+        && window_y < super::LCD_HEIGHT
+    {
+        // The window always starts at the top-left of its map.
+        let width = (super::LCD_WIDTH + 7 - window_x).min(super::LCD_WIDTH);
+        image.outline(0, 0, width, super::LCD_HEIGHT - window_y, WINDOW_COLOR);
+    }
+    image
+}
+
+/// The 40 OAM entries, in OAM order.
+pub fn objects(oam: &[u8]) -> Vec<Object> {
+    (0..NUM_OBJECTS)
+        .map(|i| Object::from_entry(i, SpriteEntry::from_slice(&oam[i * 4..])))
+        .collect()
+}
+
+/// The 40 sprites in OAM order, 8 per row, as they would be drawn: flipped, 8x8 or 8x16, and
+/// shaded through their palette register. Transparent pixels are magenta.
+pub fn object_sheet(
+    vram: &[u8],
+    oam: &[u8],
+    registers: &Registers,
+    palettes: &DisplayPalettes,
+) -> Image {
+    const PER_ROW: usize = 8;
+    // Every sprite gets an 8x16 cell with a 1 pixel border.
+    let (cell_width, cell_height) = (8 + 1, 16 + 1);
+    let rows = NUM_OBJECTS / PER_ROW;
+    let mut image = Image::new(PER_ROW * cell_width + 1, rows * cell_height + 1, GRID_COLOR);
+    let height = if registers.large_sprites { 16 } else { 8 };
+    for object in objects(oam) {
+        let (cell_x, cell_y) =
+            (object.index % PER_ROW * cell_width + 1, object.index / PER_ROW * cell_height + 1);
+        let (register, palette) = if object.palette == 0 {
+            (registers.obj_palette_0, &palettes.obj0)
+        } else {
+            (registers.obj_palette_1, &palettes.obj1)
+        };
+        // 8x16 sprites ignore bit 0 of the tile index.
+        let first_tile = if registers.large_sprites { object.tile & 0xFE } else { object.tile };
+        for y in 0..16 {
+            for x in 0..8 {
+                image.set_pixel(cell_x + x, cell_y + y, TRANSPARENT_COLOR);
+            }
+        }
+        for y in 0..height {
+            let row = if object.flip_y { height - 1 - y } else { y };
+            let indices = tile_row(vram, first_tile as usize + row / 8, row % 8);
+            for x in 0..8 {
+                let index = indices[if object.flip_x { 7 - x } else { x }];
+                if index != 0 {
+                    image.set_pixel(cell_x + x, cell_y + y, palette.pixel(shade(register, index)));
+                }
+            }
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const WHITE: Pixel = Pixel::new(255, 255, 255);
+    const BLACK: Pixel = Pixel::new(0, 0, 0);
+    const LIGHT: Pixel = Pixel::new(192, 192, 192);
+
+    /// A tile with a black top row and a light grey left column.
+    fn write_test_tile(vram: &mut [u8], tile: usize) {
+        vram[tile * 16] = 0xFF;
+        vram[tile * 16 + 1] = 0xFF;
+        for row in 1..8 {
+            vram[tile * 16 + row * 2] = 0x80;
+        }
+    }
+
+    #[test]
+    fn test_tile_sheet() {
+        let mut vram = vec![0; 0x2000];
+        write_test_tile(&mut vram, 17);
+        let image = tile_sheet(&vram, 0xE4, &Palette::GREY);
+        assert_eq!((image.width, image.height), (128, 192));
+        // Tile 17 is the second tile of the second row.
+        assert_eq!(image.pixel(8, 8), BLACK);
+        assert_eq!(image.pixel(15, 8), BLACK);
+        assert_eq!(image.pixel(8, 9), LIGHT);
+        assert_eq!(image.pixel(9, 9), WHITE);
+        // Inverted BGP.
+        let image = tile_sheet(&vram, 0x1B, &Palette::GREY);
+        assert_eq!(image.pixel(8, 8), WHITE);
+        assert_eq!(image.pixel(9, 9), BLACK);
+    }
+
+    #[test]
+    fn test_tile_map_addressing_and_overlays() {
+        let mut vram = vec![0; 0x2000];
+        write_test_tile(&mut vram, 0x80);
+        // Tile 1 of map 1 uses index 0x80.
+        vram[MAPS_OFFSET + 0x400 + 1] = 0x80;
+        let mut registers = Registers {
+            unsigned_tile_data: true,
+            bg_map: 1,
+            bg_palette: 0xE4,
+            scroll_x: 250,
+            scroll_y: 200,
+            ..Registers::default()
+        };
+        let image = tile_map(&vram, &registers, 1, &Palette::GREY);
+        assert_eq!(image.pixel(8, 1), LIGHT);
+        // The viewport wraps around both edges.
+        assert_eq!(image.pixel(250, 210), VIEWPORT_COLOR);
+        assert_eq!(image.pixel((250 + 159) % 256, 210), VIEWPORT_COLOR);
+        assert_eq!(image.pixel(100, (200 + 143) % 256), VIEWPORT_COLOR);
+        assert_eq!(image.pixel(100, 100), WHITE);
+        // Map 0 is not the background's.
+        assert_eq!(tile_map(&vram, &registers, 0, &Palette::GREY).pixel(250, 210), WHITE);
+
+        // With signed addressing, index 0x80 is 128 tiles below 0x9000, i.e. still tile 0x80.
+        registers.unsigned_tile_data = false;
+        let image = tile_map(&vram, &registers, 1, &Palette::GREY);
+        assert_eq!(image.pixel(8, 1), LIGHT);
+        // But index 0 is tile 256.
+        write_test_tile(&mut vram, 256);
+        let image = tile_map(&vram, &registers, 1, &Palette::GREY);
+        assert_eq!(image.pixel(0, 0), BLACK);
+
+        registers.enable_window = true;
+        registers.window_map = 0;
+        registers.window_x = 87;
+        registers.window_y = 44;
+        let image = tile_map(&vram, &registers, 0, &Palette::GREY);
+        assert_eq!(image.pixel(79, 50), WINDOW_COLOR);
+        assert_eq!(image.pixel(50, 99), WINDOW_COLOR);
+        assert_eq!(image.pixel(80, 50), LIGHT);
+    }
+
+    #[test]
+    fn test_objects() {
+        let mut vram = vec![0; 0x2000];
+        write_test_tile(&mut vram, 4);
+        let mut oam = vec![0; 160];
+        oam[4..8].copy_from_slice(&[16 + 10, 8 + 20, 5, 0b1011_0000]);
+        let objects = objects(&oam);
+        assert_eq!(objects.len(), 40);
+        let object = objects[1];
+        assert_eq!((object.x, object.y, object.tile, object.palette), (20, 10, 5, 1));
+        assert!(object.flip_x && !object.flip_y && object.behind_bg);
+        assert_eq!(object.to_string(), "#01 x=  20 y=  10 tile=05 OBP1 flip_x behind_bg");
+
+        let registers =
+            Registers { large_sprites: true, obj_palette_1: 0xE4, ..Registers::default() };
+        let image = object_sheet(&vram, &oam, &registers, &DisplayPalettes::default());
+        // Tile 5 is drawn as 4 and 5 in 8x16 mode, flipped horizontally.
+        let (cell_x, cell_y) = (10, 1);
+        assert_eq!(image.pixel(cell_x, cell_y), BLACK);
+        assert_eq!(image.pixel(cell_x + 7, cell_y + 1), LIGHT);
+        assert_eq!(image.pixel(cell_x, cell_y + 1), TRANSPARENT_COLOR);
+        assert_eq!(image.pixel(cell_x - 1, cell_y), GRID_COLOR);
+    }
+}
@@ -1,11 +1,15 @@
 use bitfield::bitfield;
+use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
+use std::collections::VecDeque;
 
 use crate::io_registers::Addresses;
 use crate::mmu;
 use crate::system::Interrupts;
 
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen::prelude::wasm_bindgen)]
+#[derive(Clone, Copy, Debug, PartialEq, FromPrimitive)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum Key {
     Right,
     Left,
@@ -18,11 +22,24 @@ pub enum Key {
     NumKeys,
 }
 
+/// A change in a key's state, stamped with the T-cycle it happens on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+pub struct KeyEvent {
+    pub cycle: u64,
+    pub key: Key,
+    pub pressed: bool,
+}
+
 #[derive(Default)]
 #[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub struct Joypad {
     keys_pressed: [bool; Key::NumKeys as usize],
     ctrl: PadControl,
+    /// The P1 input lines (bits 0-3) as of the last T-cycle. Used to detect falling edges.
+    lines: i32,
+    /// Key events that have yet to happen, ordered by cycle.
+    pending_events: VecDeque<KeyEvent>,
 }
 
 bitfield! {
@@ -41,24 +58,43 @@ bitfield! {
 define_typed_register!(PadControl, Addresses::Joypad);
 
 impl Joypad {
-    pub fn execute_tcycle(&mut self) -> Interrupts {
-        // let new_reg_value = self.reg_value();
-        // let current = self.reg_value;
-
-        // let get_bit =
-        //     |x| is_bit_set(x, 0) && is_bit_set(x, 1) && is_bit_set(x, 2) && is_bit_set(x, 3);
-
-        // let new_bit = get_bit(new_reg_value);
-        // let current_bit = get_bit(current);
-        // let interrupts = if !new_bit && current_bit {
-        //     Interrupts::JOYPAD
-        // } else {
-        //     Interrupts::empty()
-        // };
-
-        // self.reg_value = new_reg_value;
-        // interrupts
-        Interrupts::empty()
+    /// Applies any key events due at `cycle`, and then samples the P1 input lines. The joypad
+    /// interrupt fires on any high-to-low transition of the lines. Lines are active-low, and are
+    /// only driven by keys in the currently selected group(s). So both a key press and a P1 write
+    /// that selects a group with a held key can fire the interrupt.
+    pub fn execute_tcycle(&mut self, cycle: u64) -> Interrupts {
+        while let Some(&event) = self.pending_events.front() {
+            if event.cycle > cycle {
+                break;
+            }
+            self.pending_events.pop_front();
+            self.keys_pressed[event.key as usize] = event.pressed;
+        }
+
+        let new_lines = self.reg_value().0 & 0xF;
+        let falling_edges = self.lines & !new_lines;
+        self.lines = new_lines;
+        if falling_edges != 0 {
+            Interrupts::JOYPAD
+        } else {
+            Interrupts::empty()
+        }
+    }
+
+    /// Schedules a key event. Events must be queued in cycle order.
+    pub fn queue_event(&mut self, event: KeyEvent) {
+        debug_assert!(self.pending_events.back().map_or(true, |x| x.cycle <= event.cycle));
+        self.pending_events.push_back(event);
+    }
+
+    /// Whether any queued key event has yet to happen.
+    pub fn has_pending_events(&self) -> bool {
+        !self.pending_events.is_empty()
+    }
+
+    /// Drops all queued key events that have yet to happen.
+    pub fn clear_pending_events(&mut self) {
+        self.pending_events.clear();
     }
 
     fn reg_value(&self) -> PadControl {
@@ -118,3 +154,40 @@ impl mmu::MemoryMapped for Joypad {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn select(joypad: &mut Joypad, value: i32) {
+        mmu::MemoryMapped::write(joypad, mmu::Address::from_raw(0xFF00).unwrap(), value);
+    }
+
+    #[test]
+    fn test_press_fires_on_cycle() {
+        let mut joypad = Joypad::default();
+        // Select the button keys.
+        select(&mut joypad, 0x10);
+        joypad.queue_event(KeyEvent { cycle: 10, key: Key::Start, pressed: true });
+        for cycle in 0..10 {
+            assert_eq!(joypad.execute_tcycle(cycle), Interrupts::empty());
+        }
+        assert_eq!(joypad.execute_tcycle(10), Interrupts::JOYPAD);
+        // Holding the key doesn't fire again. Neither does releasing it.
+        assert_eq!(joypad.execute_tcycle(11), Interrupts::empty());
+        joypad.queue_event(KeyEvent { cycle: 12, key: Key::Start, pressed: false });
+        assert_eq!(joypad.execute_tcycle(12), Interrupts::empty());
+    }
+
+    #[test]
+    fn test_unselected_keys_do_not_fire() {
+        let mut joypad = Joypad::default();
+        // Select the direction keys.
+        select(&mut joypad, 0x20);
+        joypad.queue_event(KeyEvent { cycle: 0, key: Key::A, pressed: true });
+        assert_eq!(joypad.execute_tcycle(0), Interrupts::empty());
+        // Selecting the buttons while A is held pulls the line low.
+        select(&mut joypad, 0x10);
+        assert_eq!(joypad.execute_tcycle(1), Interrupts::JOYPAD);
+    }
+}
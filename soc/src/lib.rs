@@ -15,14 +15,20 @@ mod util;
 pub mod cart;
 pub mod cpu;
 pub mod error;
+pub mod gbs;
 pub mod gpu;
 pub mod joypad;
 pub mod log;
+pub mod movie;
+pub mod png;
+#[cfg(feature = "serialize")]
+pub mod rewind;
+pub mod screenshot;
 pub mod sim;
 pub mod system;
 
 #[cfg(feature = "audio")]
-mod apu;
+pub mod apu;
 
 mod dma;
 mod mmu;
@@ -6,6 +6,8 @@ pub struct LogSettings {
     pub timer: bool,
     pub dma: bool,
     pub gpu: bool,
+    /// Accesses to unmapped memory, see `System::set_strict`.
+    pub memory: bool,
 }
 
 pub fn setup_logging(settings: LogSettings) -> Result<(), fern::InitError> {
@@ -28,6 +30,9 @@ pub fn setup_logging(settings: LogSettings) -> Result<(), fern::InitError> {
     if settings.gpu {
         allowed_modules.insert("gpu");
     }
+    if settings.memory {
+        allowed_modules.insert("memory");
+    }
     if !allowed_modules.is_empty() {
         fern::Dispatch::new()
             .filter(move |metadata| allowed_modules.contains(metadata.target()))
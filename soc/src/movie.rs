@@ -0,0 +1,255 @@
+use num_traits::FromPrimitive;
+
+use crate::error::{self, Result};
+use crate::joypad::{Key, KeyEvent};
+use crate::system::System;
+
+/// Input movies. A movie is a log of joypad state changes, each stamped with the emulated T-cycle
+/// it happened on. A movie either starts from power-on, or from a save-state embedded in the movie
+/// itself. Since the emulator is deterministic, playing the movie back produces the exact same
+/// frames as when it was recorded.
+///
+/// File format (all integers are little-endian):
+///   magic:        "RBMV"
+///   version:      u8
+///   has_state:    u8 (0 or 1)
+///   state_len:    u32, followed by state_len bytes of bincode System. Only if has_state is 1.
+///   num_events:   u32, followed by num_events events of:
+///     cycle:      u64
+///     key:        u8
+///     pressed:    u8 (0 or 1)
+const MAGIC: &[u8; 4] = b"RBMV";
+const VERSION: u8 = 1;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Movie {
+    /// The serialized System the movie starts from. If None, the movie starts from power-on.
+    start_state: Option<Vec<u8>>,
+    events: Vec<KeyEvent>,
+}
+
+impl Movie {
+    /// Creates an empty movie that starts from a freshly powered-on system.
+    pub fn from_power_on() -> Movie {
+        Movie::default()
+    }
+
+    /// Creates an empty movie that starts from the current state of `system`.
+    #[cfg(feature = "serialize")]
+    pub fn from_state(system: &System) -> Movie {
+        Movie { start_state: Some(system.save_state()), events: Vec::new() }
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+
+    pub fn record(&mut self, event: KeyEvent) {
+        debug_assert!(self.events.last().map_or(true, |x| x.cycle <= event.cycle));
+        self.events.push(event);
+    }
+
+    /// Drops all events from `cycle` on, e.g. after the recorded system went back in time.
+    pub fn truncate(&mut self, cycle: u64) {
+        self.events.retain(|x| x.cycle < cycle);
+    }
+
+    /// Prepares `system` for playback. Restores the embedded save-state (if any), and queues all
+    /// the movie's events into the joypad.
+    pub fn start(&self, system: &mut System) -> Result<()> {
+        match &self.start_state {
+            #[cfg(feature = "serialize")]
+            Some(state) => system.load_state(state)?,
+            #[cfg(not(feature = "serialize"))]
+            Some(_) => {
+                return Err(error::Type::InvalidOperation(
+                    "Movie starts from a save-state, but serialization is not enabled.".into(),
+                ))
+            }
+            None if system.cycles() != 0 => {
+                return Err(error::Type::InvalidOperation(
+                    "Movie starts from power-on, but the system is already running.".into(),
+                ))
+            }
+            None => (),
+        }
+        for &event in &self.events {
+            system.joypad_mut().queue_event(event);
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(VERSION);
+        if let Some(state) = &self.start_state {
+            bytes.push(1);
+            bytes.extend_from_slice(&(state.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(state);
+        } else {
+            bytes.push(0);
+        }
+        bytes.extend_from_slice(&(self.events.len() as u32).to_le_bytes());
+        for event in &self.events {
+            bytes.extend_from_slice(&event.cycle.to_le_bytes());
+            bytes.push(event.key as u8);
+            bytes.push(event.pressed as u8);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Movie> {
+        let mut reader = Reader(bytes);
+        if reader.take(4)? != MAGIC {
+            return Err(bad_movie("Not a movie file"));
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(bad_movie(&format!("Unsupported version {}", version)));
+        }
+        let start_state = match reader.u8()? {
+            0 => None,
+            1 => {
+                let len = reader.u32()? as usize;
+                Some(reader.take(len)?.to_vec())
+            }
+            _ => return Err(bad_movie("Corrupt header")),
+        };
+        let num_events = reader.u32()? as usize;
+        let mut events = Vec::with_capacity(num_events);
+        for _ in 0..num_events {
+            let cycle = reader.u64()?;
+            let key = reader.u8()?;
+            let key = Key::from_u8(key)
+                .filter(|&x| x != Key::NumKeys)
+                .ok_or_else(|| bad_movie(&format!("Invalid key {}", key)))?;
+            let pressed = reader.u8()? != 0;
+            if events.last().map_or(false, |x: &KeyEvent| x.cycle > cycle) {
+                return Err(bad_movie("Events are not in cycle order"));
+            }
+            events.push(KeyEvent { cycle, key, pressed });
+        }
+        Ok(Movie { start_state, events })
+    }
+
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        std::fs::write(path, self.to_bytes())
+    }
+
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Movie> {
+        let bytes = std::fs::read(path).map_err(|err| {
+            error::Type::InvalidOperation(format!("Could not read movie: {}", err))
+        })?;
+        Movie::from_bytes(&bytes)
+    }
+}
+
+fn bad_movie(reason: &str) -> error::Type {
+    error::Type::InvalidOperation(format!("Bad movie file: {}.", reason))
+}
+
+/// Tiny cursor over the movie bytes.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(bad_movie("Unexpected end of file"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::with_default;
+
+    fn make_movie() -> Movie {
+        let mut movie = Movie::from_power_on();
+        movie.record(KeyEvent { cycle: 8, key: Key::A, pressed: true });
+        movie.record(KeyEvent { cycle: 8, key: Key::Up, pressed: true });
+        movie.record(KeyEvent { cycle: 1000, key: Key::A, pressed: false });
+        movie
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let movie = make_movie();
+        assert_eq!(Movie::from_bytes(&movie.to_bytes()).unwrap(), movie);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let mut movie = make_movie();
+        movie.truncate(1000);
+        assert_eq!(movie.events().len(), 2);
+        movie.truncate(8);
+        assert!(movie.events().is_empty());
+    }
+
+    #[test]
+    fn test_rejects_bad_files() {
+        let bytes = make_movie().to_bytes();
+        assert!(Movie::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Movie::from_bytes(b"RBMX").is_err());
+    }
+
+    #[test]
+    fn test_playback_applies_events_on_cycle() {
+        let mut context = with_default();
+        // Select the button keys.
+        context.system.memory_write(0xFF00, 0x10);
+        make_movie().start(&mut context.system).unwrap();
+        // Events are applied at the start of their cycle, so after 2 mcycles A is down.
+        context.system.execute_machine_cycle().unwrap();
+        assert_eq!(context.system.memory_read(0xFF00) & 0xF, 0xF);
+        context.system.execute_machine_cycle().unwrap();
+        context.system.execute_machine_cycle().unwrap();
+        assert_eq!(context.system.memory_read(0xFF00) & 0xF, 0xE);
+    }
+
+    #[test]
+    #[cfg(feature = "serialize")]
+    fn test_playback_from_state() {
+        let mut context = with_default();
+        context.system.memory_write(0xFF00, 0x10);
+        context.system.execute_machine_cycle().unwrap();
+        let mut movie = Movie::from_state(&context.system);
+        let cycle = context.system.cycles() + 4;
+        movie.record(KeyEvent { cycle, key: Key::B, pressed: true });
+
+        let mut other = with_default();
+        Movie::from_bytes(&movie.to_bytes()).unwrap().start(&mut other.system).unwrap();
+        assert_eq!(other.system.cycles(), context.system.cycles());
+        other.system.execute_machine_cycle().unwrap();
+        other.system.execute_machine_cycle().unwrap();
+        assert_eq!(other.system.memory_read(0xFF00) & 0xF, 0xD);
+    }
+
+    #[test]
+    fn test_power_on_movie_requires_fresh_system() {
+        let mut context = with_default();
+        context.system.execute_machine_cycle().unwrap();
+        assert!(make_movie().start(&mut context.system).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+//! A minimal PNG encoder, enough to dump debug images without pulling in a compression library.
+//! The image data is stored uncompressed.
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+/// Deflate's limit for a single stored block.
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+/// Encodes an 8-bit RGBA image. `rgba` holds the rows top to bottom, 4 bytes per pixel.
+pub fn encode_rgba(width: usize, height: usize, rgba: &[u8]) -> Vec<u8> {
+    assert_eq!(rgba.len(), width * height * 4);
+    let mut png = SIGNATURE.to_vec();
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, truecolor with alpha, default compression, filter and no interlacing.
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    write_chunk(&mut png, b"IHDR", &header);
+
+    // Every row starts with its filter type: 0 is None.
+    let mut scanlines = Vec::with_capacity((width * 4 + 1) * height);
+    for row in rgba.chunks(width * 4) {
+        scanlines.push(0);
+        scanlines.extend_from_slice(row);
+    }
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&scanlines));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wraps `data` in a zlib stream made of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let num_blocks = (data.len() + MAX_STORED_BLOCK - 1) / MAX_STORED_BLOCK;
+    let mut stream = Vec::with_capacity(data.len() + num_blocks.max(1) * 5 + 6);
+    // Deflate with a 32K window, no preset dictionary, and a valid check value.
+    stream.extend_from_slice(&[0x78, 0x01]);
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let is_final = blocks.peek().is_none();
+        stream.push(is_final as u8);
+        let len = block.len() as u16;
+        stream.extend_from_slice(&len.to_le_bytes());
+        stream.extend_from_slice(&(!len).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1_u32, 0_u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn test_encodes_rgba() {
+        let png = encode_rgba(2, 1, &[255, 0, 0, 255, 0, 0, 255, 128]);
+        assert_eq!(png[..8], SIGNATURE);
+        // IHDR.
+        assert_eq!(png[8..16], [0, 0, 0, 13, b'I', b'H', b'D', b'R']);
+        assert_eq!(png[16..29], [0, 0, 0, 2, 0, 0, 0, 1, 8, 6, 0, 0, 0]);
+        // IDAT: zlib header, one final stored block with the filtered row, and the adler32.
+        assert_eq!(png[33..41], [0, 0, 0, 20, b'I', b'D', b'A', b'T']);
+        assert_eq!(png[41..48], [0x78, 0x01, 1, 9, 0, 0xF6, 0xFF]);
+        assert_eq!(png[48..57], [0, 255, 0, 0, 255, 0, 0, 255, 128]);
+        assert_eq!(png[57..61], adler32(&png[48..57]).to_be_bytes());
+        assert_eq!(
+            png[png.len() - 12..],
+            [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]
+        );
+    }
+
+    #[test]
+    fn test_png_crate_decodes_it() {
+        let rgba: Vec<u8> = (0..300 * 200 * 4).map(|x| (x * 7 % 251) as u8).collect();
+        let png = encode_rgba(300, 200, &rgba);
+        let decoder = ::png::Decoder::new(&png[..]);
+        let (info, mut reader) = decoder.read_info().unwrap();
+        assert_eq!((info.width, info.height), (300, 200));
+        let mut decoded = vec![0; info.buffer_size()];
+        reader.next_frame(&mut decoded).unwrap();
+        assert_eq!(decoded, rgba);
+    }
+
+    #[test]
+    fn test_splits_large_images_into_blocks() {
+        let png = encode_rgba(256, 256, &vec![7; 256 * 256 * 4]);
+        let idat_len = u32::from_be_bytes([png[33], png[34], png[35], png[36]]) as usize;
+        let scanlines = 256 * (256 * 4 + 1);
+        // Five stored blocks of 5 bytes of header each, plus the zlib header and check value.
+        assert_eq!(idat_len, scanlines + 5 * 5 + 6);
+    }
+}
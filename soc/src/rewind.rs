@@ -0,0 +1,190 @@
+use std::collections::VecDeque;
+
+/// Equal bytes shorter than this are folded into the surrounding literal, since each run costs at
+/// least two bytes of overhead.
+const MIN_RUN: usize = 4;
+
+/// Ring buffer of periodic snapshots, used to run the emulation backwards. Snapshots are opaque
+/// byte blobs tagged with the frame they were taken on.
+///
+/// Only the newest snapshot is stored as-is. Every older snapshot is stored as a compressed delta
+/// against the snapshot taken after it, so that consecutive snapshots (which differ in only a few
+/// bytes) are cheap, and so that the oldest snapshot can be dropped without touching any other.
+/// Once the memory budget is exceeded, the oldest snapshots are dropped.
+pub struct RewindBuffer {
+    newest: Option<(u64, Vec<u8>)>,
+    /// Older snapshots, oldest first.
+    deltas: VecDeque<(u64, Vec<u8>)>,
+    budget: usize,
+    used: usize,
+}
+
+impl RewindBuffer {
+    /// Creates a buffer holding at most `budget` bytes. The newest snapshot is always kept, even
+    /// if it alone exceeds the budget.
+    pub fn new(budget: usize) -> RewindBuffer {
+        RewindBuffer { newest: None, deltas: VecDeque::new(), budget, used: 0 }
+    }
+
+    /// The number of snapshots held.
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.newest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    /// The number of bytes used by all snapshots.
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.used = 0;
+    }
+
+    pub fn push(&mut self, frame: u64, snapshot: Vec<u8>) {
+        debug_assert!(self.newest.as_ref().map_or(true, |(x, _)| *x < frame));
+        self.used += snapshot.len();
+        if let Some((old_frame, old)) = self.newest.replace((frame, snapshot)) {
+            let delta = encode_delta(&self.newest.as_ref().unwrap().1, &old);
+            self.used = self.used - old.len() + delta.len();
+            self.deltas.push_back((old_frame, delta));
+        }
+        while self.used > self.budget {
+            match self.deltas.pop_front() {
+                Some((_, delta)) => self.used -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Drops all snapshots taken after `frame`, and returns the newest remaining one. If every
+    /// snapshot is newer than `frame`, stops at the oldest one instead.
+    pub fn rewind_to(&mut self, frame: u64) -> Option<(u64, &[u8])> {
+        while self.newest.as_ref()?.0 > frame {
+            let (older_frame, delta) = match self.deltas.pop_back() {
+                Some(x) => x,
+                None => break,
+            };
+            let (_, newer) = self.newest.take().unwrap();
+            let older = decode_delta(&newer, &delta);
+            self.used = self.used - newer.len() - delta.len() + older.len();
+            self.newest = Some((older_frame, older));
+        }
+        self.newest.as_ref().map(|(frame, snapshot)| (*frame, snapshot.as_slice()))
+    }
+}
+
+/// Encodes `target` against `reference` as a list of (unchanged run length, literal length,
+/// literal bytes) triples, with the lengths stored as LEB128.
+fn encode_delta(reference: &[u8], target: &[u8]) -> Vec<u8> {
+    let equal = |i: usize| i < reference.len() && reference[i] == target[i];
+    let mut delta = Vec::new();
+    let mut i = 0;
+    while i < target.len() {
+        let run_start = i;
+        while i < target.len() && equal(i) {
+            i += 1;
+        }
+        let literal_start = i;
+        while i < target.len() && !(i + MIN_RUN <= target.len() && (i..i + MIN_RUN).all(equal)) {
+            i += 1;
+        }
+        write_varint(&mut delta, literal_start - run_start);
+        write_varint(&mut delta, i - literal_start);
+        delta.extend_from_slice(&target[literal_start..i]);
+    }
+    delta
+}
+
+fn decode_delta(reference: &[u8], mut delta: &[u8]) -> Vec<u8> {
+    let mut target = Vec::with_capacity(reference.len());
+    while !delta.is_empty() {
+        let run = read_varint(&mut delta);
+        let literal = read_varint(&mut delta);
+        let start = target.len();
+        target.extend_from_slice(&reference[start..start + run]);
+        target.extend_from_slice(&delta[..literal]);
+        delta = &delta[literal..];
+    }
+    target
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(input: &mut &[u8]) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = input[0];
+        *input = &input[1..];
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn snapshot(seed: u8) -> Vec<u8> {
+        let mut snapshot = vec![0u8; 1000];
+        snapshot[10] = seed;
+        snapshot[500..510].iter_mut().for_each(|x| *x = seed.wrapping_mul(3));
+        snapshot.resize(1000 + seed as usize, seed);
+        snapshot
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        for (a, b) in &[(1, 2), (2, 1), (0, 200), (200, 0), (7, 7)] {
+            let (reference, target) = (snapshot(*a), snapshot(*b));
+            assert_eq!(decode_delta(&reference, &encode_delta(&reference, &target)), target);
+        }
+        assert_eq!(decode_delta(&[1, 2, 3], &encode_delta(&[1, 2, 3], &[])), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_delta_is_compressed() {
+        assert_lt!(encode_delta(&snapshot(1), &snapshot(2)).len(), 32);
+    }
+
+    #[test]
+    fn test_rewind_restores_snapshots() {
+        let mut buffer = RewindBuffer::new(1 << 20);
+        for frame in 0..10 {
+            buffer.push(frame * 2, snapshot(frame as u8));
+        }
+        assert_eq!(buffer.len(), 10);
+        // Frame 13 was never snapshotted, so fall back to frame 12.
+        assert_eq!(buffer.rewind_to(13), Some((12, &snapshot(6)[..])));
+        assert_eq!(buffer.len(), 7);
+        assert_eq!(buffer.rewind_to(0), Some((0, &snapshot(0)[..])));
+        assert_eq!(buffer.memory_used(), snapshot(0).len());
+    }
+
+    #[test]
+    fn test_budget_drops_oldest() {
+        let mut buffer = RewindBuffer::new(1100);
+        for frame in 0..100 {
+            buffer.push(frame, snapshot(1 + frame as u8 % 2));
+        }
+        assert_le!(buffer.memory_used(), 1100);
+        assert_lt!(buffer.len(), 100);
+        let oldest = 100 - buffer.len() as u64;
+        assert_eq!(buffer.rewind_to(0).map(|(x, _)| x), Some(oldest));
+    }
+}
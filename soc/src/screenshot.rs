@@ -0,0 +1,164 @@
+//! Golden-image checks of the screen: run a system for a number of frames, then compare what it
+//! drew with a reference PNG. On a mismatch the actual screen, the expected one and the pixels
+//! that differ are saved as `<name>_actual.png`, `<name>_expected.png` and `<name>_diff.png`.
+
+use std::path::{Path, PathBuf};
+
+use crate::gpu::{viewer::Image, Color, Pixel, LCD_HEIGHT, LCD_WIDTH};
+use crate::system::System;
+
+/// A frame is 154 lines of 456 dots.
+pub const MCYCLES_PER_FRAME: usize = 154 * 456 / 4;
+/// Marks the pixels that differ in the diff image.
+const DIFF_COLOR: Pixel = Pixel::new(255, 0, 0);
+
+/// Runs `system` for `frames` frames worth of cycles, whether the LCD is on or not.
+pub fn run_frames(system: &mut System, frames: usize) -> crate::error::Result<()> {
+    for _ in 0..frames * MCYCLES_PER_FRAME {
+        system.execute_machine_cycle()?;
+    }
+    Ok(())
+}
+
+/// Draws a screen with the grey palette.
+pub fn screen_image(screen: &[Color]) -> Image {
+    Image { width: LCD_WIDTH, height: LCD_HEIGHT, pixels: screen.iter().map(Pixel::from).collect() }
+}
+
+/// Reads a screen from the grey levels of an image, one byte per pixel, such as the reference
+/// images of the test ROMs.
+pub fn screen_from_greys(width: usize, height: usize, greys: &[u8]) -> Result<Vec<Color>, String> {
+    if (width, height) != (LCD_WIDTH, LCD_HEIGHT) {
+        return Err(format!(
+            "Expected a {}x{} image, got {}x{}.",
+            LCD_WIDTH, LCD_HEIGHT, width, height
+        ));
+    }
+    Ok(greys
+        .iter()
+        .map(|x| match x {
+            0..=42 => Color::Black,
+            43..=127 => Color::DarkGray,
+            128..=212 => Color::LightGray,
+            _ => Color::White,
+        })
+        .collect())
+}
+
+/// Decodes a screen saved as a PNG, see `screen_from_greys`. Needs the `png` feature.
+#[cfg(any(test, feature = "png"))]
+pub fn decode_screen(png: &[u8]) -> Result<Vec<Color>, String> {
+    let mut decoder = ::png::Decoder::new(png);
+    decoder.set_transformations(::png::Transformations::EXPAND | ::png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info().map_err(|e| e.to_string())?;
+    let mut pixels = vec![0; info.buffer_size()];
+    reader.next_frame(&mut pixels).map_err(|e| e.to_string())?;
+    let greys: Vec<u8> = pixels.chunks(info.color_type.samples()).map(|x| x[0]).collect();
+    screen_from_greys(info.width as usize, info.height as usize, &greys)
+}
+
+/// The pixels that differ in `DIFF_COLOR`, over a faded copy of the expected screen.
+pub fn diff_image(actual: &[Color], expected: &[Color]) -> Image {
+    let pixels = actual
+        .iter()
+        .zip(expected)
+        .map(|(a, e)| {
+            if a == e {
+                let pixel = Pixel::from(e);
+                Pixel::new(pixel.r / 4 + 191, pixel.g / 4 + 191, pixel.b / 4 + 191)
+            } else {
+                DIFF_COLOR
+            }
+        })
+        .collect();
+    Image { width: LCD_WIDTH, height: LCD_HEIGHT, pixels }
+}
+
+/// Saves the actual, expected and diff images of `name` under `dir`, and returns their paths.
+pub fn save_diff(
+    dir: impl AsRef<Path>,
+    name: &str,
+    actual: &[Color],
+    expected: &[Color],
+) -> std::io::Result<[PathBuf; 3]> {
+    let dir = dir.as_ref();
+    let path = |kind| dir.join(format!("{}_{}.png", name, kind));
+    let paths = [path("actual"), path("expected"), path("diff")];
+    // `name` may contain a sub directory.
+    std::fs::create_dir_all(paths[0].parent().unwrap())?;
+    screen_image(actual).save_png(&paths[0])?;
+    screen_image(expected).save_png(&paths[1])?;
+    diff_image(actual, expected).save_png(&paths[2])?;
+    Ok(paths)
+}
+
+/// Compares `actual` with `expected`. On a mismatch, saves the images under `dir` and returns a
+/// message that says how many pixels differ and where the images are.
+pub fn compare(
+    dir: impl AsRef<Path>,
+    name: &str,
+    actual: &[Color],
+    expected: &[Color],
+) -> Result<(), String> {
+    if actual == expected {
+        return Ok(());
+    }
+    let differing = actual.iter().zip(expected).filter(|(a, e)| a != e).count();
+    let paths = save_diff(dir, name, actual, expected).map_err(|e| {
+        format!("{} does not match the reference, and could not be saved: {}", name, e)
+    })?;
+    Err(format!("{}: {} pixels do not match the reference, see {:?}.", name, differing, paths[2]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(color: Color) -> Vec<Color> {
+        vec![color; LCD_WIDTH * LCD_HEIGHT]
+    }
+
+    #[test]
+    fn test_decodes_saved_screens() {
+        let mut expected = screen(Color::White);
+        expected[1] = Color::LightGray;
+        expected[2] = Color::DarkGray;
+        expected[3] = Color::Black;
+        let decoded = decode_screen(&screen_image(&expected).to_png()).unwrap();
+        assert!(decoded == expected);
+    }
+
+    #[test]
+    fn test_rejects_other_sizes() {
+        assert!(screen_from_greys(8, 8, &[0; 64]).is_err());
+    }
+
+    #[test]
+    fn test_diff_marks_differing_pixels() {
+        let expected = screen(Color::Black);
+        let mut actual = expected.clone();
+        actual[5] = Color::White;
+        let diff = diff_image(&actual, &expected);
+        assert_eq!(diff.pixel(5, 0), DIFF_COLOR);
+        assert_eq!(diff.pixel(0, 0), Pixel::new(191, 191, 191));
+    }
+
+    #[test]
+    fn test_compare_saves_images() {
+        let dir = std::env::temp_dir().join(format!("soc_screenshot_{}", std::process::id()));
+        let expected = screen(Color::White);
+        assert_eq!(compare(&dir, "same", &expected, &expected), Ok(()));
+        assert!(!dir.join("same_diff.png").exists());
+
+        let mut actual = expected.clone();
+        actual[0] = Color::Black;
+        let message = compare(&dir, "different", &actual, &expected).unwrap_err();
+        assert!(message.contains("1 pixels"), "{}", message);
+        let saved = decode_screen(&std::fs::read(dir.join("different_actual.png")).unwrap());
+        let saved = saved.unwrap();
+        assert!(saved == actual);
+        assert!(dir.join("different_expected.png").exists());
+        assert!(dir.join("different_diff.png").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,8 +1,31 @@
-use crate::gpu::Pixel;
+use crate::error::Result;
+use crate::gpu::filter::{Filters, Scaler};
+use crate::gpu::palette::{self, DisplayPalettes, PixelFormat};
+use crate::gpu::scanline_log::ScanlineLog;
+use crate::gpu::viewer::Image;
+#[cfg(feature = "serialize")]
+use crate::gpu::{Color, Layer};
 use crate::gpu::{LCD_HEIGHT, LCD_WIDTH};
-use crate::joypad::Key;
+use crate::joypad::{Key, KeyEvent};
+use crate::movie::Movie;
+#[cfg(feature = "serialize")]
+use crate::rewind::RewindBuffer;
 use crate::system::System;
 
+/// Simulated frames are 16.66ms long.
+const STEP_SIZE: f32 = 1. / 60.;
+
+/// When unthrottled, the wall-clock time a single `update` may spend simulating frames.
+#[cfg(not(target_arch = "wasm32"))]
+const UNTHROTTLED_TIME_SLICE: std::time::Duration = std::time::Duration::from_millis(16);
+/// There is no wall-clock in wasm, so simulate a fixed number of frames per `update` instead.
+#[cfg(target_arch = "wasm32")]
+const UNTHROTTLED_FRAMES: usize = 8;
+
+/// Frames between two rewind snapshots.
+#[cfg(feature = "serialize")]
+const REWIND_INTERVAL: u64 = 2;
+
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 
@@ -12,11 +35,217 @@ pub struct Simulator {
     system: System,
 
     time_accum: f32,
+    /// Speed multiplier relative to real time. Infinite when unthrottled.
+    speed: f32,
+    is_paused: bool,
+    /// The number of frames simulated.
+    frame: u64,
+
+    #[cfg(feature = "serialize")]
+    rewind: Option<RewindBuffer>,
+    /// While rewinding, `update` runs the game backwards.
+    #[cfg(feature = "serialize")]
+    is_rewinding: bool,
+
+    /// The movie being recorded, if any.
+    recording: Option<Movie>,
+    /// The cycle the recording started on.
+    #[cfg(feature = "serialize")]
+    recording_start: u64,
+    /// While playing back a movie, user input is ignored.
+    is_playing_movie: bool,
+
+    /// How screens are returned.
+    palettes: DisplayPalettes,
+    pixel_format: PixelFormat,
+    filters: Filters,
+    /// The screen before the last simulated frame, kept while blending frames.
+    previous_frame: Option<Image>,
+
+    /// Collects the audio samples for `take_audio_samples`, if enabled.
+    #[cfg(feature = "audio")]
+    audio_buffer: Option<crate::apu::output::MemoryOutput>,
 }
 
 impl Simulator {
     pub fn with_system(system: System) -> Simulator {
-        Simulator { system, time_accum: 0.0 }
+        Simulator {
+            system,
+            time_accum: 0.0,
+            speed: 1.0,
+            is_paused: false,
+            frame: 0,
+            #[cfg(feature = "serialize")]
+            rewind: None,
+            #[cfg(feature = "serialize")]
+            is_rewinding: false,
+            recording: None,
+            #[cfg(feature = "serialize")]
+            recording_start: 0,
+            is_playing_movie: false,
+            palettes: DisplayPalettes::default(),
+            pixel_format: PixelFormat::Bgra8,
+            filters: Filters::default(),
+            previous_frame: None,
+            #[cfg(feature = "audio")]
+            audio_buffer: None,
+        }
+    }
+
+    /// Starts taking periodic snapshots, keeping at most `budget` bytes of them around.
+    #[cfg(feature = "serialize")]
+    pub fn enable_rewind(&mut self, budget: usize) {
+        self.rewind = Some(RewindBuffer::new(budget));
+    }
+
+    #[cfg(feature = "serialize")]
+    pub fn set_rewinding(&mut self, is_rewinding: bool) {
+        self.is_rewinding = is_rewinding;
+    }
+
+    /// Goes back (at least) `frames` frames, or as far back as the rewind buffer reaches.
+    /// Snapshots are only taken every REWIND_INTERVAL frames, so this lands on the newest one at
+    /// least `frames` old: `rewind(1)` usually goes back REWIND_INTERVAL frames. Returns the number
+    /// of frames actually rewound.
+    ///
+    /// While recording a movie, the events after the restored point are dropped. Rewinding past
+    /// the start of the recording starts it over from the restored state.
+    #[cfg(feature = "serialize")]
+    pub fn rewind(&mut self, frames: u64) -> u64 {
+        use num_traits::FromPrimitive;
+
+        let target = self.frame.saturating_sub(frames);
+        let (frame, snapshot) = match self.rewind.as_mut().and_then(|x| x.rewind_to(target)) {
+            Some(x) => x,
+            None => return 0,
+        };
+        let (screen, state) = snapshot.split_at(LCD_WIDTH * LCD_HEIGHT);
+        self.system.load_state(state).expect("Rewind snapshots should always be loadable");
+        for (pixel, &x) in self.system.screen_mut().iter_mut().zip(screen) {
+            *pixel = Color::from_u8(x & 0x3).unwrap();
+        }
+        for (layer, &x) in self.system.screen_layers_mut().iter_mut().zip(screen) {
+            *layer = Layer::from_u8(x >> 2).unwrap();
+        }
+        if self.recording.is_some() {
+            if self.system.cycles() < self.recording_start {
+                self.start_recording();
+            } else if let Some(movie) = &mut self.recording {
+                movie.truncate(self.system.cycles());
+            }
+        }
+        // The frame before the restored one is not around anymore.
+        self.previous_frame = None;
+        let rewound = self.frame - frame;
+        self.frame = frame;
+        rewound
+    }
+
+    #[cfg(feature = "serialize")]
+    fn take_rewind_snapshot(&mut self) {
+        if self.frame % REWIND_INTERVAL != 0 {
+            return;
+        }
+        if let Some(rewind) = &mut self.rewind {
+            // Every pixel takes a byte: the shade in bits 0-1, and the layer above.
+            let screen = self.system.screen().iter().zip(self.system.screen_layers());
+            let mut snapshot: Vec<u8> =
+                screen.map(|(&color, &layer)| color as u8 | (layer as u8) << 2).collect();
+            snapshot.extend(self.system.save_state());
+            rewind.push(self.frame, snapshot);
+        }
+    }
+
+    /// Starts recording all key events into a new movie. If serialization is enabled, the movie
+    /// starts from the current state, otherwise the system must not have started running yet.
+    /// Stops any movie playback, dropping its remaining events.
+    pub fn start_recording(&mut self) {
+        if self.is_playing_movie {
+            self.system.joypad_mut().clear_pending_events();
+            self.is_playing_movie = false;
+        }
+        #[cfg(feature = "serialize")]
+        let movie = if self.system.cycles() == 0 {
+            Movie::from_power_on()
+        } else {
+            Movie::from_state(&self.system)
+        };
+        #[cfg(not(feature = "serialize"))]
+        let movie = Movie::from_power_on();
+        #[cfg(feature = "serialize")]
+        {
+            self.recording_start = self.system.cycles();
+        }
+        self.recording = Some(movie);
+    }
+
+    /// Stops recording, returning the recorded movie.
+    pub fn stop_recording(&mut self) -> Option<Movie> {
+        self.recording.take()
+    }
+
+    /// Sets the colors screens are returned with. Ignored by `PixelFormat::Shade`.
+    pub fn set_palettes(&mut self, palettes: DisplayPalettes) {
+        self.palettes = palettes;
+    }
+
+    pub fn palettes(&self) -> &DisplayPalettes {
+        &self.palettes
+    }
+
+    /// Sets the post-processing of screens, see `gpu::filter`. Ignored by `PixelFormat::Shade`.
+    pub fn set_filters(&mut self, filters: Filters) {
+        debug_assert!(filters.scale >= 1);
+        if !filters.blend_frames {
+            self.previous_frame = None;
+        }
+        self.filters = filters;
+    }
+
+    pub fn filters(&self) -> &Filters {
+        &self.filters
+    }
+
+    /// The current screen with the palettes and filters applied, e.g. to save it as a PNG.
+    pub fn screenshot(&self) -> Image {
+        self.filters.apply(&self.frame_image(), self.previous_frame.as_ref())
+    }
+
+    fn screen_size(&self) -> (usize, usize) {
+        if self.pixel_format == PixelFormat::Shade {
+            (LCD_WIDTH, LCD_HEIGHT)
+        } else {
+            self.filters.output_size(LCD_WIDTH, LCD_HEIGHT)
+        }
+    }
+
+    /// The current screen with the palettes applied.
+    fn frame_image(&self) -> Image {
+        let screen = self.system.screen().iter().zip(self.system.screen_layers());
+        let pixels = screen.map(|(&color, &layer)| self.palettes.for_layer(layer).pixel(color));
+        Image { width: LCD_WIDTH, height: LCD_HEIGHT, pixels: pixels.collect() }
+    }
+
+    pub fn system(&self) -> &System {
+        &self.system
+    }
+
+    /// Records what the PPU does on every scanline, see `gpu::scanline_log`.
+    pub fn start_scanline_log(&mut self) {
+        self.system.start_scanline_log();
+    }
+
+    pub fn stop_scanline_log(&mut self) -> Option<ScanlineLog> {
+        self.system.stop_scanline_log()
+    }
+
+    /// Starts playing back `movie`. User key presses are ignored until the movie's last event
+    /// happened, or a new recording starts.
+    pub fn play_movie(&mut self, movie: &Movie) -> Result<()> {
+        movie.start(&mut self.system)?;
+        self.is_playing_movie = true;
+        self.previous_frame = None;
+        Ok(())
     }
 }
 
@@ -29,50 +258,245 @@ impl Simulator {
         let cart = crate::cart::from_file_contents(cart_bytes);
         let mut system = System::new_complete();
         system.set_cart(cart);
-        Simulator { system, time_accum: 0. }
+        Simulator::with_system(system)
     }
 
-    /// Updates the internal simulator state by dt seconds. The state is updated in chunks of
-    /// simulated "frames", i.e. one simulated 16.66ms block. Will therefore produce 0 or more of
-    /// those chunks. If at least one frame was simulated, will return the latest system screen.
+    /// Updates the internal simulator state by dt seconds (scaled by the speed multiplier). The
+    /// state is updated in chunks of simulated "frames", i.e. one simulated 16.66ms block. Will
+    /// therefore produce 0 or more of those chunks. If at least one frame was simulated, will
+    /// return the latest system screen. Does nothing while paused.
     pub fn update(&mut self, dt: f32) -> Option<Box<[u8]>> {
+        if self.is_paused {
+            return None;
+        }
+        if self.speed.is_infinite() {
+            self.run_unthrottled();
+            return Some(self.screen_bytes());
+        }
         // Accumulate passed time. Make sure not to fall back behind by more than one second.
-        self.time_accum += dt.min(1.);
+        self.time_accum += dt.min(1.) * self.speed;
         // Simulate the system in discrete 16.66ms chunks.
-        const STEP_SIZE: f32 = 1. / 60.;
         // Either return a screen, or early out (saves having to copy multiple screens if stepping
         // for more than one frame).
         if self.time_accum >= STEP_SIZE {
             while self.time_accum >= STEP_SIZE {
-                self.simulate_frame();
+                self.step_frame();
                 self.time_accum -= STEP_SIZE;
             }
-            let mut arr = [0; LCD_WIDTH * LCD_HEIGHT * 4];
-
-            for (i, pixel) in self.system.screen().iter().map(Pixel::from).enumerate() {
-                if cfg!(target_arch = "wasm32") {
-                    arr[i * 4] = pixel.r;
-                    arr[i * 4 + 1] = pixel.g;
-                    arr[i * 4 + 2] = pixel.b;
-                    arr[i * 4 + 3] = pixel.a;
-                } else {
-                    arr[i * 4] = pixel.b;
-                    arr[i * 4 + 1] = pixel.g;
-                    arr[i * 4 + 2] = pixel.r;
-                    arr[i * 4 + 3] = pixel.a;
-                }
-            }
-            Some(Box::from(arr))
+            Some(self.screen_bytes())
         } else {
             None
         }
     }
 
+    /// Sets the speed multiplier, e.g. 2.0 for double speed. Pass infinity to run as fast as
+    /// possible.
+    pub fn set_speed(&mut self, speed: f32) {
+        debug_assert!(speed > 0.);
+        self.speed = speed;
+        self.time_accum = 0.;
+        if !self.is_paused {
+            self.system.set_audio_speed(speed);
+        }
+    }
+
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    pub fn pause(&mut self) {
+        self.is_paused = true;
+        self.time_accum = 0.;
+        self.system.set_audio_speed(0.);
+    }
+
+    pub fn resume(&mut self) {
+        self.is_paused = false;
+        self.system.set_audio_speed(self.speed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.is_paused
+    }
+
+    /// Sets the layout of the screens returned by `update` and `advance_frame`. Defaults to BGRA8.
+    pub fn set_pixel_format(&mut self, format: PixelFormat) {
+        self.pixel_format = format;
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+
+    /// Turns frame blending on or off, see `Filters::blend_frames`.
+    pub fn set_frame_blending(&mut self, is_enabled: bool) {
+        self.set_filters(Filters { blend_frames: is_enabled, ..self.filters });
+    }
+
+    pub fn set_scaler(&mut self, scaler: Scaler) {
+        self.set_filters(Filters { scaler, ..self.filters });
+    }
+
+    /// Sets the integer scale applied after the scaler.
+    pub fn set_scale(&mut self, scale: usize) {
+        self.set_filters(Filters { scale: scale.max(1), ..self.filters });
+    }
+
+    /// The width of the screens returned, which depends on the filters.
+    pub fn screen_width(&self) -> usize {
+        self.screen_size().0
+    }
+
+    pub fn screen_height(&self) -> usize {
+        self.screen_size().1
+    }
+
+    /// Returns the current screen, e.g. to redraw it after changing the filters while paused.
+    pub fn screen(&self) -> Box<[u8]> {
+        self.screen_bytes()
+    }
+
+    /// Pauses the simulation (if it isn't already), and advances it by exactly one frame. Returns
+    /// the new screen.
+    pub fn advance_frame(&mut self) -> Box<[u8]> {
+        if !self.is_paused {
+            self.pause();
+        }
+        self.step_frame();
+        self.screen_bytes()
+    }
+
+    fn screen_bytes(&self) -> Box<[u8]> {
+        if self.filters.is_identity() || self.pixel_format == PixelFormat::Shade {
+            let screen = self.system.screen();
+            let layers = self.system.screen_layers();
+            palette::encode(screen, layers, &self.palettes, self.pixel_format).into_boxed_slice()
+        } else {
+            palette::encode_pixels(&self.screenshot().pixels, self.pixel_format).into_boxed_slice()
+        }
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn run_unthrottled(&mut self) {
+        let start = std::time::Instant::now();
+        while start.elapsed() < UNTHROTTLED_TIME_SLICE {
+            self.step_frame();
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn run_unthrottled(&mut self) {
+        for _ in 0..UNTHROTTLED_FRAMES {
+            self.step_frame();
+        }
+    }
+
+    /// Hands off all pending audio samples to the output. Call before exiting to make sure
+    /// recordings are complete.
+    pub fn flush_audio(&mut self) {
+        self.system.flush_audio();
+    }
+
+    /// Collects audio at `sample_rate` for `take_audio_samples`, instead of sending it to the
+    /// audio device. For frontends that play the audio themselves, like the web demo.
+    pub fn enable_audio_buffer(&mut self, _sample_rate: u32) {
+        #[cfg(feature = "audio")]
+        {
+            let buffer = crate::apu::output::MemoryOutput::default();
+            self.system.set_audio_output(Box::new(buffer.clone()));
+            self.system.set_audio_sample_rate(_sample_rate);
+            self.audio_buffer = Some(buffer);
+        }
+    }
+
+    /// Returns the stereo samples produced since the last call, interleaved: left, right, left,
+    /// and so on. Empty unless enabled with `enable_audio_buffer`.
+    pub fn take_audio_samples(&mut self) -> Box<[f32]> {
+        #[cfg(feature = "audio")]
+        let samples = self
+            .audio_buffer
+            .as_ref()
+            .map(|x| x.take().frames.iter().flat_map(|x| x.iter().cloned()).collect())
+            .unwrap_or_default();
+        #[cfg(not(feature = "audio"))]
+        let samples = Box::new([]);
+        samples
+    }
+
+    /// Leaves an audio channel (0 to 3: square 1, square 2, wave, noise) out of the mix.
+    pub fn set_channel_muted(&mut self, channel: usize, is_muted: bool) {
+        self.system.set_channel_muted(channel, is_muted);
+    }
+
+    pub fn is_channel_muted(&self, channel: usize) -> bool {
+        self.system.is_channel_muted(channel)
+    }
+
+    /// While any audio channel is soloed, only soloed channels are heard.
+    pub fn set_channel_soloed(&mut self, channel: usize, is_soloed: bool) {
+        self.system.set_channel_soloed(channel, is_soloed);
+    }
+
+    pub fn is_channel_soloed(&self, channel: usize) -> bool {
+        self.system.is_channel_soloed(channel)
+    }
+
+    /// Starts or stops producing a separate sample stream for every audio channel, e.g. to draw
+    /// per-channel oscilloscopes. Muting doesn't affect these streams.
+    pub fn enable_channel_taps(&mut self, is_enabled: bool) {
+        self.system.enable_channel_taps(is_enabled);
+    }
+
+    /// Returns the per-channel samples produced since the last call, interleaved: square 1,
+    /// square 2, wave and noise for the first sample, then for the second, and so on.
+    pub fn take_channel_samples(&mut self) -> Box<[f32]> {
+        #[cfg(feature = "audio")]
+        let samples = {
+            let frames = self.system.take_channel_samples();
+            frames.iter().flat_map(|x| x.iter().cloned()).collect()
+        };
+        #[cfg(not(feature = "audio"))]
+        let samples = Box::new([]);
+        samples
+    }
+
     pub fn press_key(&mut self, key: Key) {
-        self.system.joypad_mut().press(key);
+        self.queue_key_event(key, true);
     }
     pub fn release_key(&mut self, key: Key) {
-        self.system.joypad_mut().release(key);
+        self.queue_key_event(key, false);
+    }
+
+    fn queue_key_event(&mut self, key: Key, pressed: bool) {
+        if self.is_playing_movie {
+            return;
+        }
+        let event = KeyEvent { cycle: self.system.cycles(), key, pressed };
+        if let Some(movie) = &mut self.recording {
+            movie.record(event);
+        }
+        self.system.joypad_mut().queue_event(event);
+    }
+
+    /// Advances one frame, or goes back one frame when rewinding.
+    fn step_frame(&mut self) {
+        #[cfg(feature = "serialize")]
+        {
+            if self.is_rewinding {
+                self.rewind(1);
+                return;
+            }
+        }
+        if self.filters.blend_frames {
+            self.previous_frame = Some(self.frame_image());
+        }
+        self.simulate_frame();
+        if self.is_playing_movie && !self.system.joypad().has_pending_events() {
+            self.is_playing_movie = false;
+        }
+        self.frame += 1;
+        #[cfg(feature = "serialize")]
+        self.take_rewind_snapshot();
     }
 
     fn simulate_frame(&mut self) {
@@ -84,3 +508,120 @@ impl Simulator {
         }
     }
 }
+
+#[cfg(test)]
+#[cfg(feature = "serialize")]
+mod test {
+    use super::*;
+    use crate::test::with_default;
+
+    fn make_simulator() -> Simulator {
+        let mut simulator = Simulator::with_system(*with_default().wait_for_vsync().system);
+        simulator.enable_rewind(1 << 20);
+        simulator
+    }
+
+    #[test]
+    fn test_rewind_truncates_recording() {
+        let mut simulator = make_simulator();
+        simulator.step_frame();
+        simulator.step_frame();
+        simulator.start_recording();
+        simulator.press_key(Key::A);
+        simulator.step_frame();
+        simulator.step_frame();
+        simulator.step_frame();
+        simulator.press_key(Key::B);
+        assert_eq!(simulator.rewind(1), 1);
+        // Recording goes on from the restored cycle.
+        simulator.press_key(Key::Start);
+        let keys: Vec<Key> =
+            simulator.stop_recording().unwrap().events().iter().map(|x| x.key).collect();
+        assert_eq!(keys, [Key::A, Key::Start]);
+    }
+
+    #[test]
+    fn test_rewind_past_recording_start() {
+        let mut simulator = make_simulator();
+        for _ in 0..4 {
+            simulator.step_frame();
+        }
+        simulator.start_recording();
+        simulator.press_key(Key::A);
+        simulator.step_frame();
+        assert_eq!(simulator.rewind(3), 3);
+        simulator.press_key(Key::B);
+        let movie = simulator.stop_recording().unwrap();
+        assert_eq!(movie.events().len(), 1);
+        assert_eq!(movie.events()[0].key, Key::B);
+    }
+
+    fn make_movie(simulator: &Simulator) -> Movie {
+        let mut movie = Movie::from_state(&simulator.system);
+        let cycle = simulator.system.cycles() + 4;
+        movie.record(KeyEvent { cycle, key: Key::A, pressed: true });
+        movie
+    }
+
+    #[test]
+    fn test_playback_ends_after_last_event() {
+        let mut simulator = make_simulator();
+        simulator.play_movie(&make_movie(&simulator)).unwrap();
+        simulator.press_key(Key::B);
+        assert!(simulator.is_playing_movie);
+        simulator.step_frame();
+        assert!(!simulator.is_playing_movie);
+        simulator.press_key(Key::B);
+        assert!(simulator.system.joypad().has_pending_events());
+    }
+
+    #[test]
+    fn test_recording_ends_playback() {
+        let mut simulator = make_simulator();
+        simulator.play_movie(&make_movie(&simulator)).unwrap();
+        simulator.start_recording();
+        simulator.press_key(Key::B);
+        let events = simulator.stop_recording().unwrap().events().to_vec();
+        assert_eq!(events.iter().map(|x| x.key).collect::<Vec<_>>(), [Key::B]);
+    }
+
+    #[test]
+    #[cfg(feature = "audio")]
+    fn test_rewind_restores_apu() {
+        let mut simulator = make_simulator();
+        simulator.system.set_audio_output(Box::new(crate::apu::output::NullOutput));
+        simulator.system.memory_write(0xFF26, 0x80);
+        simulator.system.memory_write(0xFF24, 0x77);
+        simulator.step_frame();
+        simulator.step_frame();
+        simulator.step_frame();
+        simulator.system.memory_write(0xFF24, 0x00);
+        assert_eq!(simulator.rewind(1), 1);
+        assert_eq!(simulator.system.peek(0xFF24), Some(0x77));
+    }
+
+    #[test]
+    fn test_rewind_clears_previous_frame() {
+        let mut simulator = make_simulator();
+        simulator.set_frame_blending(true);
+        simulator.step_frame();
+        simulator.step_frame();
+        simulator.set_rewinding(true);
+        simulator.step_frame();
+        assert!(simulator.previous_frame.is_none());
+        simulator.set_rewinding(false);
+        simulator.step_frame();
+        assert!(simulator.previous_frame.is_some());
+    }
+
+    #[test]
+    fn test_play_movie_clears_previous_frame() {
+        let mut simulator = make_simulator();
+        simulator.set_frame_blending(true);
+        simulator.step_frame();
+        let movie = Movie::from_state(&simulator.system);
+        simulator.step_frame();
+        simulator.play_movie(&movie).unwrap();
+        assert!(simulator.previous_frame.is_none());
+    }
+}
@@ -54,12 +54,20 @@ pub struct System {
     dma: dma::Dma,
     joypad: joypad::Joypad,
 
+    /// The number of T-cycles executed since power-on.
+    cycles: u64,
+    /// See `set_strict`.
     #[cfg_attr(feature = "serialize", serde(skip))]
+    is_strict: bool,
+
     #[cfg(feature = "audio")]
     apu: Option<crate::apu::Apu>,
 
     #[cfg_attr(feature = "serialize", serde(skip))]
     screen: Vec<Color>,
+    /// The palette register every screen pixel was shaded with.
+    #[cfg_attr(feature = "serialize", serde(skip))]
+    screen_layers: Vec<gpu::Layer>,
     pub cart: Option<Box<dyn Cart>>,
 }
 
@@ -86,7 +94,10 @@ impl Default for System {
             serial: serial::Controller::new(),
             dma: dma::Dma::new(),
             joypad: joypad::Joypad::default(),
+            cycles: 0,
+            is_strict: false,
             screen: vec![Color::Black; (gpu::LCD_WIDTH * gpu::LCD_HEIGHT) as usize],
+            screen_layers: vec![gpu::Layer::Bg; (gpu::LCD_WIDTH * gpu::LCD_HEIGHT) as usize],
             cart: None,
             #[cfg(feature = "audio")]
             apu: None,
@@ -108,11 +119,45 @@ impl System {
 
     pub fn restore_from_deserialize(&mut self) {
         self.screen = vec![Color::Black; (gpu::LCD_WIDTH * gpu::LCD_HEIGHT) as usize];
+        self.screen_layers = vec![gpu::Layer::Bg; (gpu::LCD_WIDTH * gpu::LCD_HEIGHT) as usize];
+        #[cfg(feature = "audio")]
+        {
+            match &mut self.apu {
+                Some(apu) => apu.set_output(crate::apu::output::default_device()),
+                None => self.apu = Some(Default::default()),
+            }
+        }
+    }
+
+    /// Serializes the complete emulated state. The screen and audio output are not included.
+    #[cfg(feature = "serialize")]
+    pub fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("System state should always be serializable")
+    }
+
+    /// Replaces the emulated state with one produced by `save_state`. The current screen buffer
+    /// and audio output are kept, see `Apu::restore_state`.
+    #[cfg(feature = "serialize")]
+    pub fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        let mut system: System = bincode::deserialize(state).map_err(|err| {
+            error::Type::InvalidOperation(format!("Could not load state: {}", err))
+        })?;
+        system.screen = std::mem::take(&mut self.screen);
+        system.screen_layers = std::mem::take(&mut self.screen_layers);
         #[cfg(feature = "audio")]
         {
-            self.apu = Some(Default::default());
+            system.apu = self.apu.take().map(|mut apu| {
+                let state = system.apu.take().unwrap_or_else(|| {
+                    crate::apu::Apu::with_output(Box::new(crate::apu::output::NullOutput))
+                });
+                apu.restore_state(state);
+                apu
+            });
         }
+        *self = system;
+        Ok(())
     }
+
     pub fn set_cart(&mut self, cart: Box<dyn Cart>) {
         self.cart = Some(cart);
     }
@@ -126,11 +171,150 @@ impl System {
     pub fn screen(&self) -> &[Color] {
         &self.screen
     }
+    pub fn screen_mut(&mut self) -> &mut [Color] {
+        &mut self.screen
+    }
+    pub fn screen_layers(&self) -> &[gpu::Layer] {
+        &self.screen_layers
+    }
+    pub fn screen_layers_mut(&mut self) -> &mut [gpu::Layer] {
+        &mut self.screen_layers
+    }
+
+    pub fn start_scanline_log(&mut self) {
+        self.gpu.start_scanline_log();
+    }
+    pub fn stop_scanline_log(&mut self) -> Option<gpu::scanline_log::ScanlineLog> {
+        self.gpu.stop_scanline_log()
+    }
+
+    /// Tells the audio output how fast emulation runs relative to real time, so that it can keep
+    /// playing at the right pitch. 0 when paused.
+    pub fn set_audio_speed(&mut self, _speed: f32) {
+        #[cfg(feature = "audio")]
+        {
+            if let Some(apu) = &mut self.apu {
+                apu.set_speed(_speed);
+            }
+        }
+    }
+
+    /// Replaces where audio samples go. Creates the APU if the system doesn't have one yet.
+    #[cfg(feature = "audio")]
+    pub fn set_audio_output(&mut self, output: Box<dyn crate::apu::output::AudioOutput>) {
+        match &mut self.apu {
+            Some(apu) => apu.set_output(output),
+            None => self.apu = Some(crate::apu::Apu::with_output(output)),
+        }
+    }
+
+    /// Sends audio samples to `output` in addition to the current output.
+    #[cfg(feature = "audio")]
+    pub fn add_audio_output(&mut self, output: Box<dyn crate::apu::output::AudioOutput>) {
+        match &mut self.apu {
+            Some(apu) => apu.add_output(output),
+            None => self.apu = Some(crate::apu::Apu::with_output(output)),
+        }
+    }
+
+    /// Changes the rate audio samples are produced at. Creates the APU if the system doesn't have
+    /// one yet.
+    #[cfg(feature = "audio")]
+    pub fn set_audio_sample_rate(&mut self, rate: u32) {
+        self.apu.get_or_insert_with(Default::default).set_sample_rate(rate);
+    }
+
+    /// Leaves an audio channel (0 to 3: square 1, square 2, wave, noise) out of the mix.
+    pub fn set_channel_muted(&mut self, _channel: usize, _is_muted: bool) {
+        #[cfg(feature = "audio")]
+        {
+            if let Some(apu) = &mut self.apu {
+                apu.set_channel_muted(_channel, _is_muted);
+            }
+        }
+    }
+
+    pub fn is_channel_muted(&self, _channel: usize) -> bool {
+        #[cfg(feature = "audio")]
+        {
+            if let Some(apu) = &self.apu {
+                return apu.is_channel_muted(_channel);
+            }
+        }
+        false
+    }
+
+    /// While any audio channel is soloed, only soloed channels are mixed.
+    pub fn set_channel_soloed(&mut self, _channel: usize, _is_soloed: bool) {
+        #[cfg(feature = "audio")]
+        {
+            if let Some(apu) = &mut self.apu {
+                apu.set_channel_soloed(_channel, _is_soloed);
+            }
+        }
+    }
+
+    pub fn is_channel_soloed(&self, _channel: usize) -> bool {
+        #[cfg(feature = "audio")]
+        {
+            if let Some(apu) = &self.apu {
+                return apu.is_channel_soloed(_channel);
+            }
+        }
+        false
+    }
+
+    /// Starts or stops producing a separate sample stream for every audio channel.
+    pub fn enable_channel_taps(&mut self, _is_enabled: bool) {
+        #[cfg(feature = "audio")]
+        {
+            if let Some(apu) = &mut self.apu {
+                apu.enable_channel_taps(_is_enabled);
+            }
+        }
+    }
+
+    /// Returns the per-channel samples produced since the last call, at the APU's sample rate.
+    #[cfg(feature = "audio")]
+    pub fn take_channel_samples(&mut self) -> Vec<crate::apu::ChannelFrame> {
+        self.apu.as_mut().map(|x| x.take_channel_samples()).unwrap_or_default()
+    }
+
+    /// Hands off all pending audio samples to the output.
+    pub fn flush_audio(&mut self) {
+        #[cfg(feature = "audio")]
+        {
+            if let Some(apu) = &mut self.apu {
+                apu.flush();
+            }
+        }
+    }
+
+    pub fn joypad(&self) -> &joypad::Joypad {
+        &self.joypad
+    }
 
     pub fn joypad_mut(&mut self) -> &mut joypad::Joypad {
         &mut self.joypad
     }
 
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// In strict mode, CPU accesses to addresses nothing is mapped to are logged as warnings, on the
+    /// `memory` target. Either way they behave like on hardware: writes are ignored, and reads
+    /// return 0xFF (0x00 for unused OAM while OAM isn't blocked).
+    pub fn set_strict(&mut self, is_strict: bool) {
+        self.is_strict = is_strict;
+    }
+
+    /// Reads `raw_address` from the bus without affecting the emulation, e.g. to check the results
+    /// test ROMs leave in memory.
+    pub fn peek(&self, raw_address: i32) -> Option<i32> {
+        self.read_request(raw_address).ok()
+    }
+
     fn read_request(&self, raw_address: i32) -> Result<i32> {
         let modules: &[Option<&dyn mmu::MemoryMapped>] = &[
             Some(&self.timer),
@@ -173,8 +357,37 @@ impl System {
         Err(error::Type::TODOMemoryBus)
     }
 
-    fn is_invalid_source_address(address: i32) -> bool {
-        address >= 0xFE00 && address < 0xFEA0
+    /// LCDC, STAT, LY and LYC are read from the bus by the GPU, rather than through `read_request`.
+    fn is_bus_register(address: i32) -> bool {
+        [0xFF40, 0xFF41, 0xFF44, 0xFF45].contains(&address)
+    }
+
+    fn is_unmapped(&self, address: i32) -> bool {
+        match mmu::Address::from_raw(address) {
+            Ok(mmu::Address(mmu::Location::UnusedOAM, _))
+            | Ok(mmu::Address(mmu::Location::UnknownRegisters, _)) => true,
+            _ => !System::is_bus_register(address) && self.read_request(address).is_err(),
+        }
+    }
+
+    /// Warns about the M-cycle the CPU just finished if it accessed an unmapped address, see
+    /// `set_strict`.
+    fn report_unmapped_access(&self) {
+        let used = self.cpu.state.address_use;
+        let address = self.cpu.state.address_latch;
+        if (used.read || used.write) && self.is_unmapped(address) {
+            let access = if used.write { "Write to" } else { "Read from" };
+            let pc = self.cpu.registers.get(cpu::register::Register::PC);
+            warn!(target: "memory", "{} unmapped address {:04X} near PC {:04X}.", access, address, pc);
+        }
+    }
+
+    /// How the address the CPU is using conflicts with DMA, see `mmu::Conflict`.
+    fn dma_conflict(&self) -> mmu::Conflict {
+        match self.dma.source_address() {
+            Some(source) => mmu::Conflict::between(self.cpu.state.address_latch, source),
+            None => mmu::Conflict::None,
+        }
     }
 
     fn handle_cpu_memory_reads(&mut self) -> Result<()> {
@@ -182,32 +395,20 @@ impl System {
         let t_state = self.cpu.t_state.get();
         if self.cpu.state.read_latch {
             if t_state >= 2 {
-                // During DMA, reads return 0xFF.
-                let maybe_data = if self.dma.is_active()
-                    && System::is_invalid_source_address(self.cpu.state.address_latch)
-                    && self.cpu.state.address_latch > 0x100
-                {
-                    strict_fail!("Reading during dma {:X}", self.cpu.state.address_latch);
-                    Ok(0xFF)
-                } else {
-                    self.read_request(self.cpu.state.address_latch)
-                };
-                if let Ok(value) = maybe_data {
-                    self.cpu.state.data_latch = value;
-                } else {
-                    match self.cpu.state.address_latch {
-                        // TODO: Cleanup. These registers are provided from the MemoryBus hacky
-                        // system.
-                        0xFF41 | 0xFF40 | 0xFF45 | 0xFF44 => (),
-                        _ => {
-                            #[cfg(debug_assertions)]
-                            eprintln!(
-                                "Could not find any memory module accepting {:X}.",
-                                self.cpu.state.address_latch
-                            );
-                            panic!()
-                        }
+                let maybe_data = match self.dma_conflict() {
+                    mmu::Conflict::None => self.read_request(self.cpu.state.address_latch),
+                    mmu::Conflict::Source => self.read_request(self.dma.source_address().unwrap()),
+                    mmu::Conflict::Destination => {
+                        strict_fail!("Reading during dma {:X}", self.cpu.state.address_latch);
+                        Ok(0xFF)
                     }
+                };
+                match maybe_data {
+                    Ok(value) => self.cpu.state.data_latch = value,
+                    // TODO: Cleanup. These registers are provided from the MemoryBus hacky system.
+                    Err(_) if System::is_bus_register(self.cpu.state.address_latch) => (),
+                    // Open bus.
+                    Err(_) => self.cpu.state.data_latch = 0xFF,
                 }
             } else if false {
                 // Write garbage in data latch to catch bad reads.
@@ -220,21 +421,22 @@ impl System {
     fn handle_cpu_memory_writes(&mut self) -> Result<()> {
         // Service write requests at T=4's rising edge.
         if self.cpu.state.write_latch {
+            let conflict = self.dma_conflict();
             strict_assert!(
-                !self.dma.is_active(),
+                conflict == mmu::Conflict::None,
                 "Attempting to write to {:X} while DMA is active.",
                 self.cpu.state.address_latch
             );
-            if self.dma.is_active() {
-                return Ok(());
-            }
             debug_assert!(util::is_16bit(self.cpu.state.address_latch));
             debug_assert!(util::is_8bit(self.cpu.state.data_latch));
-            if self.cpu.t_state.get() == 4
-                && !(self.dma.is_active()
-                    && System::is_invalid_source_address(self.cpu.state.address_latch))
-            {
-                self.write_request(self.cpu.state.address_latch, self.cpu.state.data_latch)?;
+            if self.cpu.t_state.get() == 4 && conflict == mmu::Conflict::None {
+                let address = self.cpu.state.address_latch;
+                match self.write_request(address, self.cpu.state.data_latch) {
+                    // Writes to unmapped addresses go nowhere. Strict mode reports them, see
+                    // `report_unmapped_access`.
+                    Err(error::Type::TODOMemoryBus) if self.is_unmapped(address) => (),
+                    result => result?,
+                }
             }
         }
         Ok(())
@@ -247,8 +449,10 @@ impl System {
     }
 
     fn temp_hack_get_bus(&self) -> mmu::MemoryBus {
-        let is_dma =
-            self.dma.is_active() && System::is_invalid_source_address(self.cpu.state.address_latch);
+        // Accesses that collide with the DMA transfer never reach a device, whichever bus the
+        // source is on. The GPU and DMA only decode their registers, which are on the internal
+        // bus DMA never uses, so masking the others cannot hide a register access from them.
+        let is_dma = self.dma_conflict() != mmu::Conflict::None;
         mmu::MemoryBus {
             address_latch: self.cpu.state.address_latch,
             data_latch: self.cpu.state.data_latch,
@@ -276,6 +480,21 @@ impl System {
         }
     }
 
+    /// Corrupts OAM if the M-cycle the CPU just finished used an address in 0xFE00-0xFEFF during the
+    /// OAM scan, see `gpu::oam_bug`.
+    fn handle_oam_bug(&mut self) {
+        use gpu::oam_bug::Access;
+        if !(0xFE00..=0xFEFF).contains(&self.cpu.state.address_latch) {
+            return;
+        }
+        let used = self.cpu.state.address_use;
+        if used.read {
+            self.gpu.corrupt_oam(if used.idu { Access::ReadIncrease } else { Access::Read });
+        } else if used.write || used.idu {
+            self.gpu.corrupt_oam(Access::Write);
+        }
+    }
+
     fn handle_timer(&mut self) -> Result<()> {
         let bus = self.temp_hack_get_bus();
         let (new_timer, should_interrupt) = self.timer.execute_tcycle(&bus);
@@ -297,6 +516,7 @@ impl System {
             self.cpu.t_state.get_as_tstate(),
             &mut bus,
             &mut self.screen,
+            &mut self.screen_layers,
         );
         if self.gpu.at_vblank() {
             self.maybe_fire_interrupt(Interrupts::VBLANK);
@@ -305,8 +525,15 @@ impl System {
         self.maybe_fire_interrupt(should_interrupt);
     }
 
+    #[cfg(feature = "audio")]
+    fn handle_apu(&mut self) {
+        if let Some(apu) = &mut self.apu {
+            apu.execute_tcycle(self.cycles, self.timer.frame_sequencer_input());
+        }
+    }
+
     fn handle_joypad(&mut self) {
-        let should_interrupt = self.joypad.execute_tcycle();
+        let should_interrupt = self.joypad.execute_tcycle(self.cycles);
         self.maybe_fire_interrupt(should_interrupt);
     }
 
@@ -317,7 +544,15 @@ impl System {
         self.handle_cpu_memory_reads()?;
         self.handle_gpu();
         self.cpu.execute_t_cycle(&mut self.memory, self.gpu.hack())?;
+        if self.cpu.t_state.get() == 4 {
+            self.handle_oam_bug();
+            if self.is_strict {
+                self.report_unmapped_access();
+            }
+        }
         self.handle_timer()?;
+        #[cfg(feature = "audio")]
+        self.handle_apu();
         let new_serial = self.handle_serial();
 
         self.handle_joypad();
@@ -327,6 +562,7 @@ impl System {
         // Last step is DMA.
         self.handle_dma()?;
         self.cpu.t_state.inc();
+        self.cycles += 1;
 
         Ok(())
     }
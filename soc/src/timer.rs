@@ -110,6 +110,12 @@ impl Timer {
         }
     }
 
+    /// DIV bit 12. The APU's frame sequencer steps on its falling edge, including the one caused
+    /// by writing to DIV.
+    pub fn frame_sequencer_input(&self) -> bool {
+        is_bit_set(*self.div, 12)
+    }
+
     /// Tries to emulate the internal behavior of the timer as much possible (mostly to accurately
     /// implement unintended consequences and glitches!).
     fn edge_detector_input(&self) -> bool {
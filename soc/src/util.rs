@@ -1,6 +1,5 @@
-/// Hodgepodge of util classes. Mostly integer stuff. Timers used by audio. Atomic trait.
+/// Hodgepodge of util classes. Mostly integer stuff. Timers used by audio.
 use num_traits::PrimInt;
-use std::sync::atomic::Ordering;
 
 macro_rules! strict_fail {
     ($($vals:expr),*) => {
@@ -99,46 +98,3 @@ pub fn iterate_bits<T: PrimInt>(mut value: T) -> impl Iterator<Item = bool> {
         }
     })
 }
-
-/// Helpful trait to allow using atomics with any data type convertable from/to primitive integers.
-/// Used mainly with the audio driver.
-pub trait AtomicInt<T: PrimInt> {
-    fn weak_update_with<U: From<T> + Into<T>>(
-        &self,
-        success: Ordering,
-        with: impl Fn(U) -> U,
-    ) -> U {
-        let mut current = self.load_relaxed();
-        loop {
-            let new = with(current.into()).into();
-            match self.compare_exchange_weak_relaxed(current, new, success) {
-                Ok(_) => break current.into(),
-                Err(x) => current = x,
-            }
-        }
-    }
-    fn load_relaxed(&self) -> T;
-    fn compare_exchange_weak_relaxed(&self, current: T, new: T, ordering: Ordering)
-        -> Result<T, T>;
-}
-
-macro_rules! impl_weak_atomic {
-    ($tprim:ty, $tatomic:ty) => {
-        impl AtomicInt<$tprim> for $tatomic {
-            fn load_relaxed(&self) -> $tprim {
-                self.load(Ordering::Relaxed)
-            }
-            fn compare_exchange_weak_relaxed(
-                &self,
-                current: $tprim,
-                new: $tprim,
-                ordering: Ordering,
-            ) -> Result<$tprim, $tprim> {
-                self.compare_exchange_weak(current, new, ordering, Ordering::Relaxed)
-            }
-        }
-    };
-}
-
-impl_weak_atomic!(u8, std::sync::atomic::AtomicU8);
-impl_weak_atomic!(u64, std::sync::atomic::AtomicU64);
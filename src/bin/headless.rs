@@ -0,0 +1,247 @@
+#![warn(warnings)]
+#![deny(clippy::all)]
+
+//! Runs a ROM without a window or an audio device. Useful for regression tests on machines without
+//! either.
+//!
+//! `headless gbs <file.gbs> --record-audio <file.wav>` instead renders a track of a GBS music rip
+//! (`--track`, 1-based, defaults to the rip's first song) for `--seconds` seconds.
+//!
+//! `--dump-vram <dir>` writes the tile sheet, both tile maps and the sprites as PNGs into `dir` at
+//! the end of the run, along with the decoded OAM in `objects.txt`. `--ppu-log <file.json>` logs
+//! the PPU registers, writes, modes and sprites of every scanline of the last frame.
+//!
+//! `--blend`, `--scaler <none|scale2x|scale3x>` and `--scale <n>` post-process the screen, see
+//! `gpu::filter`. `--screenshot <file.png>` saves the last screen, filtered, at the end of the run.
+//!
+//! `--strict` warns about every access to unmapped memory, see `System::set_strict`.
+
+use soc::cart;
+#[cfg(feature = "audio")]
+use soc::gbs;
+use soc::gpu::filter::{Filters, Scaler};
+use soc::gpu::viewer;
+use soc::log;
+use soc::movie;
+use soc::sim;
+use soc::system;
+
+struct Opt {
+    cart_path: std::path::PathBuf,
+    /// How many frames to run for.
+    frames: u64,
+    play_movie: Option<std::path::PathBuf>,
+    dump_vram: Option<std::path::PathBuf>,
+    ppu_log: Option<std::path::PathBuf>,
+    screenshot: Option<std::path::PathBuf>,
+    filters: Filters,
+    strict: bool,
+    #[cfg(feature = "audio")]
+    record_audio: Option<std::path::PathBuf>,
+    /// Set in `gbs` mode, in which case cart_path is a GBS file.
+    gbs: Option<GbsOpt>,
+}
+
+struct GbsOpt {
+    /// 1-based, as numbered by most players.
+    track: Option<u8>,
+    seconds: u64,
+}
+
+impl Opt {
+    fn from_args(mut args: pico_args::Arguments) -> Result<Opt, pico_args::Error> {
+        // Only a leading `gbs` is a subcommand. Anything else is the cart path, unless options
+        // come first, in which case it is taken once they are all parsed.
+        let first = args.subcommand()?;
+        let is_gbs = first.iter().any(|x| x == "gbs");
+        let gbs = if is_gbs {
+            Some(GbsOpt {
+                track: args.opt_value_from_str("--track")?,
+                seconds: args.opt_value_from_str("--seconds")?.unwrap_or(60),
+            })
+        } else {
+            None
+        };
+        let frames = args.opt_value_from_str("--frames")?;
+        let opt = Opt {
+            frames: frames.unwrap_or(60 * 60),
+            play_movie: args.opt_value_from_str("--play_movie")?,
+            dump_vram: args.opt_value_from_str("--dump-vram")?,
+            ppu_log: args.opt_value_from_str("--ppu-log")?,
+            screenshot: args.opt_value_from_str("--screenshot")?,
+            filters: Filters {
+                blend_frames: args.contains("--blend"),
+                scaler: args.opt_value_from_str("--scaler")?.unwrap_or(Scaler::None),
+                scale: args.opt_value_from_str::<_, usize>("--scale")?.unwrap_or(1).max(1),
+            },
+            strict: args.contains("--strict"),
+            #[cfg(feature = "audio")]
+            record_audio: args.opt_value_from_str("--record-audio")?,
+            gbs,
+            cart_path: match first {
+                Some(path) if !is_gbs => Some(path.into()),
+                _ => args.free_from_str()?,
+            }
+            .ok_or(pico_args::Error::ArgumentParsingFailed {
+                cause: "Must specify cart path.".to_string(),
+            })?,
+        };
+        if opt.gbs.is_some() {
+            let ignored = [
+                ("--frames", frames.is_some()),
+                ("--play_movie", opt.play_movie.is_some()),
+                ("--dump-vram", opt.dump_vram.is_some()),
+                ("--ppu-log", opt.ppu_log.is_some()),
+                ("--screenshot", opt.screenshot.is_some()),
+                ("--blend, --scaler and --scale", !opt.filters.is_identity()),
+                ("--strict", opt.strict),
+            ];
+            if let Some((name, _)) = ignored.iter().find(|(_, is_set)| *is_set) {
+                return Err(pico_args::Error::ArgumentParsingFailed {
+                    cause: format!("{} can't be used in gbs mode.", name),
+                });
+            }
+        }
+        Ok(opt)
+    }
+}
+
+fn main() {
+    let args = match Opt::from_args(pico_args::Arguments::from_env()) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("Error while parsing arguments: {:?}.", err);
+            std::process::exit(1);
+        }
+    };
+
+    if args.gbs.is_some() {
+        #[cfg(feature = "audio")]
+        play_gbs(&args);
+        #[cfg(not(feature = "audio"))]
+        eprintln!("gbs mode needs the audio feature.");
+        return;
+    }
+
+    let cart = cart::from_file(args.cart_path.to_str().unwrap());
+    // Not new_complete: there is no need for an audio device.
+    let mut system = system::System::default();
+    system.set_cart(cart);
+    if args.strict {
+        log::setup_logging(log::LogSettings { memory: true, ..Default::default() }).unwrap();
+        system.set_strict(true);
+    }
+    #[cfg(feature = "audio")]
+    {
+        if let Some(path) = &args.record_audio {
+            match soc::apu::output::WavWriter::create(path, soc::apu::DEFAULT_SAMPLE_RATE) {
+                Ok(writer) => system.set_audio_output(Box::new(writer)),
+                Err(err) => {
+                    eprintln!("Could not record audio to {}: {}.", path.display(), err);
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+    let mut simulator = sim::Simulator::with_system(system);
+    simulator.set_filters(args.filters);
+
+    if let Some(path) = &args.play_movie {
+        if let Err(err) = movie::Movie::load(path).and_then(|x| simulator.play_movie(&x)) {
+            eprintln!("Could not play movie {}: {:?}.", path.display(), err);
+            std::process::exit(1);
+        }
+    }
+
+    for frame in 0..args.frames {
+        if args.ppu_log.is_some() && frame + 1 == args.frames {
+            simulator.start_scanline_log();
+        }
+        simulator.advance_frame();
+    }
+    simulator.flush_audio();
+
+    if let (Some(path), Some(log)) = (&args.ppu_log, simulator.stop_scanline_log()) {
+        if let Err(err) = log.save_json(path) {
+            eprintln!("Could not write the PPU log to {}: {}.", path.display(), err);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(path) = &args.screenshot {
+        if let Err(err) = simulator.screenshot().save_png(path) {
+            eprintln!("Could not save the screenshot to {}: {}.", path.display(), err);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(dir) = &args.dump_vram {
+        if let Err(err) = dump_vram(&simulator, dir) {
+            eprintln!("Could not dump VRAM to {}: {}.", dir.display(), err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Writes the VRAM and OAM debug views into `dir`.
+fn dump_vram(simulator: &sim::Simulator, dir: &std::path::Path) -> std::io::Result<()> {
+    let gpu = simulator.system().gpu();
+    let (vram, oam) = (gpu.vram_contents(), gpu.oam_contents());
+    let registers = gpu.viewer_registers();
+    let palettes = simulator.palettes();
+    std::fs::create_dir_all(dir)?;
+    viewer::tile_sheet(vram, registers.bg_palette, &palettes.bg).save_png(dir.join("tiles.png"))?;
+    for map in 0..2 {
+        let image = viewer::tile_map(vram, &registers, map, &palettes.bg);
+        image.save_png(dir.join(format!("map{}.png", map)))?;
+    }
+    viewer::object_sheet(vram, oam, &registers, palettes).save_png(dir.join("objects.png"))?;
+    let objects: Vec<String> = viewer::objects(oam).iter().map(ToString::to_string).collect();
+    std::fs::write(dir.join("objects.txt"), objects.join("\n") + "\n")
+}
+
+/// Renders a GBS track to WAV.
+#[cfg(feature = "audio")]
+fn play_gbs(args: &Opt) {
+    let gbs_args = args.gbs.as_ref().unwrap();
+    let gbs = match gbs::Gbs::load(&args.cart_path) {
+        Ok(gbs) => gbs,
+        Err(err) => {
+            eprintln!("Could not load {}: {:?}.", args.cart_path.display(), err);
+            std::process::exit(1);
+        }
+    };
+    println!("{} - {} ({}), {} tracks.", gbs.title, gbs.author, gbs.copyright, gbs.num_songs);
+    let song = gbs_args.track.map_or(gbs.first_song, |x| x.saturating_sub(1));
+    let mut system = match gbs.system(song) {
+        Ok(system) => system,
+        Err(err) => {
+            eprintln!("Could not play track {}: {:?}.", song + 1, err);
+            std::process::exit(1);
+        }
+    };
+    let path = match &args.record_audio {
+        Some(path) => path,
+        None => {
+            eprintln!("gbs mode needs --record-audio <path>.");
+            std::process::exit(1);
+        }
+    };
+    match soc::apu::output::WavWriter::create(path, soc::apu::DEFAULT_SAMPLE_RATE) {
+        Ok(writer) => system.set_audio_output(Box::new(writer)),
+        Err(err) => {
+            eprintln!("Could not record audio to {}: {}.", path.display(), err);
+            std::process::exit(1);
+        }
+    }
+
+    // The rip may turn the LCD off, so run by cycles rather than by frames.
+    let end = gbs_args.seconds * soc::apu::TCYCLE_FREQ as u64;
+    while system.cycles() < end {
+        if let Err(err) = system.execute_machine_cycle() {
+            eprintln!("Track {} crashed: {:?}.", song + 1, err);
+            break;
+        }
+    }
+    system.flush_audio();
+}
@@ -8,8 +8,11 @@ use window::*;
 
 use soc::cart;
 use soc::gpu;
+use soc::gpu::filter::{Filters, Scaler};
+use soc::gpu::viewer;
 use soc::joypad;
 use soc::log;
+use soc::movie;
 use soc::sim;
 use soc::system;
 
@@ -17,8 +20,24 @@ struct Opt {
     cart_path: std::path::PathBuf,
     #[cfg(feature = "serialize")]
     serialize_path: std::path::PathBuf,
+    /// Memory budget for the rewind buffer, in MB.
+    #[cfg(feature = "serialize")]
+    rewind_budget: usize,
+    // Movies.
+    record_movie: Option<std::path::PathBuf>,
+    play_movie: Option<std::path::PathBuf>,
+    #[cfg(feature = "audio")]
+    record_audio: Option<std::path::PathBuf>,
+    /// A built-in palette (grey, green or pocket), or a palette file.
+    palette: Option<String>,
+    // Post-processing, see `gpu::filter`.
+    blend: bool,
+    scaler: Scaler,
+    scale: usize,
     // Logging.
     log_audio: bool,
+    /// Warn about accesses to unmapped memory.
+    strict: bool,
 }
 
 impl Opt {
@@ -27,8 +46,19 @@ impl Opt {
             #[cfg(feature = "serialize")]
             serialize_path: args
                 .opt_value_from_str(["--serialize_path", "-sp"])?
-                .unwrap_or("./serialized.bincode"),
+                .unwrap_or_else(|| "./serialized.bincode".into()),
+            #[cfg(feature = "serialize")]
+            rewind_budget: args.opt_value_from_str("--rewind_budget")?.unwrap_or(64),
+            record_movie: args.opt_value_from_str("--record_movie")?,
+            play_movie: args.opt_value_from_str("--play_movie")?,
+            #[cfg(feature = "audio")]
+            record_audio: args.opt_value_from_str("--record-audio")?,
+            palette: args.opt_value_from_str("--palette")?,
+            blend: args.contains("--blend"),
+            scaler: args.opt_value_from_str("--scaler")?.unwrap_or(Scaler::None),
+            scale: args.opt_value_from_str("--scale")?.unwrap_or(1),
             log_audio: args.contains("--log_audio"),
+            strict: args.contains("--strict"),
             cart_path: args.free_from_str()?.ok_or(pico_args::Error::ArgumentParsingFailed {
                 cause: "Must specify cart path.".to_string(),
             })?,
@@ -66,9 +96,109 @@ fn key_map(key: glutin::event::VirtualKeyCode) -> Option<joypad::Key> {
     }
 }
 
+/// Speed multipliers selectable with the number keys.
+fn speed_map(key: glutin::event::VirtualKeyCode) -> Option<f32> {
+    use glutin::event::VirtualKeyCode;
+    match key {
+        VirtualKeyCode::Key1 => Some(0.5),
+        VirtualKeyCode::Key2 => Some(1.0),
+        VirtualKeyCode::Key3 => Some(2.0),
+        VirtualKeyCode::Key4 => Some(4.0),
+        _ => None,
+    }
+}
+
+/// Audio channel toggled by the function keys: F1-F4 mute square 1, square 2, wave and noise, and
+/// F5-F8 solo them. Returns the channel, and whether the key solos it.
+fn channel_map(key: glutin::event::VirtualKeyCode) -> Option<(usize, bool)> {
+    use glutin::event::VirtualKeyCode;
+    match key {
+        VirtualKeyCode::F1 => Some((0, false)),
+        VirtualKeyCode::F2 => Some((1, false)),
+        VirtualKeyCode::F3 => Some((2, false)),
+        VirtualKeyCode::F4 => Some((3, false)),
+        VirtualKeyCode::F5 => Some((0, true)),
+        VirtualKeyCode::F6 => Some((1, true)),
+        VirtualKeyCode::F7 => Some((2, true)),
+        VirtualKeyCode::F8 => Some((3, true)),
+        _ => None,
+    }
+}
+
+/// The largest integer scale selectable with the keyboard.
+const MAX_SCALE: usize = 6;
+
+/// Applies the filter keys to `filters`: B toggles frame blending, V cycles through the scalers,
+/// and - and = change the integer scale.
+fn filter_map(key: glutin::event::VirtualKeyCode, filters: &Filters) -> Option<Filters> {
+    use glutin::event::VirtualKeyCode;
+    let mut filters = *filters;
+    match key {
+        VirtualKeyCode::B => filters.blend_frames = !filters.blend_frames,
+        VirtualKeyCode::V => filters.scaler = filters.scaler.next(),
+        VirtualKeyCode::Minus => filters.scale = (filters.scale - 1).max(1),
+        VirtualKeyCode::Equals => filters.scale = (filters.scale + 1).min(MAX_SCALE),
+        _ => return None,
+    }
+    Some(filters)
+}
+
+/// The VRAM and OAM debug windows, toggled by F9-F11.
+#[derive(Clone, Copy, PartialEq)]
+enum DebugView {
+    Tiles,
+    Maps,
+    Objects,
+}
+
+impl DebugView {
+    fn from_key(key: glutin::event::VirtualKeyCode) -> Option<DebugView> {
+        use glutin::event::VirtualKeyCode;
+        match key {
+            VirtualKeyCode::F9 => Some(DebugView::Tiles),
+            VirtualKeyCode::F10 => Some(DebugView::Maps),
+            VirtualKeyCode::F11 => Some(DebugView::Objects),
+            _ => None,
+        }
+    }
+
+    fn title(self) -> &'static str {
+        match self {
+            DebugView::Tiles => "Tiles",
+            DebugView::Maps => "Tile maps",
+            DebugView::Objects => "Sprites",
+        }
+    }
+
+    /// Renders the view of the current state, with the simulator's palettes.
+    fn render(self, simulator: &sim::Simulator) -> viewer::Image {
+        let ppu = simulator.system().gpu();
+        let (vram, registers) = (ppu.vram_contents(), ppu.viewer_registers());
+        let palettes = simulator.palettes();
+        match self {
+            DebugView::Tiles => viewer::tile_sheet(vram, registers.bg_palette, &palettes.bg),
+            DebugView::Maps => {
+                let map = |i| viewer::tile_map(vram, &registers, i, &palettes.bg);
+                map(0).beside(&map(1), 4, gpu::Pixel::new(64, 64, 64))
+            }
+            DebugView::Objects => {
+                viewer::object_sheet(vram, ppu.oam_contents(), &registers, palettes)
+            }
+        }
+    }
+}
+
+/// Looks up a built-in palette by name, or loads a palette file.
+fn load_palettes(name: &str) -> soc::error::Result<gpu::palette::DisplayPalettes> {
+    match gpu::palette::Palette::from_name(name) {
+        Some(palette) => Ok(gpu::palette::DisplayPalettes::uniform(palette)),
+        None => gpu::palette::DisplayPalettes::load(name),
+    }
+}
+
 fn main() {
     use glutin::event::Event;
-    use glutin::event::{ElementState, KeyboardInput, WindowEvent};
+    use glutin::event::{ElementState, KeyboardInput, VirtualKeyCode, WindowEvent};
     use glutin::event_loop::ControlFlow;
     use std::time::Instant;
 
@@ -87,6 +217,7 @@ fn main() {
         dma: false,
         gpu: false,
         audio: args.log_audio,
+        memory: args.strict,
     })
     .unwrap();
 
@@ -96,21 +227,67 @@ fn main() {
         let cart = cart::from_file(args.cart_path.to_str().unwrap());
         let mut system = system::System::new_complete();
         system.set_cart(cart);
+        system.set_strict(args.strict);
+        #[cfg(feature = "audio")]
+        {
+            if let Some(path) = &args.record_audio {
+                match soc::apu::output::WavWriter::create(path, soc::apu::DEFAULT_SAMPLE_RATE) {
+                    Ok(writer) => system.add_audio_output(Box::new(writer)),
+                    Err(err) => eprintln!("Could not record audio to {}: {}.", path.display(), err),
+                }
+            }
+        }
         sim::Simulator::with_system(system)
     };
+    // The window uploads BGRA textures.
+    simulator.set_pixel_format(gpu::palette::PixelFormat::Bgra8);
+    if let Some(name) = &args.palette {
+        match load_palettes(name) {
+            Ok(palettes) => simulator.set_palettes(palettes),
+            Err(err) => {
+                eprintln!("Could not load palette {}: {:?}.", name, err);
+                return;
+            }
+        }
+    }
+
+    simulator.set_filters(Filters {
+        blend_frames: args.blend,
+        scaler: args.scaler,
+        scale: args.scale.max(1),
+    });
+
+    // Movies always start from power-on when launched from the command line.
+    if let Some(path) = &args.play_movie {
+        if let Err(err) = movie::Movie::load(path).and_then(|x| simulator.play_movie(&x)) {
+            eprintln!("Could not play movie {}: {:?}.", path.display(), err);
+            return;
+        }
+    }
+    if args.record_movie.is_some() {
+        simulator.start_recording();
+    }
+    #[cfg(feature = "serialize")]
+    simulator.enable_rewind(args.rewind_budget << 20);
 
     // Set up the window.
     let event_loop = glutin::event_loop::EventLoop::new();
-    let window = Window::with_event_loop(&event_loop);
+    let mut window = Window::with_event_loop(&event_loop);
+    window.resize(simulator.screen_width(), simulator.screen_height());
+    let mut debug_windows: Vec<(DebugView, Window)> = Vec::new();
 
     let mut last_screen: Option<Box<[u8]>> = None;
+    // F12 saves the screen, with the filters, to screenshot_<n>.png.
+    let mut screenshots = 0;
 
     // And just run!
     let mut sim_timer = Instant::now();
     let mut fps_timer = Instant::now();
     let mut fps_counter = 0;
+    // The speed to go back to after fast-forwarding.
+    let mut speed = 1.0;
 
-    event_loop.run(move |event, _, control_flow| {
+    event_loop.run(move |event, target, control_flow| {
         let elapsed = sim_timer.elapsed();
         sim_timer += elapsed;
 
@@ -118,24 +295,45 @@ fn main() {
         *control_flow = ControlFlow::WaitUntil(sim_timer + std::time::Duration::from_millis(1));
 
         // Handle any window event now.
-        if let Event::RedrawRequested(..) = event {
-            if let Some(screen) = &last_screen {
-                window.update_screen(screen.as_ref());
-                last_screen = None;
-            }
-            window.swap_buffers();
-
-            fps_counter += 1;
-            let elapsed = fps_timer.elapsed();
-            if elapsed.as_secs() > 0 {
-                fps_timer += elapsed;
-                println!("Avg FPS: {}", fps_counter / elapsed.as_secs());
-                fps_counter = 0;
+        if let Event::RedrawRequested(window_id) = event {
+            if window_id != window.id() {
+                let debug_window = debug_windows.iter_mut().find(|(_, x)| x.id() == window_id);
+                if let Some((view, debug_window)) = debug_window {
+                    debug_window.update_screen(&view.render(&simulator).bgra8());
+                    debug_window.swap_buffers();
+                }
+            } else {
+                if let Some(screen) = &last_screen {
+                    window.update_screen(screen.as_ref());
+                    last_screen = None;
+                }
+                window.swap_buffers();
+
+                fps_counter += 1;
+                let elapsed = fps_timer.elapsed();
+                if elapsed.as_secs() > 0 {
+                    fps_timer += elapsed;
+                    println!("Avg FPS: {}", fps_counter / elapsed.as_secs());
+                    fps_counter = 0;
+                }
             }
-        } else if let Event::WindowEvent { event, .. } = event {
+        } else if let Event::WindowEvent { window_id, event } = event {
             match event {
+                // Closing a debug window only closes that window.
+                WindowEvent::CloseRequested if window_id != window.id() => {
+                    debug_windows.retain(|(_, x)| x.id() != window_id);
+                }
                 // CloseRequested. End the loop.
                 WindowEvent::CloseRequested => {
+                    if let (Some(path), Some(movie)) =
+                        (&args.record_movie, simulator.stop_recording())
+                    {
+                        match movie.save(path) {
+                            Ok(()) => println!("Saved movie to {}.", path.display()),
+                            Err(err) => eprintln!("Could not save movie: {}.", err),
+                        }
+                    }
+                    simulator.flush_audio();
                     *control_flow = ControlFlow::Exit;
                     return;
                 }
@@ -144,6 +342,75 @@ fn main() {
                     input: KeyboardInput { virtual_keycode, state, .. },
                     ..
                 } => {
+                    let is_pressed = state == ElementState::Pressed;
+                    match virtual_keycode {
+                        // Hold R to rewind. Each frame goes back to the previous snapshot, i.e.
+                        // the game runs backwards at twice the speed.
+                        #[cfg(feature = "serialize")]
+                        Some(VirtualKeyCode::R) => simulator.set_rewinding(is_pressed),
+                        // Hold F to fast-forward.
+                        Some(VirtualKeyCode::F) => {
+                            simulator.set_speed(if is_pressed { std::f32::INFINITY } else { speed })
+                        }
+                        // P toggles pause, N advances a single frame.
+                        Some(VirtualKeyCode::P) if is_pressed => {
+                            if simulator.is_paused() {
+                                simulator.resume();
+                            } else {
+                                simulator.pause();
+                            }
+                        }
+                        Some(VirtualKeyCode::N) if is_pressed => {
+                            last_screen = Some(simulator.advance_frame());
+                            window.request_redraw();
+                            for (_, debug_window) in &debug_windows {
+                                debug_window.request_redraw();
+                            }
+                        }
+                        Some(key) if is_pressed && DebugView::from_key(key).is_some() => {
+                            let view = DebugView::from_key(key).unwrap();
+                            if let Some(i) = debug_windows.iter().position(|(x, _)| *x == view) {
+                                debug_windows.remove(i);
+                            } else {
+                                let image = view.render(&simulator);
+                                let debug_window =
+                                    Window::debug(target, view.title(), image.width, image.height);
+                                debug_window.request_redraw();
+                                debug_windows.push((view, debug_window));
+                            }
+                        }
+                        Some(key)
+                            if is_pressed && filter_map(key, simulator.filters()).is_some() =>
+                        {
+                            simulator.set_filters(filter_map(key, simulator.filters()).unwrap());
+                            window.resize(simulator.screen_width(), simulator.screen_height());
+                            last_screen = Some(simulator.screen());
+                            window.request_redraw();
+                        }
+                        Some(VirtualKeyCode::F12) if is_pressed => {
+                            let path = format!("screenshot_{}.png", screenshots);
+                            match simulator.screenshot().save_png(&path) {
+                                Ok(()) => println!("Saved screenshot to {}.", path),
+                                Err(err) => eprintln!("Could not save screenshot: {}.", err),
+                            }
+                            screenshots += 1;
+                        }
+                        Some(key) if is_pressed && speed_map(key).is_some() => {
+                            speed = speed_map(key).unwrap();
+                            simulator.set_speed(speed);
+                        }
+                        Some(key) if is_pressed && channel_map(key).is_some() => {
+                            let (channel, is_solo) = channel_map(key).unwrap();
+                            if is_solo {
+                                let is_soloed = !simulator.is_channel_soloed(channel);
+                                simulator.set_channel_soloed(channel, is_soloed);
+                            } else {
+                                let is_muted = !simulator.is_channel_muted(channel);
+                                simulator.set_channel_muted(channel, is_muted);
+                            }
+                        }
+                        _ => (),
+                    }
                     if let Some(key) = virtual_keycode.and_then(key_map) {
                         if let ElementState::Pressed = state {
                             simulator.press_key(key);
@@ -159,6 +426,9 @@ fn main() {
         // Run the simulation!
         if let Some(screen) = simulator.update(elapsed.as_micros() as f32 * 1e-6) {
             window.request_redraw();
+            for (_, debug_window) in &debug_windows {
+                debug_window.request_redraw();
+            }
             last_screen = Some(screen);
         }
     });
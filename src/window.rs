@@ -3,6 +3,7 @@ use gl::types::GLuint;
 use crate::gpu;
 
 type EventLoop = glutin::event_loop::EventLoop<()>;
+type EventLoopWindowTarget = glutin::event_loop::EventLoopWindowTarget<()>;
 
 #[macro_export]
 macro_rules! GL {
@@ -98,19 +99,45 @@ fn load_all_shaders() -> GLuint {
     }
 }
 
+/// A window that displays a single image, stretched to fill it.
 pub struct Window {
-    context: glutin::WindowedContext<glutin::PossiblyCurrent>,
+    /// Only None while switching the current context.
+    context: Option<glutin::WindowedContext<glutin::PossiblyCurrent>>,
     shader: GLuint,
+    /// The size of the image.
+    width: usize,
+    height: usize,
 }
 
 impl Window {
     pub fn with_event_loop(event_loop: &EventLoop) -> Window {
         let window = glutin::window::WindowBuilder::new().with_title("RustyBoy");
+        Window::from_builder(event_loop, window, gpu::LCD_WIDTH, gpu::LCD_HEIGHT)
+    }
+
+    /// A debug window, showing `width`x`height` images at twice their size.
+    pub fn debug(
+        target: &EventLoopWindowTarget,
+        title: &str,
+        width: usize,
+        height: usize,
+    ) -> Window {
+        let size = glutin::dpi::LogicalSize::new((width * 2) as f64, (height * 2) as f64);
+        let window = glutin::window::WindowBuilder::new().with_title(title).with_inner_size(size);
+        Window::from_builder(target, window, width, height)
+    }
+
+    fn from_builder(
+        target: &EventLoopWindowTarget,
+        window: glutin::window::WindowBuilder,
+        width: usize,
+        height: usize,
+    ) -> Window {
         let context = glutin::ContextBuilder::new()
             .with_vsync(false)
             .with_gl(glutin::GlRequest::Latest)
             .with_gl_profile(glutin::GlProfile::Core)
-            .build_windowed(window, event_loop)
+            .build_windowed(window, target)
             .unwrap();
 
         let context = unsafe { context.make_current().unwrap() };
@@ -138,8 +165,8 @@ impl Window {
                 gl::TEXTURE_2D,
                 0,
                 gl::RGBA8 as i32,
-                gpu::LCD_WIDTH as i32,
-                gpu::LCD_HEIGHT as i32,
+                width as i32,
+                height as i32,
                 0,
                 gl::BGRA,
                 gl::UNSIGNED_INT_8_8_8_8_REV,
@@ -148,19 +175,56 @@ impl Window {
             GL!(gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::NEAREST as i32));
             GL!(gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, filter as i32));
         }
-        Window { context, shader: fs_copy }
+        Window { context: Some(context), shader: fs_copy, width, height }
+    }
+
+    pub fn id(&self) -> glutin::window::WindowId {
+        self.context().window().id()
+    }
+
+    fn context(&self) -> &glutin::WindowedContext<glutin::PossiblyCurrent> {
+        self.context.as_ref().unwrap()
+    }
+
+    /// Every window has its own GL context, which must be made current before drawing to it.
+    fn make_current(&mut self) {
+        let context = self.context.take().unwrap();
+        self.context = Some(unsafe { context.make_current() }.map_err(|(_, err)| err).unwrap());
+    }
+
+    /// Changes the size of the images drawn, e.g. after changing the filters. They are still
+    /// stretched to fill the window.
+    pub fn resize(&mut self, width: usize, height: usize) {
+        self.make_current();
+        unsafe {
+            GL!(gl::TexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                gl::RGBA8 as i32,
+                width as i32,
+                height as i32,
+                0,
+                gl::BGRA,
+                gl::UNSIGNED_INT_8_8_8_8_REV,
+                core::ptr::null(),
+            ));
+        }
+        self.width = width;
+        self.height = height;
     }
 
-    pub fn update_screen(&self, pixels: &[u8]) {
-        assert_eq!(pixels.len(), gpu::LCD_WIDTH * gpu::LCD_HEIGHT * 4);
+    /// Draws a BGRA image of the size set by `resize`, the window's size by default.
+    pub fn update_screen(&mut self, pixels: &[u8]) {
+        assert_eq!(pixels.len(), self.width * self.height * 4);
+        self.make_current();
         unsafe {
             GL!(gl::TexSubImage2D(
                 gl::TEXTURE_2D,
                 0,
                 0,
                 0,
-                gpu::LCD_WIDTH as i32,
-                gpu::LCD_HEIGHT as i32,
+                self.width as i32,
+                self.height as i32,
                 gl::BGRA,
                 gl::UNSIGNED_INT_8_8_8_8_REV,
                 pixels.as_ptr() as *const core::ffi::c_void
@@ -174,10 +238,11 @@ impl Window {
     }
 
     pub fn request_redraw(&self) {
-        self.context.window().request_redraw();
+        self.context().window().request_redraw();
     }
 
-    pub fn swap_buffers(&self) {
-        self.context.swap_buffers().unwrap();
+    pub fn swap_buffers(&mut self) {
+        self.make_current();
+        self.context().swap_buffers().unwrap();
     }
 }
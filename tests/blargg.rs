@@ -0,0 +1,63 @@
+//! Blargg's test ROMs that report through cartridge RAM: http://gbdev.gg8.se/files/roms/blargg-gb-tests/
+//!
+//! While a test runs, 0xA000 reads 0x80. Once it is done, 0xA000 holds the result (0 on success)
+//! and 0xA004 the text it printed. 0xA001-0xA003 hold a signature, so that RAM left over from
+//! before the test starts isn't taken as a result.
+
+#[macro_use]
+mod common;
+
+use soc::system::System;
+
+/// The slowest tests take about 20 seconds of emulated time.
+const MAX_MCYCLES: usize = 30 * 1024 * 1024;
+const SIGNATURE: [i32; 3] = [0xDE, 0xB0, 0x61];
+const RUNNING: i32 = 0x80;
+
+// Sound. The ROM names have spaces, which are underscores here.
+ignored_rom_tests!(
+    dmg_sound__01___registers;
+    dmg_sound__02___len_ctr;
+    dmg_sound__03___trigger;
+    dmg_sound__04___sweep;
+    dmg_sound__05___sweep_details;
+    dmg_sound__06___overflow_on_trigger;
+    dmg_sound__07___len_sweep_period_sync;
+    dmg_sound__08___len_ctr_during_power;
+    dmg_sound__09___wave_read_while_on;
+    dmg_sound__10___wave_trigger_while_on;
+    dmg_sound__11___regs_after_power;
+    dmg_sound__12___wave_write_while_on;
+);
+
+// OAM corruption bug. Until the ROMs are checked in, the rows `gpu::oam_bug` corrupts are only
+// checked by its unit tests.
+ignored_rom_tests!(
+    oam_bug__1___lcd_sync;
+    oam_bug__2___causes;
+    oam_bug__3___non_causes;
+    oam_bug__4___scanline_timing;
+    oam_bug__5___timing_bug;
+    oam_bug__6___timing_no_bug;
+    oam_bug__7___timing_effect;
+    oam_bug__8___instr_effect;
+);
+
+fn run_target(target: &str) {
+    common::setup_logging();
+    let mut system = common::load_rom(
+        std::path::Path::new("test_roms/blargg").join(target).with_extension("gb"),
+    );
+    let has_signature =
+        |system: &System| (0..3).all(|i| system.peek(0xA001 + i) == Some(SIGNATURE[i as usize]));
+    common::run_until(&mut system, MAX_MCYCLES, target, |system| {
+        has_signature(system) && system.peek(0xA000) != Some(RUNNING)
+    });
+
+    let text: String = (0xA004..0xC000)
+        .map(|address| system.peek(address).unwrap_or(0) as u8)
+        .take_while(|&x| x != 0)
+        .map(char::from)
+        .collect();
+    assert_eq!(system.peek(0xA000), Some(0), "{} failed:\n{}", target, text);
+}
@@ -0,0 +1,85 @@
+//! Helpers shared by the integration tests.
+//!
+//! Suites whose ROMs are not checked in (see `test_roms/README.md`) are declared with
+//! `ignored_rom_tests!`, and run with `cargo test --test <suite> -- --ignored` once set up.
+
+// Each test binary only uses some of the helpers.
+#![allow(dead_code, unused_macros)]
+
+use std::path::Path;
+
+use soc::cart;
+use soc::gpu::Color;
+use soc::log;
+use soc::screenshot;
+use soc::system::System;
+
+/// Declares an ignored test per ROM. The test calls `run_target` with its name, where `__` stands
+/// for `/` and `___` for `-`.
+macro_rules! ignored_rom_tests {
+    (
+        $($test_name:ident);*
+        ;
+    ) => {
+        $(
+            #[test]
+            #[ignore = "The ROM is not checked in, see test_roms/README.md."]
+            #[allow(non_snake_case)]
+            fn $test_name() {
+                let path = stringify!($test_name).replace("___", "-").replace("__", "/");
+                run_target(&path);
+            }
+        )*
+    };
+}
+
+pub fn setup_logging() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        log::setup_logging(log::LogSettings { ..Default::default() }).unwrap();
+    });
+}
+
+/// Creates a system running the ROM at `path`. It has an APU, so that the sound registers are
+/// mapped, but its samples go nowhere.
+pub fn load_rom(path: impl AsRef<Path>) -> System {
+    let path = path.as_ref();
+    assert!(path.exists(), "{:?} does not exist.", path);
+    let mut system = System::default();
+    #[cfg(feature = "audio")]
+    system.set_audio_output(Box::new(soc::apu::output::NullOutput));
+    system.set_cart(cart::from_file(path.to_str().unwrap()));
+    system
+}
+
+/// Runs `system` until `is_done`, failing `name` if that takes more than `max_mcycles`.
+pub fn run_until(
+    system: &mut System,
+    max_mcycles: usize,
+    name: &str,
+    is_done: impl Fn(&System) -> bool,
+) {
+    let mut mcycles = 0;
+    while !is_done(system) {
+        system.execute_machine_cycle().unwrap();
+        mcycles += 1;
+        assert!(mcycles < max_mcycles, "{} never finished.", name);
+    }
+}
+
+/// Reads a reference screen from a PNG, see `screenshot::decode_screen`.
+pub fn load_screen(path: impl AsRef<Path>) -> Result<Vec<Color>, String> {
+    let path = path.as_ref();
+    let png = std::fs::read(path).map_err(|e| format!("Could not read {:?}: {}", path, e))?;
+    screenshot::decode_screen(&png).map_err(|e| format!("{:?}: {}", path, e))
+}
+
+/// Compares the screen of `system` with the reference PNG at `expected_path`. On a mismatch, the
+/// actual screen, the expected one and their difference are saved under `failed_tests/<suite>`.
+pub fn assert_screen(suite: &str, name: &str, system: &System, expected_path: impl AsRef<Path>) {
+    let expected = load_screen(expected_path).unwrap();
+    let dir = Path::new("failed_tests").join(suite);
+    if let Err(message) = screenshot::compare(dir, name, system.screen(), &expected) {
+        panic!("{}", message);
+    }
+}
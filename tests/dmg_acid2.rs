@@ -0,0 +1,22 @@
+//! dmg-acid2: https://github.com/mattcurrie/dmg-acid2
+//!
+//! Draws a face that exercises the background, window and sprite fetches, sprite priorities and
+//! the 10 sprites per line limit. After a few frames the screen is compared with
+//! `test_roms/dmg-acid2/reference-dmg.png`.
+
+mod common;
+
+use soc::screenshot;
+
+/// The face is drawn within the first few frames, and stays up.
+const FRAMES: usize = 10;
+
+#[test]
+#[ignore = "The ROM is not checked in, see test_roms/README.md."]
+fn dmg_acid2() {
+    common::setup_logging();
+    let base_path = std::path::Path::new("test_roms/dmg-acid2");
+    let mut system = common::load_rom(base_path.join("dmg-acid2.gb"));
+    screenshot::run_frames(&mut system, FRAMES).unwrap();
+    common::assert_screen("dmg-acid2", "dmg-acid2", &system, base_path.join("reference-dmg.png"));
+}
@@ -0,0 +1,52 @@
+//! Mealybug Tearoom PPU tests: https://github.com/mattcurrie/mealybug-tearoom-tests
+//!
+//! Each ROM changes PPU registers in the middle of a frame and stops on `LD B,B`. The screen is then
+//! compared with the DMG reference image, `test_roms/mealybug/<test>.png`.
+//!
+//! Neither the ROMs nor the reference images are checked in yet, so every test is ignored. Once they
+//! are, enable the ones that pass, and note why the others still fail.
+
+#[macro_use]
+mod common;
+
+use soc::cpu::register::Register;
+
+/// The tests take a few frames, give up after a few seconds of emulated time.
+const MAX_MCYCLES: usize = 5 * 1024 * 1024;
+
+ignored_rom_tests!(
+    m2_win_en_toggle;
+    m3_bgp_change;
+    m3_bgp_change_sprites;
+    m3_lcdc_bg_en_change;
+    m3_lcdc_bg_map_change;
+    m3_lcdc_obj_en_change;
+    m3_lcdc_obj_en_change_variant;
+    m3_lcdc_obj_size_change;
+    m3_lcdc_obj_size_change_scx;
+    m3_lcdc_tile_sel_change;
+    m3_lcdc_tile_sel_win_change;
+    m3_lcdc_win_en_change_multiple;
+    m3_lcdc_win_en_change_multiple_wx;
+    m3_lcdc_win_map_change;
+    m3_obp0_change;
+    m3_scx_high_5_bits;
+    m3_scx_low_3_bits;
+    m3_scy_change;
+    m3_window_timing;
+    m3_window_timing_wx_0;
+    m3_wx_4_change;
+    m3_wx_4_change_sprites;
+    m3_wx_5_change;
+    m3_wx_6_change;
+);
+
+fn run_target(target: &str) {
+    common::setup_logging();
+    let base_path = std::path::Path::new("test_roms/mealybug").join(target);
+    let mut system = common::load_rom(base_path.with_extension("gb"));
+    common::run_until(&mut system, MAX_MCYCLES, target, |system| {
+        system.cpu().registers.get(Register::INSTR) == 0x40
+    });
+    common::assert_screen("mealybug", target, &system, base_path.with_extension("png"));
+}
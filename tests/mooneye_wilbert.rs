@@ -41,6 +41,7 @@ test_target!(
 test_target!(
     acceptance__oam_dma__basic;
     acceptance__oam_dma__reg_read;
+    acceptance__oam_dma__sources___dmgABCmgbS;
     acceptance__oam_dma_start;
     acceptance__oam_dma_timing;
     acceptance__oam_dma_restart;
@@ -99,9 +100,8 @@ test_target!(
     acceptance__ppu__intr_2_mode3_timing;
     acceptance__ppu__intr_2_oam_ok_timing;
     acceptance__ppu__vblank_stat_intr___GS;
-
-    // Not even going to try to fix this one...
-    // acceptance__ppu__intr_2_mode0_timing_sprites;
+    acceptance__ppu__intr_2_mode0_timing_sprites;
+    acceptance__ppu__stat_irq_blocking;
 );
 
 // Wilbert tests.
@@ -131,13 +131,20 @@ test_target!(
     wilbert__intr_2_mode0_scx7_timing_nops;
     wilbert__intr_2_mode0_scx8_timing_nops;
 
+    wilbert__intr_2_mode0_timing_sprites;
+    wilbert__intr_2_mode0_timing_sprites_nops;
+    wilbert__intr_2_mode0_timing_sprites_scx1_nops;
+    wilbert__intr_2_mode0_timing_sprites_scx2_nops;
+    wilbert__intr_2_mode0_timing_sprites_scx3_nops;
+    wilbert__intr_2_mode0_timing_sprites_scx4_nops;
+
     wilbert__lcdon_mode_timing;
 
     wilbert__ly_lyc___GS;
 
-    // wilbert__ly_lyc_write___GS;
-    // wilbert__ly_lyc_0_write___GS;
-    // wilbert__ly_lyc_153_write___GS;
+    wilbert__ly_lyc_write___GS;
+    wilbert__ly_lyc_0_write___GS;
+    wilbert__ly_lyc_153_write___GS;
 
     wilbert__ly_lyc_0___GS;
 
@@ -156,6 +163,9 @@ test_target!(
     wilbert__ly_lyc_153___GS;
 
     wilbert__ly_new_frame___GS;
+
+    wilbert__stat_irq_blocking;
+    wilbert__stat_write_if___GS;
 );
 
 pub fn run_target(target: &str) -> bool {
@@ -0,0 +1,40 @@
+//! Golden-image tests of checked-in ROMs. Each ROM runs until its breakpoint, then after a couple
+//! of frames the screen is compared with `test_roms/<test>.png`.
+//!
+//! The references are the screens the ROMs print their results on when they pass, so they don't
+//! depend on this emulator being right: `intr_2_mode0_timing_sprites` also prints the sprite counts
+//! it measured.
+
+mod common;
+
+use soc::cpu::register::Register;
+use soc::screenshot;
+
+/// Mooneye's breakpoint is `LD B,B`, wilbert's is an illegal opcode.
+const MOONEYE_BREAKPOINT: i32 = 0x40;
+const WILBERT_BREAKPOINT: i32 = 0xED;
+const MAX_MCYCLES: usize = 10 * 1024 * 1024;
+
+#[test]
+#[allow(non_snake_case)]
+fn acceptance__instr__daa() {
+    run_target("acceptance/instr/daa", MOONEYE_BREAKPOINT);
+}
+
+#[test]
+#[allow(non_snake_case)]
+fn wilbert__intr_2_mode0_timing_sprites() {
+    run_target("wilbert/intr_2_mode0_timing_sprites", WILBERT_BREAKPOINT);
+}
+
+fn run_target(target: &str, breakpoint: i32) {
+    common::setup_logging();
+    let base_path = std::path::Path::new("test_roms").join(target);
+    let mut system = common::load_rom(base_path.with_extension("gb"));
+    common::run_until(&mut system, MAX_MCYCLES, target, |system| {
+        system.cpu().registers.get(Register::INSTR) == breakpoint
+    });
+    screenshot::run_frames(&mut system, 2).unwrap();
+    let name = target.replace('/', "__");
+    common::assert_screen("screenshots", &name, &system, base_path.with_extension("png"));
+}
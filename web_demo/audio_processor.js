@@ -0,0 +1,47 @@
+// Plays the samples produced by the simulator. The main thread posts interleaved stereo
+// Float32Arrays, which are queued and played back in order.
+
+// Drop samples if more than this many seconds are queued, to keep the latency down.
+const MAX_QUEUED_SECONDS = 0.2;
+
+class SimulatorAudioProcessor extends AudioWorkletProcessor {
+  constructor() {
+    super();
+    this.chunks = [];
+    // Read position, in samples, within the first chunk.
+    this.position = 0;
+    this.queued_frames = 0;
+    this.port.onmessage = (e) => {
+      if (this.queued_frames > MAX_QUEUED_SECONDS * sampleRate) {
+        return;
+      }
+      this.chunks.push(e.data);
+      this.queued_frames += e.data.length / 2;
+    };
+  }
+
+  process(inputs, outputs) {
+    const left = outputs[0][0];
+    const right = outputs[0][1];
+    for (var i = 0; i < left.length; i++) {
+      if (this.chunks.length == 0) {
+        // Underrun: play silence.
+        left[i] = 0;
+        right[i] = 0;
+        continue;
+      }
+      const chunk = this.chunks[0];
+      left[i] = chunk[this.position];
+      right[i] = chunk[this.position + 1];
+      this.position += 2;
+      this.queued_frames -= 1;
+      if (this.position >= chunk.length) {
+        this.chunks.shift();
+        this.position = 0;
+      }
+    }
+    return true;
+  }
+}
+
+registerProcessor('simulator-audio-processor', SimulatorAudioProcessor);
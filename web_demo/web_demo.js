@@ -6,8 +6,34 @@ var simulator;
 // Store the last time update_tick was called.
 var last_time;
 
-function start_from_bytes(cart_bytes) {
+// Audio is played by an AudioWorklet, fed with the samples of every update.
+var audio_context;
+var audio_node;
+
+async function start_audio() {
+  if (audio_context) {
+    return;
+  }
+  audio_context = new AudioContext();
+  await audio_context.audioWorklet.addModule('./audio_processor.js');
+  audio_node = new AudioWorkletNode(
+      audio_context, 'simulator-audio-processor', {outputChannelCount: [2]});
+  audio_node.connect(audio_context.destination);
+  // Picking a file counts as user interaction, so the context is allowed to start.
+  await audio_context.resume();
+}
+
+async function start_from_bytes(cart_bytes) {
   simulator = soc.Simulator.from_cart_bytes(new Uint8Array(cart_bytes));
+  // The canvas takes RGBA.
+  simulator.set_pixel_format(soc.PixelFormat.Rgba8);
+  try {
+    await start_audio();
+    simulator.enable_audio_buffer(audio_context.sampleRate);
+  } catch (e) {
+    console.log('Could not start audio: ' + e);
+  }
+  update_filters();
   last_time = performance.now();
   window.requestAnimationFrame(update_tick);
 }
@@ -22,21 +48,50 @@ function update_tick(new_time) {
     // console.log(performance.now() - t);
     // If we have a new screen, put it into backing_image.
     if (maybe_data) {
-      var backing_image = document.getElementById('backing_image');
-      {
-        var backing_ctx = backing_image.getContext('2d');
-        var imageData = backing_ctx.getImageData(0, 0, 160, 144);
-        var data = imageData.data;
-        for (var i = 0; i < data.length; i++) {
-          data[i] = maybe_data[i];
-        }
-        backing_ctx.putImageData(imageData, 0, 0);
-      }
+      draw_screen(maybe_data);
+    }
+    var samples = simulator.take_audio_samples();
+    if (audio_node && samples.length > 0) {
+      audio_node.port.postMessage(samples, [samples.buffer]);
     }
   }
   window.requestAnimationFrame(update_tick);
 }
 
+// The screen's size depends on the filters, so the canvas follows it.
+function draw_screen(screen) {
+  var backing_image = document.getElementById('backing_image');
+  var width = simulator.screen_width();
+  var height = simulator.screen_height();
+  if (backing_image.width != width || backing_image.height != height) {
+    backing_image.width = width;
+    backing_image.height = height;
+  }
+  var backing_ctx = backing_image.getContext('2d');
+  var imageData = backing_ctx.getImageData(0, 0, width, height);
+  imageData.data.set(screen);
+  backing_ctx.putImageData(imageData, 0, 0);
+}
+
+// Applies the filter controls, and redraws the screen in case the simulator is paused.
+function update_filters() {
+  if (!simulator) {
+    return;
+  }
+  simulator.set_frame_blending(document.getElementById('blend_frames').checked);
+  simulator.set_scaler(soc.Scaler[document.getElementById('scaler').value]);
+  simulator.set_scale(parseInt(document.getElementById('scale').value));
+  draw_screen(simulator.screen());
+}
+
+// Downloads the screen as shown, filters included.
+function save_screenshot() {
+  var link = document.createElement('a');
+  link.download = 'screenshot.png';
+  link.href = document.getElementById('backing_image').toDataURL('image/png');
+  link.click();
+}
+
 function handleFileChange() {
   const file_button = document.getElementById('file_button');
   if (this.files.length == 0) {
@@ -57,6 +112,11 @@ export async function run() {
 
   document.getElementById('cart_file')
       .addEventListener('change', handleFileChange, false);
+  for (const id of ['blend_frames', 'scaler', 'scale']) {
+    document.getElementById(id).addEventListener('change', update_filters, false);
+  }
+  document.getElementById('screenshot_button')
+      .addEventListener('click', save_screenshot, false);
 }
 
 run();
\ No newline at end of file